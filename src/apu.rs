@@ -0,0 +1,1011 @@
+// 2A03 audio processing unit: two pulse channels, triangle, noise and DMC, sequenced by the frame counter
+// and mixed into a sample buffer at the output rate. Timing follows https://www.nesdev.org/wiki/APU
+// Everything here is clocked once per CPU cycle; the pulse timers only advance every other cycle.
+
+use crate::savestate::{StateReader, StateWriter, Stateful};
+
+pub const CPU_CLOCK: f64 = 1_789_773.0;
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+const TRIANGLE_TABLE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+// Noise and DMC periods in CPU cycles (NTSC)
+const NOISE_PERIODS: [u16; 16] = [4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068];
+const DMC_RATES: [u16; 16] = [428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54];
+
+lazy_static! {
+    // Nonlinear mixer lookup tables: https://www.nesdev.org/wiki/APU_Mixer
+    static ref PULSE_TABLE: Vec<f32> = (0..31)
+        .map(|n| if n == 0 { 0.0 } else { 95.52 / (8128.0 / n as f32 + 100.0) })
+        .collect();
+    static ref TND_TABLE: Vec<f32> = (0..203)
+        .map(|n| if n == 0 { 0.0 } else { 163.67 / (24329.0 / n as f32 + 100.0) })
+        .collect();
+}
+
+#[derive(Default)]
+struct Envelope {
+    start: bool,
+    looping: bool,
+    constant: bool,
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    // --LC VVVV, the loop flag doubles as the length counter halt flag
+    fn write(&mut self, data: u8) {
+        self.looping = data & 0b0010_0000 != 0;
+        self.constant = data & 0b0001_0000 != 0;
+        self.volume = data & 0b1111;
+    }
+
+    fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.constant { self.volume } else { self.decay }
+    }
+}
+
+#[derive(Default)]
+struct Pulse {
+    // Pulse 1 negates with ones' complement, pulse 2 with twos' complement
+    ones_complement: bool,
+    enabled: bool,
+    duty: u8,
+    sequence: u8,
+    timer_period: u16,
+    timer: u16,
+    length: u8,
+    envelope: Envelope,
+
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool,
+}
+
+impl Pulse {
+    fn new(ones_complement: bool) -> Self {
+        Pulse { ones_complement, ..Pulse::default() }
+    }
+
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.duty = data >> 6;
+                self.envelope.write(data);
+            }
+            1 => {
+                self.sweep_enabled = data & 0b1000_0000 != 0;
+                self.sweep_period = (data >> 4) & 0b111;
+                self.sweep_negate = data & 0b1000 != 0;
+                self.sweep_shift = data & 0b111;
+                self.sweep_reload = true;
+            }
+            2 => self.timer_period = (self.timer_period & 0x0700) | data as u16,
+            _ => {
+                self.timer_period = (self.timer_period & 0x00ff) | ((data as u16 & 0b111) << 8);
+                if self.enabled {
+                    self.length = LENGTH_TABLE[(data >> 3) as usize];
+                }
+                self.sequence = 0;
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.sequence = (self.sequence + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn target_period(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if self.sweep_negate {
+            self.timer_period.saturating_sub(change + self.ones_complement as u16)
+        } else {
+            self.timer_period + change
+        }
+    }
+
+    // The sweep unit silences the channel even when it is disabled
+    fn muted(&self) -> bool {
+        self.timer_period < 8 || self.target_period() > 0x7ff
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted() {
+            self.timer_period = self.target_period();
+        }
+
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if !self.envelope.looping && self.length > 0 {
+            self.length -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.muted() || self.length == 0 || DUTY_TABLE[self.duty as usize][self.sequence as usize] == 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+#[derive(Default)]
+struct Triangle {
+    enabled: bool,
+    control: bool,
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+    timer_period: u16,
+    timer: u16,
+    length: u8,
+    sequence: u8,
+}
+
+impl Triangle {
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.control = data & 0b1000_0000 != 0;
+                self.linear_reload_value = data & 0b0111_1111;
+            }
+            1 => {}
+            2 => self.timer_period = (self.timer_period & 0x0700) | data as u16,
+            _ => {
+                self.timer_period = (self.timer_period & 0x00ff) | ((data as u16 & 0b111) << 8);
+                if self.enabled {
+                    self.length = LENGTH_TABLE[(data >> 3) as usize];
+                }
+                self.linear_reload = true;
+            }
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            // Ultrasonic periods are skipped, they only produce a pop on real hardware
+            if self.length > 0 && self.linear_counter > 0 && self.timer_period >= 2 {
+                self.sequence = (self.sequence + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_linear(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if !self.control && self.length > 0 {
+            self.length -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        TRIANGLE_TABLE[self.sequence as usize]
+    }
+}
+
+struct Noise {
+    enabled: bool,
+    mode: bool,
+    timer_period: u16,
+    timer: u16,
+    shift: u16,
+    length: u8,
+    envelope: Envelope,
+}
+
+impl Noise {
+    fn new() -> Self {
+        Noise {
+            enabled: false,
+            mode: false,
+            timer_period: NOISE_PERIODS[0],
+            timer: 0,
+            shift: 1,
+            length: 0,
+            envelope: Envelope::default(),
+        }
+    }
+
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => self.envelope.write(data),
+            1 => {}
+            2 => {
+                self.mode = data & 0b1000_0000 != 0;
+                self.timer_period = NOISE_PERIODS[(data & 0b1111) as usize];
+            }
+            _ => {
+                if self.enabled {
+                    self.length = LENGTH_TABLE[(data >> 3) as usize];
+                }
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.timer_period - 1;
+
+        // 15-bit LFSR, mode 1 taps bit 6 instead of bit 1 for the short 93-step sequence
+        let tap = if self.mode { 6 } else { 1 };
+        let feedback = (self.shift ^ (self.shift >> tap)) & 1;
+        self.shift = (self.shift >> 1) | (feedback << 14);
+    }
+
+    fn clock_length(&mut self) {
+        if !self.envelope.looping && self.length > 0 {
+            self.length -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.length == 0 || self.shift & 1 != 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+struct Dmc {
+    irq_enabled: bool,
+    irq: bool,
+    looping: bool,
+    rate: u16,
+    timer: u16,
+    output_level: u8,
+
+    sample_addr: u16,
+    sample_len: u16,
+    current_addr: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+
+    shift: u8,
+    bits_remaining: u8,
+    silence: bool,
+}
+
+impl Dmc {
+    fn new() -> Self {
+        Dmc {
+            irq_enabled: false,
+            irq: false,
+            looping: false,
+            rate: DMC_RATES[0],
+            timer: 0,
+            output_level: 0,
+
+            sample_addr: 0xc000,
+            sample_len: 1,
+            current_addr: 0xc000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+
+            shift: 0,
+            bits_remaining: 8,
+            silence: true,
+        }
+    }
+
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.irq_enabled = data & 0b1000_0000 != 0;
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+                self.looping = data & 0b0100_0000 != 0;
+                self.rate = DMC_RATES[(data & 0b1111) as usize];
+            }
+            1 => self.output_level = data & 0b0111_1111,
+            2 => self.sample_addr = 0xc000 + data as u16 * 64,
+            _ => self.sample_len = data as u16 * 16 + 1,
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_addr = self.sample_addr;
+        self.bytes_remaining = self.sample_len;
+    }
+
+    fn fetch_addr(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_addr)
+        } else {
+            None
+        }
+    }
+
+    fn fill(&mut self, data: u8) {
+        self.sample_buffer = Some(data);
+        // The address wraps around to $8000 rather than $0000
+        self.current_addr = if self.current_addr == 0xffff { 0x8000 } else { self.current_addr + 1 };
+        self.bytes_remaining -= 1;
+
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.rate - 1;
+
+        if !self.silence {
+            if self.shift & 1 != 0 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift >>= 1;
+        self.bits_remaining -= 1;
+
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(data) => {
+                    self.silence = false;
+                    self.shift = data;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+}
+
+// First order filters matching the analog output stage of the console
+struct Filter {
+    high_pass: bool,
+    alpha: f32,
+    prev_in: f32,
+    prev_out: f32,
+}
+
+impl Filter {
+    fn new(high_pass: bool, cutoff: f32, sample_rate: u32) -> Self {
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff);
+        // Filters built while the output is off are never run
+        let dt = if sample_rate == 0 { 0.0 } else { 1.0 / sample_rate as f32 };
+        let alpha = if high_pass { rc / (rc + dt) } else { dt / (rc + dt) };
+        Filter { high_pass, alpha, prev_in: 0.0, prev_out: 0.0 }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let output = if self.high_pass {
+            self.alpha * (self.prev_out + input - self.prev_in)
+        } else {
+            self.prev_out + self.alpha * (input - self.prev_out)
+        };
+        self.prev_in = input;
+        self.prev_out = output;
+        output
+    }
+}
+
+pub struct APU {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+
+    five_step: bool,
+    frame_irq_inhibit: bool,
+    frame_irq: bool,
+    frame_cycle: u32,
+    cycles: u64,
+
+    sample_rate: u32,
+    // CPU cycles per output sample, the mixer output is averaged over that window
+    sample_period: f64,
+    sample_clock: f64,
+    sample_sum: f32,
+    sample_count: u32,
+    filters: Vec<Filter>,
+    samples: Vec<f32>,
+}
+
+impl APU {
+    /// A `sample_rate` of 0 leaves the output off: the channels still run but nothing is mixed or resampled
+    pub fn new(sample_rate: u32) -> Self {
+        APU {
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            triangle: Triangle::default(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+
+            five_step: false,
+            frame_irq_inhibit: false,
+            frame_irq: false,
+            frame_cycle: 0,
+            cycles: 0,
+
+            sample_rate,
+            sample_period: CPU_CLOCK / sample_rate as f64,
+            sample_clock: 0.0,
+            sample_sum: 0.0,
+            sample_count: 0,
+            filters: APU::output_filters(sample_rate),
+            samples: Vec::new(),
+        }
+    }
+
+    fn output_filters(sample_rate: u32) -> Vec<Filter> {
+        vec![
+            Filter::new(true, 90.0, sample_rate),
+            Filter::new(true, 440.0, sample_rate),
+            Filter::new(false, 14000.0, sample_rate),
+        ]
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Changes the output rate, typically to 44100 or 48000 to match the audio device, or 0 to turn it off
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.sample_period = CPU_CLOCK / sample_rate as f64;
+        self.filters = APU::output_filters(sample_rate);
+        self.samples.clear();
+    }
+
+    /// Hands over the samples produced since the last call, ready to be queued to the audio device
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    /// Advances the APU by one CPU cycle
+    pub fn tick(&mut self) {
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        if self.cycles % 2 == 1 {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+
+        self.clock_frame_counter();
+        self.cycles += 1;
+        if self.sample_rate != 0 {
+            self.push_sample();
+        }
+    }
+
+    // https://www.nesdev.org/wiki/APU_Frame_Counter
+    fn clock_frame_counter(&mut self) {
+        self.frame_cycle += 1;
+
+        match (self.five_step, self.frame_cycle) {
+            (_, 7457) | (_, 22371) => self.clock_quarter_frame(),
+            (_, 14913) => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            (false, 29829) => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+                if !self.frame_irq_inhibit {
+                    self.frame_irq = true;
+                }
+            }
+            (false, 29830) | (true, 37282) => self.frame_cycle = 0,
+            (true, 37281) => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            _ => {}
+        }
+    }
+
+    // Envelopes and the triangle's linear counter
+    fn clock_quarter_frame(&mut self) {
+        self.pulse1.envelope.clock();
+        self.pulse2.envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear();
+    }
+
+    // Length counters and sweep units
+    fn clock_half_frame(&mut self) {
+        self.pulse1.clock_length();
+        self.pulse2.clock_length();
+        self.triangle.clock_length();
+        self.noise.clock_length();
+        self.pulse1.clock_sweep();
+        self.pulse2.clock_sweep();
+    }
+
+    fn mix(&self) -> f32 {
+        let pulse = self.pulse1.output() + self.pulse2.output();
+        let tnd = 3 * self.triangle.output() as usize + 2 * self.noise.output() as usize + self.dmc.output_level as usize;
+        PULSE_TABLE[pulse as usize] + TND_TABLE[tnd]
+    }
+
+    fn push_sample(&mut self) {
+        self.sample_sum += self.mix();
+        self.sample_count += 1;
+        self.sample_clock += 1.0;
+
+        if self.sample_clock < self.sample_period {
+            return;
+        }
+        self.sample_clock -= self.sample_period;
+
+        let mut sample = self.sample_sum / self.sample_count as f32;
+        self.sample_sum = 0.0;
+        self.sample_count = 0;
+        for filter in self.filters.iter_mut() {
+            sample = filter.process(sample);
+        }
+
+        // Keep at most a second of audio around when nobody is draining the buffer
+        if self.samples.len() < self.sample_rate as usize {
+            self.samples.push(sample);
+        }
+    }
+
+    pub fn irq_pending(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+
+    /// Address the DMC wants to read its next sample byte from, if its buffer is empty
+    pub fn dmc_fetch_addr(&self) -> Option<u16> {
+        self.dmc.fetch_addr()
+    }
+
+    pub fn dmc_fill(&mut self, data: u8) {
+        self.dmc.fill(data);
+    }
+
+    pub fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4000..=0x4003 => self.pulse1.write(addr & 0b11, data),
+            0x4004..=0x4007 => self.pulse2.write(addr & 0b11, data),
+            0x4008..=0x400b => self.triangle.write(addr & 0b11, data),
+            0x400c..=0x400f => self.noise.write(addr & 0b11, data),
+            0x4010..=0x4013 => self.dmc.write(addr & 0b11, data),
+            0x4015 => self.write_status(data),
+            0x4017 => self.write_frame_counter(data),
+            _ => {}
+        }
+    }
+
+    // Status (0x4015) - ---D NT21 channel enables
+    fn write_status(&mut self, data: u8) {
+        self.pulse1.enabled = data & 0b0001 != 0;
+        self.pulse2.enabled = data & 0b0010 != 0;
+        self.triangle.enabled = data & 0b0100 != 0;
+        self.noise.enabled = data & 0b1000 != 0;
+
+        if !self.pulse1.enabled { self.pulse1.length = 0; }
+        if !self.pulse2.enabled { self.pulse2.length = 0; }
+        if !self.triangle.enabled { self.triangle.length = 0; }
+        if !self.noise.enabled { self.noise.length = 0; }
+
+        self.dmc.irq = false;
+        if data & 0b1_0000 == 0 {
+            self.dmc.bytes_remaining = 0;
+        } else if self.dmc.bytes_remaining == 0 {
+            self.dmc.restart();
+        }
+    }
+
+    // Status (0x4015) - IF-D NT21: interrupt flags and which channels are still playing
+    pub fn read_status(&mut self) -> u8 {
+        let status = self.peek_status();
+
+        // Reading acknowledges the frame interrupt
+        self.frame_irq = false;
+        status
+    }
+
+    /// What a read of $4015 would return, without acknowledging the frame interrupt
+    pub fn peek_status(&self) -> u8 {
+        let mut status = 0;
+        if self.pulse1.length > 0 { status |= 0b0000_0001; }
+        if self.pulse2.length > 0 { status |= 0b0000_0010; }
+        if self.triangle.length > 0 { status |= 0b0000_0100; }
+        if self.noise.length > 0 { status |= 0b0000_1000; }
+        if self.dmc.bytes_remaining > 0 { status |= 0b0001_0000; }
+        if self.frame_irq { status |= 0b0100_0000; }
+        if self.dmc.irq { status |= 0b1000_0000; }
+        status
+    }
+
+    // Frame counter (0x4017) - MI-- ----: 5-step mode and IRQ inhibit
+    fn write_frame_counter(&mut self, data: u8) {
+        self.five_step = data & 0b1000_0000 != 0;
+        self.frame_irq_inhibit = data & 0b0100_0000 != 0;
+        if self.frame_irq_inhibit {
+            self.frame_irq = false;
+        }
+
+        self.frame_cycle = 0;
+        if self.five_step {
+            self.clock_quarter_frame();
+            self.clock_half_frame();
+        }
+    }
+}
+
+impl Stateful for Envelope {
+    fn save(&self, w: &mut StateWriter) {
+        w.write_bool(self.start);
+        w.write_bool(self.looping);
+        w.write_bool(self.constant);
+        w.write_u8(self.volume);
+        w.write_u8(self.divider);
+        w.write_u8(self.decay);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.start = r.read_bool()?;
+        self.looping = r.read_bool()?;
+        self.constant = r.read_bool()?;
+        self.volume = r.read_u8()?;
+        self.divider = r.read_u8()?;
+        self.decay = r.read_u8()?;
+        Ok(())
+    }
+}
+
+impl Stateful for Pulse {
+    fn save(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_u8(self.duty);
+        w.write_u8(self.sequence);
+        w.write_u16(self.timer_period);
+        w.write_u16(self.timer);
+        w.write_u8(self.length);
+        self.envelope.save(w);
+
+        w.write_bool(self.sweep_enabled);
+        w.write_u8(self.sweep_period);
+        w.write_bool(self.sweep_negate);
+        w.write_u8(self.sweep_shift);
+        w.write_u8(self.sweep_divider);
+        w.write_bool(self.sweep_reload);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.enabled = r.read_bool()?;
+        self.duty = r.read_u8()?;
+        self.sequence = r.read_u8()?;
+        self.timer_period = r.read_u16()?;
+        self.timer = r.read_u16()?;
+        self.length = r.read_u8()?;
+        self.envelope.load(r)?;
+
+        self.sweep_enabled = r.read_bool()?;
+        self.sweep_period = r.read_u8()?;
+        self.sweep_negate = r.read_bool()?;
+        self.sweep_shift = r.read_u8()?;
+        self.sweep_divider = r.read_u8()?;
+        self.sweep_reload = r.read_bool()?;
+        Ok(())
+    }
+}
+
+impl Stateful for Triangle {
+    fn save(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_bool(self.control);
+        w.write_u8(self.linear_reload_value);
+        w.write_u8(self.linear_counter);
+        w.write_bool(self.linear_reload);
+        w.write_u16(self.timer_period);
+        w.write_u16(self.timer);
+        w.write_u8(self.length);
+        w.write_u8(self.sequence);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.enabled = r.read_bool()?;
+        self.control = r.read_bool()?;
+        self.linear_reload_value = r.read_u8()?;
+        self.linear_counter = r.read_u8()?;
+        self.linear_reload = r.read_bool()?;
+        self.timer_period = r.read_u16()?;
+        self.timer = r.read_u16()?;
+        self.length = r.read_u8()?;
+        self.sequence = r.read_u8()?;
+        Ok(())
+    }
+}
+
+impl Stateful for Noise {
+    fn save(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_bool(self.mode);
+        w.write_u16(self.timer_period);
+        w.write_u16(self.timer);
+        w.write_u16(self.shift);
+        w.write_u8(self.length);
+        self.envelope.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.enabled = r.read_bool()?;
+        self.mode = r.read_bool()?;
+        self.timer_period = r.read_u16()?;
+        self.timer = r.read_u16()?;
+        self.shift = r.read_u16()?;
+        self.length = r.read_u8()?;
+        self.envelope.load(r)
+    }
+}
+
+impl Stateful for Dmc {
+    fn save(&self, w: &mut StateWriter) {
+        w.write_bool(self.irq_enabled);
+        w.write_bool(self.irq);
+        w.write_bool(self.looping);
+        w.write_u16(self.rate);
+        w.write_u16(self.timer);
+        w.write_u8(self.output_level);
+
+        w.write_u16(self.sample_addr);
+        w.write_u16(self.sample_len);
+        w.write_u16(self.current_addr);
+        w.write_u16(self.bytes_remaining);
+        w.write_bool(self.sample_buffer.is_some());
+        w.write_u8(self.sample_buffer.unwrap_or(0));
+
+        w.write_u8(self.shift);
+        w.write_u8(self.bits_remaining);
+        w.write_bool(self.silence);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.irq_enabled = r.read_bool()?;
+        self.irq = r.read_bool()?;
+        self.looping = r.read_bool()?;
+        self.rate = r.read_u16()?;
+        self.timer = r.read_u16()?;
+        self.output_level = r.read_u8()?;
+
+        self.sample_addr = r.read_u16()?;
+        self.sample_len = r.read_u16()?;
+        self.current_addr = r.read_u16()?;
+        self.bytes_remaining = r.read_u16()?;
+        let buffered = r.read_bool()?;
+        let data = r.read_u8()?;
+        self.sample_buffer = if buffered { Some(data) } else { None };
+
+        self.shift = r.read_u8()?;
+        self.bits_remaining = r.read_u8()?;
+        self.silence = r.read_bool()?;
+        Ok(())
+    }
+}
+
+// The output rate belongs to the audio device rather than the machine, so only the sequencing and the
+// resampler's progress are saved. Pending samples are dropped on load
+impl Stateful for APU {
+    fn save(&self, w: &mut StateWriter) {
+        self.pulse1.save(w);
+        self.pulse2.save(w);
+        self.triangle.save(w);
+        self.noise.save(w);
+        self.dmc.save(w);
+
+        w.write_bool(self.five_step);
+        w.write_bool(self.frame_irq_inhibit);
+        w.write_bool(self.frame_irq);
+        w.write_u32(self.frame_cycle);
+        w.write_u64(self.cycles);
+
+        w.write_f64(self.sample_clock);
+        w.write_f32(self.sample_sum);
+        w.write_u32(self.sample_count);
+        w.write_u32(self.filters.len() as u32);
+        for filter in self.filters.iter() {
+            w.write_f32(filter.prev_in);
+            w.write_f32(filter.prev_out);
+        }
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.pulse1.load(r)?;
+        self.pulse2.load(r)?;
+        self.triangle.load(r)?;
+        self.noise.load(r)?;
+        self.dmc.load(r)?;
+
+        self.five_step = r.read_bool()?;
+        self.frame_irq_inhibit = r.read_bool()?;
+        self.frame_irq = r.read_bool()?;
+        self.frame_cycle = r.read_u32()?;
+        self.cycles = r.read_u64()?;
+
+        self.sample_clock = r.read_f64()?;
+        self.sample_sum = r.read_f32()?;
+        self.sample_count = r.read_u32()?;
+        if r.read_u32()? as usize != self.filters.len() {
+            return Err("Save state has a different number of audio filters".to_string());
+        }
+        for filter in self.filters.iter_mut() {
+            filter.prev_in = r.read_f32()?;
+            filter.prev_out = r.read_f32()?;
+        }
+        self.samples.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    fn run(apu: &mut APU, cycles: u32) {
+        for _ in 0..cycles {
+            apu.tick();
+        }
+    }
+
+    #[test]
+    fn test_length_counter_status() {
+        let mut apu = APU::new(44100);
+        apu.write_register(0x4015, 0b0000_0001);
+        apu.write_register(0x4000, 0b0001_1111);
+        apu.write_register(0x4003, 0b0000_1000); // length index 1 -> 254
+        apu.write_register(0x4007, 0b0000_1000); // pulse 2 disabled, not loaded
+        assert_eq!(apu.read_status() & 0b11, 0b01);
+
+        apu.write_register(0x4015, 0);
+        assert_eq!(apu.read_status() & 0b11, 0);
+    }
+
+    #[test]
+    fn test_length_counter_runs_out() {
+        let mut apu = APU::new(44100);
+        apu.write_register(0x4015, 0b0000_1000);
+        apu.write_register(0x400f, 0b0001_1000); // length index 3 -> 2 half frames
+
+        run(&mut apu, 14913);
+        assert_eq!(apu.read_status() & 0b1000, 0b1000);
+        run(&mut apu, 29829 - 14913);
+        assert_eq!(apu.read_status() & 0b1000, 0);
+    }
+
+    #[test]
+    fn test_frame_irq() {
+        let mut apu = APU::new(44100);
+        run(&mut apu, 29828);
+        assert!(!apu.irq_pending());
+        run(&mut apu, 1);
+        assert!(apu.irq_pending());
+        assert_eq!(apu.read_status() & 0b0100_0000, 0b0100_0000);
+        assert!(!apu.irq_pending());
+
+        apu.write_register(0x4017, 0b0100_0000);
+        run(&mut apu, 40000);
+        assert!(!apu.irq_pending());
+    }
+
+    #[test]
+    fn test_sweep_mutes_pulse() {
+        let mut apu = APU::new(44100);
+        apu.write_register(0x4015, 0b0000_0001);
+        apu.write_register(0x4000, 0b1011_1111);
+        apu.write_register(0x4001, 0b0000_1000);
+        apu.write_register(0x4002, 0x00);
+        apu.write_register(0x4003, 0b0000_1101);
+        assert!(!apu.pulse1.muted());
+
+        // Sweeping up from 0x500 would overflow the 11-bit period
+        apu.write_register(0x4001, 0b0000_0000);
+        assert!(apu.pulse1.muted());
+        assert_eq!(apu.pulse1.output(), 0);
+    }
+
+    #[test]
+    fn test_dmc_fetch_and_irq() {
+        let mut apu = APU::new(44100);
+        apu.write_register(0x4010, 0b1000_1111);
+        apu.write_register(0x4012, 0x01);
+        apu.write_register(0x4013, 0x00);
+        apu.write_register(0x4015, 0b0001_0000);
+
+        assert_eq!(apu.dmc_fetch_addr(), Some(0xc040));
+        apu.dmc_fill(0xff);
+        assert_eq!(apu.dmc_fetch_addr(), None);
+        assert!(apu.irq_pending());
+        assert_eq!(apu.read_status() & 0b1001_0000, 0b1000_0000);
+    }
+
+    #[test]
+    fn test_sample_rate() {
+        let mut apu = APU::new(48000);
+        run(&mut apu, CPU_CLOCK as u32 / 10);
+        assert!((4799..=4800).contains(&apu.take_samples().len()));
+        assert!(apu.take_samples().is_empty());
+
+        apu.set_sample_rate(44100);
+        run(&mut apu, CPU_CLOCK as u32 / 10);
+        assert!((4409..=4410).contains(&apu.take_samples().len()));
+
+        apu.set_sample_rate(0);
+        run(&mut apu, CPU_CLOCK as u32 / 10);
+        assert!(apu.take_samples().is_empty());
+    }
+}
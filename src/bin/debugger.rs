@@ -0,0 +1,54 @@
+use std::io::{self, BufRead, Write};
+
+use nes_emulator::bus::Bus;
+use nes_emulator::cartridge::Rom;
+use nes_emulator::cpu::CPU;
+use nes_emulator::debugger::Debugger;
+
+// Terminal front end for the debugger: `debugger <rom> [start pc]`, then type `help`
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 2 {
+        eprintln!("usage: {} <rom.nes> [start pc]", args[0]);
+        std::process::exit(1);
+    }
+
+    let bytes = std::fs::read(&args[1]).unwrap_or_else(|e| {
+        eprintln!("Failed to read {}: {}", args[1], e);
+        std::process::exit(1);
+    });
+    let rom = Rom::new(&bytes).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+
+    let mut cpu = CPU::new(Bus::new(rom));
+    cpu.reset();
+    let mut debugger = Debugger::new();
+
+    // nestest runs its automated mode from $C000
+    if let Some(pc) = args.get(2) {
+        if let Err(e) = debugger.command(&mut cpu, &format!("set pc {}", pc)) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    println!("{}", debugger.command(&mut cpu, "dis").unwrap());
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    while !debugger.quit_requested() {
+        print!("> ");
+        io::stdout().flush().unwrap();
+
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => break,
+        };
+        match debugger.command(&mut cpu, &line) {
+            Ok(output) if output.is_empty() => {}
+            Ok(output) => println!("{}", output),
+            Err(e) => println!("error: {}", e),
+        }
+    }
+}
@@ -0,0 +1,70 @@
+use nes_emulator::cartridge::Rom;
+use nes_emulator::disasm;
+
+const USAGE: &str = "usage: disasm <rom.nes> [--bank <n>] [--bank-size <KB>] [--origin <hex addr>]";
+
+// Dumps the PRG-ROM of a .nes file as ca65 source, one `.org` block per bank. Without --origin every bank is
+// placed at $8000 except the last one, which goes at the top of the address space where the vectors live.
+fn main() {
+    if let Err(e) = run(std::env::args().skip(1).collect()) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(args: Vec<String>) -> Result<(), String> {
+    let mut path = None;
+    let mut bank = None;
+    let mut bank_size = 0x4000;
+    let mut origin = None;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value\n{}", arg, USAGE));
+        match arg.as_str() {
+            "--bank" => bank = Some(value()?.parse::<usize>().map_err(|_| "--bank takes a number")?),
+            "--bank-size" => {
+                bank_size = match value()?.as_str() {
+                    "8" => 0x2000,
+                    "16" => 0x4000,
+                    "32" => 0x8000,
+                    _ => return Err("--bank-size is 8, 16 or 32".to_string()),
+                }
+            }
+            "--origin" => {
+                let text = value()?;
+                let addr = u16::from_str_radix(text.trim_start_matches('$').trim_start_matches("0x"), 16)
+                    .map_err(|_| format!("'{}' is not a hex address", text))?;
+                origin = Some(addr);
+            }
+            _ if path.is_none() && !arg.starts_with("--") => path = Some(arg),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+
+    let path = path.ok_or(USAGE)?;
+    let bytes = std::fs::read(&path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let rom = Rom::new(&bytes)?;
+
+    let banks: Vec<&[u8]> = rom.prg_rom.chunks(bank_size).collect();
+    let selected: Vec<usize> = match bank {
+        Some(n) if n < banks.len() => vec![n],
+        Some(n) => return Err(format!("No bank {}, {} has {} banks of {}KB", n, path, banks.len(), bank_size / 1024)),
+        None => (0..banks.len()).collect(),
+    };
+
+    println!("; {} - mapper {}, {} PRG banks of {}KB", path, rom.mapper, banks.len(), bank_size / 1024);
+    println!(".setcpu \"6502\"");
+    for n in selected {
+        let data = banks[n];
+        let origin = origin.unwrap_or(if n + 1 == banks.len() {
+            (0x10000 - data.len()) as u16
+        } else {
+            0x8000
+        });
+        println!();
+        println!("; PRG bank {}", n);
+        print!("{}", disasm::to_ca65(data, origin));
+    }
+    Ok(())
+}
@@ -0,0 +1,152 @@
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use nes_emulator::bus::Bus;
+use nes_emulator::cartridge::Rom;
+use nes_emulator::cpu::CPU;
+use nes_emulator::inputscript::InputScript;
+use nes_emulator::palette::Palette;
+
+const USAGE: &str = "\
+usage: headless <rom.nes> [options]
+  --frames <n>             frames to run, or the most to wait for a condition (default 600)
+  --until-pc <addr>        stop when the CPU is about to execute <addr>
+  --until-mem <addr>=<v>   stop at the end of a frame where memory at <addr> holds <v>
+  --input <file>           scripted controller input, see src/inputscript.rs
+  --png <file>             write the last frame as a PNG
+  --palette <file.pal>     colours for the PNG, 64 or 512 entries
+  --ram <file>             write the 2KB of CPU RAM
+Addresses and values are hex. Exits with 2 when a condition was given but not met.";
+
+struct Options {
+    rom: PathBuf,
+    frames: usize,
+    until_pc: Option<u16>,
+    until_mem: Option<(u16, u8)>,
+    input: Option<PathBuf>,
+    png: Option<PathBuf>,
+    palette: Option<PathBuf>,
+    ram: Option<PathBuf>,
+}
+
+// Runs a ROM without a display, for CI and batch jobs
+fn main() -> ExitCode {
+    let options = match parse_args(std::env::args().skip(1).collect()) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            return ExitCode::from(1);
+        }
+    };
+
+    match run(&options) {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::from(2),
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::from(1)
+        }
+    }
+}
+
+// Returns whether the run ended the way it was asked to
+fn run(options: &Options) -> Result<bool, String> {
+    let bytes = std::fs::read(&options.rom).map_err(|e| format!("Failed to read {}: {}", options.rom.display(), e))?;
+    let script = match &options.input {
+        Some(path) => {
+            let text = std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            Some(InputScript::parse(&text)?)
+        }
+        None => None,
+    };
+    // Load the palette up front so a bad file fails before the run rather than after it
+    let palette = match &options.palette {
+        Some(path) => Palette::load(path)?,
+        None => Palette::default(),
+    };
+
+    let mut cpu = CPU::new(Bus::new(Rom::new(&bytes)?));
+    cpu.reset();
+
+    let has_condition = options.until_pc.is_some() || options.until_mem.is_some();
+    let mut met = None;
+    let mut frame = 0;
+    while frame < options.frames {
+        if let Some(script) = &script {
+            script.apply(frame, &mut cpu.bus);
+        }
+
+        cpu.bus.poll_frame_complete();
+        let until_pc = options.until_pc;
+        cpu.run_until(|cpu| until_pc == Some(cpu.reg_pc) || cpu.bus.poll_frame_complete());
+        if until_pc == Some(cpu.reg_pc) {
+            met = Some(format!("PC reached ${:04X}", cpu.reg_pc));
+            break;
+        }
+
+        frame += 1;
+        if let Some((addr, value)) = options.until_mem {
+            if cpu.bus.peek(addr) == value {
+                met = Some(format!("${:04X} = {:02X}", addr, value));
+                break;
+            }
+        }
+    }
+
+    if let Some(path) = &options.png {
+        cpu.bus.ppu().frame.save_png(path, &palette)?;
+    }
+    if let Some(path) = &options.ram {
+        let ram: Vec<u8> = (0..0x800).map(|addr| cpu.bus.peek(addr)).collect();
+        std::fs::write(path, ram).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    }
+
+    match &met {
+        Some(reason) => println!("stopped after {} frames, {} cycles: {}", frame, cpu.cycles, reason),
+        None => println!("ran {} frames, {} cycles", frame, cpu.cycles),
+    }
+    Ok(met.is_some() || !has_condition)
+}
+
+fn parse_args(args: Vec<String>) -> Result<Options, String> {
+    let mut options = Options {
+        rom: PathBuf::new(),
+        frames: 600,
+        until_pc: None,
+        until_mem: None,
+        input: None,
+        png: None,
+        palette: None,
+        ram: None,
+    };
+    let mut rom = None;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--frames" => options.frames = value()?.parse().map_err(|_| "--frames takes a number")?,
+            "--until-pc" => options.until_pc = Some(parse_hex(&value()?)?),
+            "--until-mem" => {
+                let text = value()?;
+                let (addr, data) = text.split_once('=').ok_or("--until-mem takes <addr>=<value>")?;
+                let data = u8::try_from(parse_hex(data)?).map_err(|_| format!("{} is not a byte", data))?;
+                options.until_mem = Some((parse_hex(addr)?, data));
+            }
+            "--input" => options.input = Some(PathBuf::from(value()?)),
+            "--png" => options.png = Some(PathBuf::from(value()?)),
+            "--palette" => options.palette = Some(PathBuf::from(value()?)),
+            "--ram" => options.ram = Some(PathBuf::from(value()?)),
+            _ if rom.is_none() && !arg.starts_with("--") => rom = Some(PathBuf::from(arg)),
+            _ => return Err(format!("Unexpected argument '{}'", arg)),
+        }
+    }
+
+    options.rom = rom.ok_or("No ROM given")?;
+    Ok(options)
+}
+
+fn parse_hex(text: &str) -> Result<u16, String> {
+    u16::from_str_radix(text.trim_start_matches('$').trim_start_matches("0x"), 16)
+        .map_err(|_| format!("'{}' is not a hex address", text))
+}
@@ -1,7 +1,16 @@
 // From bugzmanov nes_ebook
+use std::cell::RefCell;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use crate::apu::APU;
 use crate::cpu::Mem;
 use crate::cartridge::Rom;
+use crate::joypad::Joypad;
+use crate::mapper::{self, Mapper};
 use crate::ppu::PPU;
+use crate::savestate::{self, StateReader, StateWriter, Stateful};
 
 const RAM: u16 = 0x0000;
 const RAM_MIRRORS_END: u16 = 0x1FFF;
@@ -10,48 +19,176 @@ const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF;
 
 pub struct Bus {
     cpu_vram: [u8; 2048],
-    prg_rom: Vec<u8>,
+    cartridge: Rc<RefCell<dyn Mapper>>,
+    // Hash of the PRG-ROM, save states are only loaded into the game that made them
+    rom_hash: u32,
+    // Where battery-backed PRG-RAM is persisted, only set for cartridges with a battery
+    save_path: Option<PathBuf>,
     ppu: PPU,
+    apu: APU,
+    joypads: [Joypad; 2],
     cycles: usize,
-    //gameloop_callback: Box<dyn FnMut(&NesPPU, &mut Joypad) + 'call>,
+    frame_complete: bool,
+    irq_line: bool,
+    oam_dma_pending: bool,
 }
 
 impl Bus {
     pub fn new(rom: Rom) -> Self {
-        let ppu = PPU::new(rom.chr_rom, rom.screen_mirroring);
+        let rom_hash = savestate::fnv1a(&rom.prg_rom);
+        let cartridge = mapper::from_rom(rom);
+        let ppu = PPU::new(cartridge.clone());
 
         Bus {
             cpu_vram: [0; 2048],
-            prg_rom: rom.prg_rom,
+            cartridge,
+            rom_hash,
+            save_path: None,
             ppu: ppu,
+            // No audio until a front end sets the rate of its device, headless runs skip the resampler
+            apu: APU::new(0),
+            joypads: [Joypad::new(), Joypad::new()],
             cycles: 0,
-            //gameloop_callback: Box::from(gameloop_callback),
+            frame_complete: false,
+            irq_line: false,
+            oam_dma_pending: false,
         }
     }
 
-    fn read_prg_rom(&self, mut addr: u16) -> u8 {
-        addr -= 0x8000;
-        if self.prg_rom.len() == 0x4000 && addr >= 0x4000 {
-            addr = addr % 0x4000;
+    /// Same as `new`, but cartridges with a battery load their PRG-RAM from the .sav file beside `rom_path`
+    /// and write it back on `flush_save` and when the bus is dropped
+    pub fn with_save_file(rom: Rom, rom_path: &Path) -> Result<Self, String> {
+        let battery = rom.battery;
+        let mut bus = Bus::new(rom);
+        if !battery {
+            return Ok(bus);
         }
-        self.prg_rom[addr as usize]
+
+        let save_path = rom_path.with_extension("sav");
+        match fs::read(&save_path) {
+            Ok(data) => {
+                let mut cartridge = bus.cartridge.borrow_mut();
+                let prg_ram = cartridge.prg_ram_mut();
+                let len = data.len().min(prg_ram.len());
+                prg_ram[..len].copy_from_slice(&data[..len]);
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("Failed to read {}: {}", save_path.display(), e)),
+        }
+
+        bus.save_path = Some(save_path);
+        Ok(bus)
+    }
+
+    /// Writes battery-backed PRG-RAM to the .sav file, does nothing for cartridges without a battery
+    pub fn flush_save(&self) -> Result<(), String> {
+        match &self.save_path {
+            Some(path) => fs::write(path, self.cartridge.borrow().prg_ram())
+                .map_err(|e| format!("Failed to write {}: {}", path.display(), e)),
+            None => Ok(()),
+        }
+    }
+
+    // The PPU runs 3 dots for every CPU cycle, the APU runs at the CPU clock
+    pub fn tick(&mut self, cycles: u8) {
+        self.cycles += cycles as usize;
+
+        if self.ppu.tick(cycles * 3) {
+            self.frame_complete = true;
+        }
+
+        for _ in 0..cycles {
+            self.apu.tick();
+            if let Some(addr) = self.apu.dmc_fetch_addr() {
+                let data = self.mem_read(addr);
+                self.apu.dmc_fill(data);
+            }
+        }
+    }
+
+    pub fn ppu(&self) -> &PPU {
+        &self.ppu
+    }
+
+    pub fn apu(&self) -> &APU {
+        &self.apu
+    }
+
+    pub fn apu_mut(&mut self) -> &mut APU {
+        &mut self.apu
+    }
+
+    /// Controller plugged into port 0 ($4016) or port 1 ($4017)
+    pub fn joypad_mut(&mut self, port: usize) -> &mut Joypad {
+        &mut self.joypads[port]
+    }
+
+    pub fn cycles(&self) -> usize {
+        self.cycles
+    }
+
+    /// Returns true once per frame after the PPU wraps around from the pre-render scanline
+    pub fn poll_frame_complete(&mut self) -> bool {
+        std::mem::take(&mut self.frame_complete)
     }
 
-    // pub fn tick(&mut self, cycles: u8) {
-    //     self.cycles += cycles as usize;
+    /// CPU cycles to stall for an OAM DMA started by the last instruction: 513, plus one to align
+    /// to a read cycle when the write landed on an odd cycle
+    pub fn poll_dma_stall(&mut self) -> usize {
+        if std::mem::take(&mut self.oam_dma_pending) {
+            513 + self.cycles % 2
+        } else {
+            0
+        }
+    }
 
-    //     let nmi_before = self.ppu.nmi_interrupt.is_some();
-    //     self.ppu.tick(cycles *3);
-    //     let nmi_after = self.ppu.nmi_interrupt.is_some();
-        
-    //     if !nmi_before && nmi_after {
-    //         (self.gameloop_callback)(&self.ppu, &mut self.joypad1);
-    //     }
-    // }
-    
     pub fn poll_nmi_status(&mut self) -> Option<u8> {
         self.ppu.poll_nmi_interrupt()
     }
+
+    /// IRQ is level triggered: the line stays asserted until the device that raised it acknowledges it
+    pub fn set_irq_line(&mut self, asserted: bool) {
+        self.irq_line = asserted;
+    }
+
+    pub fn poll_irq_status(&self) -> bool {
+        self.irq_line || self.apu.irq_pending() || self.cartridge.borrow().irq_pending()
+    }
+}
+
+impl Bus {
+    /// What `mem_read` would return for `addr`, without the side effects of reading the PPU, APU and controller
+    /// registers. For debuggers and tracers
+    pub fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            RAM..=RAM_MIRRORS_END => {
+                let mirror_down_addr = addr & 0b00000111_11111111;
+                self.cpu_vram[mirror_down_addr as usize]
+            }
+
+            0x2002 => self.ppu.peek_status(),
+            0x2004 => self.ppu.read_oam_data(),
+            0x2007 => self.ppu.peek_data(),
+
+            0x2008..=PPU_REGISTERS_MIRRORS_END => {
+                let mirror_down_addr = addr & 0b00100000_00000111;
+                self.peek(mirror_down_addr)
+            }
+
+            0x4015 => self.apu.peek_status(),
+            0x4016 => self.joypads[0].peek(),
+            0x4017 => self.joypads[1].peek(),
+
+            0x6000..=0xFFFF => self.cartridge.borrow().cpu_peek(addr),
+            _ => 0,
+        }
+    }
+
+    pub fn peek_u16(&self, addr: u16) -> u16 {
+        let lo = self.peek(addr) as u16;
+        let hi = self.peek(addr.wrapping_add(1)) as u16;
+        (hi << 8) | lo
+    }
 }
 
 impl Mem for Bus {
@@ -72,7 +209,11 @@ impl Mem for Bus {
                 self.mem_read(mirror_down_addr)
             }
 
-            0x8000..=0xFFFF => self.read_prg_rom(addr),
+            0x4015 => self.apu.read_status(),
+            0x4016 => self.joypads[0].read(),
+            0x4017 => self.joypads[1].read(),
+
+            0x6000..=0xFFFF => self.cartridge.borrow().cpu_read(addr),
             _ => 0,
         }
     }
@@ -92,7 +233,8 @@ impl Mem for Bus {
                 self.ppu.write_mask(data);
             }
 
-            0x2002 => panic!("attempt to write to PPU status register"),
+            // PPUSTATUS is read-only, writes go nowhere
+            0x2002 => {}
 
             0x2003 => {
                 self.ppu.write_oam_addr(data);
@@ -119,11 +261,175 @@ impl Mem for Bus {
                 self.mem_write(mirror_down_addr, data);
             }
 
-            0x8000..=0xFFFF => panic!("Attempt to write to Cartridge ROM space: {:x}", addr),
+            // Copies page $XX00-$XXFF into OAM
+            0x4014 => {
+                let mut buffer: [u8; 256] = [0; 256];
+                let hi: u16 = (data as u16) << 8;
+                for i in 0..256u16 {
+                    buffer[i as usize] = self.mem_read(hi + i);
+                }
+
+                self.ppu.write_oam_dma(&buffer);
+                self.oam_dma_pending = true;
+            }
+
+            0x4000..=0x4013 | 0x4015 | 0x4017 => {
+                self.apu.write_register(addr, data);
+            }
+
+            // The strobe line is shared by both controller ports
+            0x4016 => {
+                for joypad in self.joypads.iter_mut() {
+                    joypad.write(data);
+                }
+            }
+
+            0x6000..=0xFFFF => {
+                self.cartridge.borrow_mut().cpu_write(addr, data);
+            }
 
             _ => {
-                println!("Ignoring mem write-access at {}", addr);
+                eprintln!("Ignoring mem write-access at {}", addr);
             }
         }
     }
-}
\ No newline at end of file
+}
+
+impl Stateful for Bus {
+    fn save(&self, w: &mut StateWriter) {
+        w.write_u32(self.rom_hash);
+        w.write_bytes(&self.cpu_vram);
+        w.write_usize(self.cycles);
+        w.write_bool(self.frame_complete);
+        w.write_bool(self.irq_line);
+        w.write_bool(self.oam_dma_pending);
+
+        self.cartridge.borrow().save(w);
+        self.ppu.save(w);
+        self.apu.save(w);
+        for joypad in self.joypads.iter() {
+            joypad.save(w);
+        }
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+        if r.read_u32()? != self.rom_hash {
+            return Err("Save state was made with a different ROM".to_string());
+        }
+        r.read_bytes_into(&mut self.cpu_vram)?;
+        self.cycles = r.read_usize()?;
+        self.frame_complete = r.read_bool()?;
+        self.irq_line = r.read_bool()?;
+        self.oam_dma_pending = r.read_bool()?;
+
+        self.cartridge.borrow_mut().load(r)?;
+        self.ppu.load(r)?;
+        self.apu.load(r)?;
+        for joypad in self.joypads.iter_mut() {
+            joypad.load(r)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Bus {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush_save() {
+            eprintln!("{}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+    use crate::joypad::JoypadButton;
+
+    fn battery_rom() -> Rom {
+        let mut rom = test_rom(vec![]);
+        rom.battery = true;
+        rom.prg_ram_size = 0;
+        rom.prg_nvram_size = 0x2000;
+        rom
+    }
+
+    #[test]
+    fn test_battery_save_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("nes-save-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let rom_path = dir.join("game.nes");
+
+        let mut bus = Bus::with_save_file(battery_rom(), &rom_path).unwrap();
+        bus.mem_write(0x6010, 0x42);
+        drop(bus);
+
+        let saved = fs::read(dir.join("game.sav")).unwrap();
+        assert_eq!(saved.len(), 0x2000);
+        assert_eq!(saved[0x10], 0x42);
+
+        let mut bus = Bus::with_save_file(battery_rom(), &rom_path).unwrap();
+        assert_eq!(bus.mem_read(0x6010), 0x42);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_no_save_without_battery() {
+        let dir = std::env::temp_dir().join(format!("nes-nosave-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let rom_path = dir.join("game.nes");
+
+        let mut bus = Bus::with_save_file(test_rom(vec![]), &rom_path).unwrap();
+        bus.mem_write(0x6010, 0x42);
+        assert_eq!(bus.mem_read(0x6010), 0x42);
+        drop(bus);
+
+        assert!(!dir.join("game.sav").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_to_status_is_ignored() {
+        let mut bus = Bus::new(test_rom(vec![]));
+        while bus.ppu().scanline() != 241 || bus.ppu().dot() < 2 {
+            bus.tick(1);
+        }
+        bus.mem_write(0x2002, 0x00);
+        bus.mem_write(0x200a, 0x00);
+        assert_eq!(bus.peek(0x2002) & 0x80, 0x80);
+    }
+
+    #[test]
+    fn test_peek_has_no_side_effects() {
+        let mut bus = Bus::new(test_rom(vec![]));
+        while bus.ppu().scanline() != 241 || bus.ppu().dot() < 2 {
+            bus.tick(1);
+        }
+        assert_eq!(bus.peek(0x2002) & 0x80, 0x80);
+        assert_eq!(bus.peek(0x200a) & 0x80, 0x80);
+        assert_eq!(bus.mem_read(0x2002) & 0x80, 0x80);
+        assert_eq!(bus.peek(0x2002) & 0x80, 0);
+
+        // PPUDATA reads are buffered, peeking neither advances the address nor refills the buffer
+        bus.mem_write(0x2006, 0x20);
+        bus.mem_write(0x2006, 0x00);
+        bus.mem_write(0x2007, 0x55);
+        bus.mem_write(0x2006, 0x20);
+        bus.mem_write(0x2006, 0x00);
+        assert_eq!(bus.peek(0x2007), 0);
+        assert_eq!(bus.peek(0x2007), 0);
+        assert_eq!(bus.mem_read(0x2007), 0);
+        assert_eq!(bus.peek(0x2007), 0x55);
+        assert_eq!(bus.ppu().peek_vram(0x2000), 0x55);
+
+        bus.joypad_mut(0).set_button_pressed_status(JoypadButton::BUTTON_A, true);
+        bus.mem_write(0x4016, 1);
+        bus.mem_write(0x4016, 0);
+        assert_eq!(bus.peek(0x4016), 1);
+        assert_eq!(bus.mem_read(0x4016), 1);
+        assert_eq!(bus.peek(0x4016), 0);
+
+        assert_eq!(bus.peek_u16(0xfffc), 0x8000);
+    }
+}
@@ -1,33 +1,80 @@
 // From bugzmanov nes_ebook
+use crate::mapper;
+
 const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
 const PRG_ROM_PAGE_SIZE: usize = 16384;
 const CHR_ROM_PAGE_SIZE: usize = 8192;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[allow(non_camel_case_types)]
 pub enum Mirroring {
     VERTICAL,
     HORIZONTAL,
     FOURSCREEN,
+    // Single-screen mirroring selected at runtime by mappers such as MMC1
+    ONESCREEN_LO,
+    ONESCREEN_HI,
+}
+
+// CPU/PPU timing the cartridge was made for
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Timing {
+    NTSC,
+    PAL,
+    MULTIREGION,
+    DENDY,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[allow(non_camel_case_types)]
+pub enum ConsoleType {
+    NES,
+    VS_SYSTEM,
+    PLAYCHOICE,
+    // Extended console type from byte 13 of an NES 2.0 header
+    EXTENDED(u8),
 }
 
 pub struct Rom {
     pub prg_rom: Vec<u8>,
     pub chr_rom: Vec<u8>,
-    pub mapper: u8,
+    pub mapper: u16,
+    pub submapper: u8,
     pub screen_mirroring: Mirroring,
+    // Battery keeps the PRG-NVRAM contents while the console is off
+    pub battery: bool,
+
+    // RAM sizes in bytes, NVRAM being the battery backed part
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+
+    pub timing: Timing,
+    pub console_type: ConsoleType,
+    // Default expansion device as numbered in https://www.nesdev.org/wiki/NES_2.0#Default_Expansion_Device
+    pub expansion_device: u8,
 }
 
 impl Rom {
     pub fn new(raw: &Vec<u8>) -> Result<Rom, String> {
-        if &raw[0..4] != NES_TAG {
+        if raw.len() < 16 || raw[0..4] != NES_TAG {
             return Err("File is not in iNES file format".to_string());
         }
 
-        let mapper = (raw[7] & 0b1111_0000) | (raw[6] >> 4);
-
         let ines_ver = (raw[7] >> 2) & 0b11;
-        if ines_ver != 0 {
-            return Err("NES2.0 format is not supported".to_string());
+        let nes2 = match ines_ver {
+            0 => false,
+            2 => true,
+            _ => return Err("Unknown iNES header version".to_string()),
+        };
+
+        let mut mapper = ((raw[7] & 0b1111_0000) | (raw[6] >> 4)) as u16;
+        if nes2 {
+            mapper |= ((raw[8] & 0b1111) as u16) << 8;
+        }
+        if !mapper::is_supported(mapper) {
+            return Err(format!("Mapper {} is not supported", mapper));
         }
 
         let four_screen = raw[6] & 0b1000 != 0;
@@ -38,19 +85,207 @@ impl Rom {
             (false, false) => Mirroring::HORIZONTAL,
         };
 
-        let prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
-        let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+        let console_type = match raw[7] & 0b11 {
+            0 => ConsoleType::NES,
+            1 => ConsoleType::VS_SYSTEM,
+            2 => ConsoleType::PLAYCHOICE,
+            _ if nes2 => ConsoleType::EXTENDED(raw[13] & 0b1111),
+            _ => ConsoleType::EXTENDED(0),
+        };
+
+        let (prg_rom_size, chr_rom_size) = if nes2 {
+            (
+                nes2_rom_size(raw[4], raw[9] & 0b1111, PRG_ROM_PAGE_SIZE)?,
+                nes2_rom_size(raw[5], raw[9] >> 4, CHR_ROM_PAGE_SIZE)?,
+            )
+        } else {
+            (raw[4] as usize * PRG_ROM_PAGE_SIZE, raw[5] as usize * CHR_ROM_PAGE_SIZE)
+        };
 
         let skip_trainer = raw[6] & 0b100 != 0;
 
-        let prg_rom_start = 16 + if skip_trainer { 512 } else { 0 };
-        let chr_rom_start = prg_rom_start + prg_rom_size;
+        // Exponent sizes from a malformed NES 2.0 header can be large enough to overflow these
+        let prg_rom_start: usize = 16 + if skip_trainer { 512 } else { 0 };
+        let chr_rom_start = prg_rom_start.checked_add(prg_rom_size).ok_or("ROM size overflows")?;
+        let chr_rom_end = chr_rom_start.checked_add(chr_rom_size).ok_or("ROM size overflows")?;
+
+        if raw.len() < chr_rom_end {
+            return Err("File is shorter than the ROM sizes in its header".to_string());
+        }
+
+        let mut rom = Rom {
+            prg_rom: raw[prg_rom_start..chr_rom_start].to_vec(),
+            chr_rom: raw[chr_rom_start..chr_rom_end].to_vec(),
+            mapper,
+            screen_mirroring,
+            battery: raw[6] & 0b10 != 0,
+            console_type,
+            ..Rom::default()
+        };
+
+        if nes2 {
+            rom.submapper = raw[8] >> 4;
+            rom.prg_ram_size = nes2_ram_size(raw[10] & 0b1111);
+            rom.prg_nvram_size = nes2_ram_size(raw[10] >> 4);
+            rom.chr_ram_size = nes2_ram_size(raw[11] & 0b1111);
+            rom.chr_nvram_size = nes2_ram_size(raw[11] >> 4);
+            rom.timing = match raw[12] & 0b11 {
+                0 => Timing::NTSC,
+                1 => Timing::PAL,
+                2 => Timing::MULTIREGION,
+                _ => Timing::DENDY,
+            };
+            rom.expansion_device = raw[15] & 0b0011_1111;
+        } else {
+            // iNES 1.0 has no RAM sizes, assume the common 8K of PRG-RAM and 8K of CHR-RAM on boards without CHR-ROM.
+            // The battery flag decides whether that PRG-RAM is saved
+            if rom.battery {
+                rom.prg_nvram_size = 0x2000;
+            } else {
+                rom.prg_ram_size = 0x2000;
+            }
+            rom.chr_ram_size = if chr_rom_size == 0 { 0x2000 } else { 0 };
+        }
+
+        Ok(rom)
+    }
+}
+
+impl Default for Rom {
+    fn default() -> Self {
+        Rom {
+            prg_rom: vec![],
+            chr_rom: vec![],
+            mapper: 0,
+            submapper: 0,
+            screen_mirroring: Mirroring::HORIZONTAL,
+            battery: false,
+
+            prg_ram_size: 0,
+            prg_nvram_size: 0,
+            chr_ram_size: 0,
+            chr_nvram_size: 0,
+
+            timing: Timing::NTSC,
+            console_type: ConsoleType::NES,
+            expansion_device: 0,
+        }
+    }
+}
+
+// NES 2.0 ROM size: the MSB nibble extends the page count, or when it is 0xF the LSB byte
+// is read as EEEEEEMM and the size in bytes is 2^E * (MM * 2 + 1)
+fn nes2_rom_size(lsb: u8, msb: u8, page_size: usize) -> Result<usize, String> {
+    if msb != 0b1111 {
+        return Ok((((msb as usize) << 8) | lsb as usize) * page_size);
+    }
+
+    let exponent = (lsb >> 2) as u32;
+    let multiplier = (lsb & 0b11) as usize * 2 + 1;
+    1usize
+        .checked_shl(exponent)
+        .and_then(|size| size.checked_mul(multiplier))
+        .ok_or_else(|| "ROM size in header is too large".to_string())
+}
+
+// NES 2.0 RAM size: a shift count of 0 means no RAM, otherwise 64 << shift bytes
+fn nes2_ram_size(shift: u8) -> usize {
+    if shift == 0 { 0 } else { 64 << shift }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    // Builds a single-bank NROM image with the program at 0x8000 and the reset vector pointing to it
+    pub fn test_rom(program: Vec<u8>) -> Rom {
+        let mut raw = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x00, 0x00, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut prg_rom = vec![0; PRG_ROM_PAGE_SIZE];
+        prg_rom[..program.len()].copy_from_slice(&program);
+        prg_rom[0x3FFC] = 0x00;
+        prg_rom[0x3FFD] = 0x80;
+
+        raw.extend(prg_rom);
+        raw.extend(vec![0; CHR_ROM_PAGE_SIZE]);
+        Rom::new(&raw).unwrap()
+    }
+
+    #[test]
+    fn test_ines_battery() {
+        let mut raw = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x00, 0b10, 0x00, 0, 0, 0, 0, 0, 0, 0, 0];
+        raw.extend(vec![0; PRG_ROM_PAGE_SIZE]);
+
+        let rom = Rom::new(&raw).unwrap();
+        assert!(rom.battery);
+        assert_eq!(rom.prg_ram_size, 0);
+        assert_eq!(rom.prg_nvram_size, 0x2000);
+    }
+
+    fn nes2_header(bytes: [u8; 12]) -> Vec<u8> {
+        let mut raw = vec![0x4E, 0x45, 0x53, 0x1A];
+        raw.extend(bytes);
+        raw
+    }
+
+    #[test]
+    fn test_ines_defaults() {
+        let rom = test_rom(vec![]);
+        assert_eq!(rom.mapper, 0);
+        assert!(!rom.battery);
+        assert_eq!(rom.prg_ram_size, 0x2000);
+        assert_eq!(rom.prg_nvram_size, 0);
+        assert_eq!(rom.chr_ram_size, 0);
+        assert_eq!(rom.timing, Timing::NTSC);
+        assert_eq!(rom.console_type, ConsoleType::NES);
+    }
+
+    #[test]
+    fn test_nes2_header() {
+        let mut raw = nes2_header([0x02, 0x01, 0x41, 0x08, 0x50, 0x00, 0x97, 0x07, 0x01, 0x00, 0x00, 0x01]);
+        raw.extend(vec![0; 2 * PRG_ROM_PAGE_SIZE + CHR_ROM_PAGE_SIZE]);
+
+        let rom = Rom::new(&raw).unwrap();
+        assert_eq!(rom.mapper, 4);
+        assert_eq!(rom.submapper, 5);
+        assert_eq!(rom.screen_mirroring, Mirroring::VERTICAL);
+        assert_eq!(rom.prg_rom.len(), 2 * PRG_ROM_PAGE_SIZE);
+        assert_eq!(rom.chr_rom.len(), CHR_ROM_PAGE_SIZE);
+        assert_eq!(rom.prg_ram_size, 0x2000);
+        assert_eq!(rom.prg_nvram_size, 0x8000);
+        assert_eq!(rom.chr_ram_size, 0x2000);
+        assert_eq!(rom.chr_nvram_size, 0);
+        assert_eq!(rom.timing, Timing::PAL);
+        assert_eq!(rom.console_type, ConsoleType::NES);
+        assert_eq!(rom.expansion_device, 1);
+    }
+
+    #[test]
+    fn test_nes2_exponent_size() {
+        // PRG size of 2^14 * 3 bytes, no CHR
+        assert_eq!(nes2_rom_size(0b0011_1001, 0xf, PRG_ROM_PAGE_SIZE), Ok(0xc000));
+        assert_eq!(nes2_rom_size(0x02, 0x1, PRG_ROM_PAGE_SIZE), Ok(0x102 * PRG_ROM_PAGE_SIZE));
+        assert!(nes2_rom_size(0xff, 0xf, PRG_ROM_PAGE_SIZE).is_err());
+    }
+
+    #[test]
+    fn test_nes2_exponent_size_overflow() {
+        // PRG and CHR both 2^63 bytes, which fit on their own but not added together
+        let mut raw = nes2_header([0xfc, 0xfc, 0x00, 0x08, 0x00, 0xff, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        raw.extend(vec![0; PRG_ROM_PAGE_SIZE]);
+        assert_eq!(Rom::new(&raw).err(), Some("ROM size overflows".to_string()));
+    }
+
+    #[test]
+    fn test_nes2_extended_mapper_rejected() {
+        let mut raw = nes2_header([0x01, 0x01, 0x00, 0x08, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        raw.extend(vec![0; PRG_ROM_PAGE_SIZE + CHR_ROM_PAGE_SIZE]);
+        assert_eq!(Rom::new(&raw).err(), Some("Mapper 256 is not supported".to_string()));
+    }
 
-        Ok(Rom {
-            prg_rom: raw[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec(),
-            chr_rom: raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec(),
-            mapper: mapper,
-            screen_mirroring: screen_mirroring,
-        })
+    #[test]
+    fn test_truncated_rom() {
+        let mut raw = nes2_header([0x02, 0x01, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        raw.extend(vec![0; PRG_ROM_PAGE_SIZE]);
+        assert!(Rom::new(&raw).is_err());
     }
 }
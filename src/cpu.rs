@@ -1,5 +1,9 @@
+use std::rc::Rc;
+
 use crate::opcodes::InstructionSet;
 use crate::bus::Bus;
+use crate::debugcodes;
+use crate::savestate::{self, StateReader, StateWriter, Stateful};
 
 bitflags! {
     pub struct StatusFlags: u8 {
@@ -16,6 +20,51 @@ bitflags! {
 
 const STACK: u16 = 0x0100;
 const STACK_RESET: u8 = 0xfd;
+const NMI_VECTOR: u16 = 0xFFFA;
+const IRQ_VECTOR: u16 = 0xFFFE;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Interrupt {
+    NMI,
+    IRQ,
+    BRK,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum StopReason {
+    /// A single instruction was executed by `step`
+    Step,
+    /// The cycle budget given to `run_cycles` was used up
+    CyclesElapsed,
+    /// The PPU finished rendering a frame
+    FrameComplete,
+    /// The predicate given to `run_until` returned true
+    Predicate,
+    /// `request_stop` was called while running
+    StopRequested,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Address range whose data reads and/or writes by the CPU are reported through `poll_watch_hit`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end:   u16,
+    pub read:  bool,
+    pub write: bool,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct WatchHit {
+    pub addr:   u16,
+    pub access: Access,
+    pub data:   u8,
+}
 
 pub struct CPU {
     pub reg_pc:        u16,
@@ -25,6 +74,21 @@ pub struct CPU {
     pub reg_stack_ptr: u8,
     pub reg_status:    StatusFlags,
     pub bus:           Bus,
+
+    /// Total CPU cycles elapsed since power on, including page-cross and branch penalties
+    pub cycles:        usize,
+    // Set by the addressing mode and branch handlers while an instruction executes
+    pub(crate) page_crossed: bool,
+    pub(crate) extra_cycles: u8,
+
+    /// Checked on every CPU access. Reads of the executing instruction's own bytes are not reported
+    pub watchpoints:   Vec<Watchpoint>,
+
+    matrix:            Rc<InstructionSet>,
+    stop_requested:    bool,
+    instruction_pc:    u16,
+    instruction_len:   u16,
+    watch_hit:         Option<WatchHit>,
 }
 
 pub trait Mem {
@@ -48,20 +112,19 @@ pub trait Mem {
 
 impl Mem for CPU {
     fn mem_read(&mut self, addr: u16) -> u8 {
-        self.bus.mem_read(addr)
+        let data = self.bus.mem_read(addr);
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(addr, Access::Read, data);
+        }
+        data
     }
 
     fn mem_write(&mut self, addr: u16, data: u8) {
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(addr, Access::Write, data);
+        }
         self.bus.mem_write(addr, data)
     }
-
-    fn mem_read_u16(&mut self, pos: u16) -> u16 {
-        self.bus.mem_read_u16(pos)
-    }
-
-    fn mem_write_u16(&mut self, pos: u16, data: u16) {
-        self.bus.mem_write_u16(pos, data)
-    }
 }
 
 impl CPU {
@@ -74,6 +137,18 @@ impl CPU {
             reg_stack_ptr: STACK_RESET,
             reg_status:    StatusFlags::from_bits_truncate(0b100100),
             bus:           bus,
+
+            cycles:        0,
+            page_crossed:  false,
+            extra_cycles:  0,
+
+            watchpoints:   Vec::new(),
+
+            matrix:        Rc::new(InstructionSet::new()),
+            stop_requested: false,
+            instruction_pc: 0,
+            instruction_len: 0,
+            watch_hit:     None,
         }
     }
 
@@ -114,6 +189,39 @@ impl CPU {
         self.stack_push(lo);
     }
 
+    // NMI and IRQ push the status with the break flag cleared, BRK pushes it set. All three mask further IRQs
+    pub fn interrupt(&mut self, interrupt: Interrupt) {
+        self.stack_push_u16(self.reg_pc);
+
+        let mut flags = self.reg_status;
+        flags.set(StatusFlags::BREAK, interrupt == Interrupt::BRK);
+        flags.insert(StatusFlags::UNUSED);
+        self.stack_push(flags.bits());
+        self.reg_status.insert(StatusFlags::INTERRUPT);
+
+        self.reg_pc = match interrupt {
+            Interrupt::NMI => self.mem_read_u16(NMI_VECTOR),
+            Interrupt::IRQ | Interrupt::BRK => self.mem_read_u16(IRQ_VECTOR),
+        };
+    }
+
+    // Hardware interrupts are checked between instructions. NMI takes priority over IRQ, and IRQ is ignored
+    // while the interrupt disable flag is set
+    fn service_interrupts(&mut self) {
+        let interrupt = if self.bus.poll_nmi_status().is_some() {
+            Interrupt::NMI
+        } else if self.bus.poll_irq_status() && !self.reg_status.contains(StatusFlags::INTERRUPT) {
+            Interrupt::IRQ
+        } else {
+            return;
+        };
+
+        self.instruction_len = 0;
+        self.interrupt(interrupt);
+        self.cycles += 7;
+        self.bus.tick(7);
+    }
+
     pub fn reset(&mut self) {
         self.reg_acc = 0;
         self.reg_x = 0;
@@ -123,43 +231,459 @@ impl CPU {
 
         // Reset program counter to the start of program ROM
         self.reg_pc = self.mem_read_u16(0xFFFC);
+
+        // The reset sequence takes 7 cycles before the first instruction is fetched
+        self.cycles = 7;
+        self.bus.tick(7);
+    }
+
+    /// Asks the current run loop to return with `StopReason::StopRequested` before the next instruction
+    pub fn request_stop(&mut self) {
+        self.stop_requested = true;
     }
-    
-    pub fn load_and_run(&mut self, program: Vec<u8>) {
-        self.load(program);
-        self.reset();
-        self.run();
+
+    /// Returns the first watched access made since the last call
+    pub fn poll_watch_hit(&mut self) -> Option<WatchHit> {
+        self.watch_hit.take()
     }
 
-    pub fn load(&mut self, program: Vec<u8>) {
-        // Memory location [0x8000 .. 0xFFFF] is reserved for program ROM
-        // Copy program ROM into NES memory starting from position 0x8000
-        // Write reference of the start of program ROM to NES memory position 0xFFFC
-        for i in 0..(program.len() as u16) {
-            self.mem_write(0x0600 + i, program[i as usize]);
+    fn check_watchpoints(&mut self, addr: u16, access: Access, data: u8) {
+        if self.watch_hit.is_some() {
+            return;
+        }
+
+        // Opcodes fetch their operands through mem_read, those are not data reads
+        if access == Access::Read && addr.wrapping_sub(self.instruction_pc) < self.instruction_len {
+            return;
+        }
+
+        let watched = self.watchpoints.iter().any(|w| {
+            (w.start..=w.end).contains(&addr) && match access {
+                Access::Read => w.read,
+                Access::Write => w.write,
+            }
+        });
+        if watched {
+            self.watch_hit = Some(WatchHit { addr, access, data });
         }
-        self.mem_write_u16(0xFFFC, 0x0600);
     }
 
-    pub fn run(&mut self) {
-        self.run_with_callback(|_| {});
+    /// Services any pending interrupt and executes exactly one instruction
+    pub fn step(&mut self) -> StopReason {
+        self.service_interrupts();
+        self.execute();
+        StopReason::Step
+    }
+
+    pub fn run_cycles(&mut self, cycles: usize) -> StopReason {
+        let target = self.cycles + cycles;
+        self.run_loop(|cpu| (cpu.cycles >= target).then_some(StopReason::CyclesElapsed))
+    }
+
+    pub fn run_frame(&mut self) -> StopReason {
+        // Drop a frame completion left over from a previous run so we stop at the end of the next one
+        self.bus.poll_frame_complete();
+        self.run_loop(|cpu| cpu.bus.poll_frame_complete().then_some(StopReason::FrameComplete))
     }
-    
-    pub fn run_with_callback<F>(&mut self, mut callback: F) 
+
+    /// Runs until `predicate` returns true. The predicate is checked before every instruction
+    pub fn run_until<P>(&mut self, mut predicate: P) -> StopReason
+    where
+    P: FnMut(&mut CPU) -> bool,
+    {
+        self.run_loop(|cpu| predicate(cpu).then_some(StopReason::Predicate))
+    }
+
+    /// Calls `callback` before every instruction and runs until the callback calls `request_stop`
+    pub fn run_with_callback<F>(&mut self, mut callback: F) -> StopReason
     where 
     F: FnMut(&mut CPU),
     {
-        let matrix = InstructionSet::new();
-        
-        while self.mem_read(self.reg_pc) != 0x00 {
-            callback(self);
-            let code = self.mem_read(self.reg_pc);
-            
-            matrix.call_opcode(code)(&matrix, self);
-            self.reg_pc += 1;
+        self.run_loop(|cpu| {
+            callback(cpu);
+            None
+        })
+    }
+
+    fn run_loop<F>(&mut self, mut check: F) -> StopReason
+    where
+    F: FnMut(&mut CPU) -> Option<StopReason>,
+    {
+        loop {
+            self.service_interrupts();
+
+            if let Some(reason) = check(self) {
+                return reason;
+            }
+
+            if std::mem::take(&mut self.stop_requested) {
+                return StopReason::StopRequested;
+            }
+
+            self.execute();
+        }
+    }
+
+    /// Snapshots the whole machine: CPU, RAM, PPU, APU, controllers and cartridge state
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.write_u32(u32::from_le_bytes(savestate::MAGIC));
+        w.write_u16(savestate::VERSION);
+        self.save(&mut w);
+        w.into_bytes()
+    }
+
+    /// Restores a snapshot made by `save_state`. On error the machine is left as it was
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let backup = self.save_state();
+        let result = self.load_state_unchecked(data);
+        if result.is_err() {
+            self.load_state_unchecked(&backup).expect("restoring the state from before the failed load");
+        }
+        result
+    }
+
+    fn load_state_unchecked(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut r = StateReader::new(data);
+        if r.read_u32()? != u32::from_le_bytes(savestate::MAGIC) {
+            return Err("Not a save state".to_string());
+        }
+        let version = r.read_u16()?;
+        if version != savestate::VERSION {
+            return Err(format!("Save state version {} is not supported", version));
+        }
+
+        Stateful::load(self, &mut r)?;
+        if !r.is_empty() {
+            return Err("Save state has trailing data".to_string());
+        }
+        Ok(())
+    }
+
+    // Executes the instruction at the program counter, advances the rest of the system by the cycles it took
+    // and returns that cycle count
+    fn execute(&mut self) -> u8 {
+        let matrix = Rc::clone(&self.matrix);
+        self.instruction_pc = self.reg_pc;
+        self.instruction_len = 1;
+        let code = self.mem_read(self.reg_pc);
+        self.instruction_len = debugcodes::DEBUG_OPCODES[code as usize].len as u16;
+        self.page_crossed = false;
+        self.extra_cycles = 0;
+
+        matrix.call_opcode(code)(&matrix, self);
+        self.reg_pc = self.reg_pc.wrapping_add(1);
+
+        let mut cycles = matrix.get_cycle(code) + self.extra_cycles;
+        if self.page_crossed && matrix.has_page_penalty(code) {
+            cycles += 1;
+        }
+        self.cycles += cycles as usize;
+        self.bus.tick(cycles);
 
-            
+        // The CPU is halted while OAM DMA copies its page, the rest of the system keeps running
+        let stall = self.bus.poll_dma_stall();
+        self.cycles += stall;
+        for _ in 0..stall {
+            self.bus.tick(1);
+        }
+        cycles
+    }
+}
+
+impl Stateful for CPU {
+    fn save(&self, w: &mut StateWriter) {
+        w.write_u16(self.reg_pc);
+        w.write_u8(self.reg_acc);
+        w.write_u8(self.reg_x);
+        w.write_u8(self.reg_y);
+        w.write_u8(self.reg_stack_ptr);
+        w.write_u8(self.reg_status.bits());
+        w.write_usize(self.cycles);
+        self.bus.save(w);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.reg_pc = r.read_u16()?;
+        self.reg_acc = r.read_u8()?;
+        self.reg_x = r.read_u8()?;
+        self.reg_y = r.read_u8()?;
+        self.reg_stack_ptr = r.read_u8()?;
+        self.reg_status = StatusFlags::from_bits_truncate(r.read_u8()?);
+        self.cycles = r.read_usize()?;
+        self.stop_requested = false;
+        self.bus.load(r)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::test_rom;
+
+    // Test programs end with a BRK, stop right before executing it
+    fn run_to_brk(cpu: &mut CPU) -> StopReason {
+        cpu.run_until(|cpu| cpu.mem_read(cpu.reg_pc) == 0x00)
+    }
+
+    fn run_program(program: Vec<u8>) -> CPU {
+        let mut cpu = CPU::new(Bus::new(test_rom(program)));
+        cpu.reset();
+        run_to_brk(&mut cpu);
+        cpu
+    }
+
+    #[test]
+    fn test_cycles_include_reset_sequence() {
+        // LDA #$01
+        let cpu = run_program(vec![0xa9, 0x01, 0x00]);
+        assert_eq!(cpu.cycles, 7 + 2);
+    }
+
+    #[test]
+    fn test_abx_page_cross_penalty() {
+        // LDX #$01; LDA $80FF,X; LDA $8000,X
+        let cpu = run_program(vec![0xa2, 0x01, 0xbd, 0xff, 0x80, 0xbd, 0x00, 0x80, 0x00]);
+        assert_eq!(cpu.cycles, 7 + 2 + 5 + 4);
+    }
+
+    #[test]
+    fn test_store_has_no_page_cross_penalty() {
+        // LDX #$01; STA $02FF,X
+        let cpu = run_program(vec![0xa2, 0x01, 0x9d, 0xff, 0x02, 0x00]);
+        assert_eq!(cpu.cycles, 7 + 2 + 5);
+    }
+
+    #[test]
+    fn test_izy_page_cross_penalty() {
+        // LDA #$FF; STA $10; LDA #$02; STA $11; LDY #$01; LDA ($10),Y
+        let cpu = run_program(vec![
+            0xa9, 0xff, 0x85, 0x10, 0xa9, 0x02, 0x85, 0x11, 0xa0, 0x01, 0xb1, 0x10, 0x00,
+        ]);
+        assert_eq!(cpu.cycles, 7 + 2 + 3 + 2 + 3 + 2 + 6);
+    }
+
+    #[test]
+    fn test_branch_cycles() {
+        // SEC; BCC +2 (not taken); BCS +0 (taken)
+        let cpu = run_program(vec![0x38, 0x90, 0x02, 0xb0, 0x00, 0x00]);
+        assert_eq!(cpu.cycles, 7 + 2 + 2 + 3);
+    }
+
+    #[test]
+    fn test_branch_page_cross_cycles() {
+        // JMP $80F0; at $80F0: CLC; BCC +$20 -> $8113
+        let mut program = vec![0xea; 0x114];
+        program[0..3].copy_from_slice(&[0x4c, 0xf0, 0x80]);
+        program[0xf0..0xf3].copy_from_slice(&[0x18, 0x90, 0x20]);
+        program[0x113] = 0x00;
+
+        let cpu = run_program(program);
+        assert_eq!(cpu.reg_pc, 0x8113);
+        assert_eq!(cpu.cycles, 7 + 3 + 2 + 4);
+    }
+
+    // Lays out a full 16KB bank so tests can place code and vectors anywhere in it
+    fn bank_with_vector(vector: u16, handler: &[u8]) -> Vec<u8> {
+        let mut program = vec![0xea; 0x4000];
+        program[0x1000..0x1000 + handler.len()].copy_from_slice(handler);
+        program[(vector - 0xC000) as usize] = 0x00;
+        program[(vector - 0xC000) as usize + 1] = 0x90;
+        program
+    }
+
+    #[test]
+    fn test_brk() {
+        let mut program = bank_with_vector(IRQ_VECTOR, &[0x00]);
+        program[0] = 0x00;
+
+        let mut cpu = CPU::new(Bus::new(test_rom(program)));
+        cpu.reset();
+        cpu.reg_status.remove(StatusFlags::INTERRUPT);
+        assert_eq!(cpu.step(), StopReason::Step);
+
+        assert_eq!(cpu.reg_pc, 0x9000);
+        assert_eq!(cpu.cycles, 7 + 7);
+        assert!(cpu.reg_status.contains(StatusFlags::INTERRUPT));
+        assert_eq!(cpu.stack_pop(), 0b0011_0000);
+        assert_eq!(cpu.stack_pop_u16(), 0x8002);
+    }
+
+    #[test]
+    fn test_brk_wraps_around_address_space() {
+        // The test ROM leaves the IRQ vector at $0000, and its bytes at $FFFE are themselves a BRK
+        let mut cpu = CPU::new(Bus::new(test_rom(vec![])));
+        cpu.reset();
+        cpu.reg_pc = 0xfffe;
+        cpu.step();
+
+        assert_eq!(cpu.reg_pc, 0x0000);
+        cpu.stack_pop();
+        assert_eq!(cpu.stack_pop_u16(), 0x0000);
+    }
+
+    #[test]
+    fn test_irq_masked_by_interrupt_flag() {
+        // NOP; CLI; then the handler at $9000: LDA #$42
+        let mut program = bank_with_vector(IRQ_VECTOR, &[0xa9, 0x42, 0x00]);
+        program[1] = 0x58;
 
+        let mut cpu = CPU::new(Bus::new(test_rom(program)));
+        cpu.reset();
+        cpu.bus.set_irq_line(true);
+        run_to_brk(&mut cpu);
+
+        assert_eq!(cpu.reg_acc, 0x42);
+        assert_eq!(cpu.stack_pop() & 0b0011_0000, 0b0010_0000);
+        assert_eq!(cpu.stack_pop_u16(), 0x8002);
+    }
+
+    #[test]
+    fn test_nmi_on_vblank() {
+        // LDA #$80; STA $2000; loop: JMP loop. The NMI handler at $9000: LDA #$42
+        let mut program = bank_with_vector(NMI_VECTOR, &[0xa9, 0x42, 0x00]);
+        program[0..8].copy_from_slice(&[0xa9, 0x80, 0x8d, 0x00, 0x20, 0x4c, 0x05, 0x80]);
+
+        let mut cpu = CPU::new(Bus::new(test_rom(program)));
+        cpu.reset();
+        run_to_brk(&mut cpu);
+
+        assert_eq!(cpu.reg_acc, 0x42);
+        assert_eq!(cpu.stack_pop() & 0b0001_0000, 0);
+        assert_eq!(cpu.stack_pop_u16(), 0x8005);
+    }
+
+    #[test]
+    fn test_cpu_cycles_drive_ppu() {
+        // LDY #$20; outer: LDX #$00; inner: DEX; BNE inner; DEY; BNE outer
+        let mut cpu = run_program(vec![0xa0, 0x20, 0xa2, 0x00, 0xca, 0xd0, 0xfd, 0x88, 0xd0, 0xf8, 0x00]);
+        assert_eq!(cpu.bus.cycles(), cpu.cycles);
+        assert!(cpu.cycles > 29781);
+        assert!(cpu.bus.poll_frame_complete());
+        assert!(!cpu.bus.poll_frame_complete());
+    }
+
+    #[test]
+    fn test_run_cycles() {
+        // loop: JMP loop
+        let mut cpu = CPU::new(Bus::new(test_rom(vec![0x4c, 0x00, 0x80])));
+        cpu.reset();
+
+        assert_eq!(cpu.run_cycles(100), StopReason::CyclesElapsed);
+        assert!(cpu.cycles >= 107 && cpu.cycles < 110);
+    }
+
+    #[test]
+    fn test_run_frame() {
+        let mut cpu = CPU::new(Bus::new(test_rom(vec![0x4c, 0x00, 0x80])));
+        cpu.reset();
+
+        assert_eq!(cpu.run_frame(), StopReason::FrameComplete);
+        let first = cpu.cycles;
+        assert_eq!(cpu.run_frame(), StopReason::FrameComplete);
+        assert!((cpu.cycles - first).abs_diff(29781) < 3);
+    }
+
+    #[test]
+    fn test_request_stop() {
+        let mut cpu = CPU::new(Bus::new(test_rom(vec![0x4c, 0x00, 0x80])));
+        cpu.reset();
+
+        let mut count = 0;
+        let reason = cpu.run_with_callback(|cpu| {
+            count += 1;
+            if count == 10 {
+                cpu.request_stop();
+            }
+        });
+
+        assert_eq!(reason, StopReason::StopRequested);
+        assert_eq!(cpu.cycles, 7 + 9 * 3);
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        // LDA #$42; STA $0205; LDA #$02; STA $4014; BRK
+        let cpu = run_program(vec![0xa9, 0x42, 0x8d, 0x05, 0x02, 0xa9, 0x02, 0x8d, 0x14, 0x40, 0x00]);
+        assert_eq!(cpu.bus.ppu().reg_oam_data[5], 0x42);
+        // DMA starts on an odd cycle (19) and takes an extra alignment cycle
+        assert_eq!(cpu.cycles, 19 + 514);
+
+        // Same with STA $00 in between to start the DMA on an even cycle
+        let cpu = run_program(vec![0xa9, 0x42, 0x8d, 0x05, 0x02, 0x85, 0x00, 0xa9, 0x02, 0x8d, 0x14, 0x40, 0x00]);
+        assert_eq!(cpu.bus.ppu().reg_oam_data[5], 0x42);
+        assert_eq!(cpu.cycles, 22 + 513);
+    }
+
+    // Turns on rendering and sound, then keeps changing RAM, VRAM and the pulse channel every iteration
+    fn busy_program() -> Vec<u8> {
+        vec![
+            0xa9, 0x1e, 0x8d, 0x01, 0x20, // LDA #$1E; STA $2001
+            0xa9, 0x0f, 0x8d, 0x15, 0x40, // LDA #$0F; STA $4015
+            0xe6, 0x10, 0xa5, 0x10,       // loop: INC $10; LDA $10
+            0x8d, 0x00, 0x40, 0x8d, 0x02, 0x40, 0x8d, 0x03, 0x40, // STA $4000; STA $4002; STA $4003
+            0x8d, 0x07, 0x20,             // STA $2007
+            0x4c, 0x0a, 0x80,             // JMP loop
+        ]
+    }
+
+    fn run_frames(cpu: &mut CPU, frames: usize) -> (Vec<u16>, Vec<f32>) {
+        for _ in 0..frames {
+            cpu.run_frame();
         }
+        (cpu.bus.ppu().frame.pixels.clone(), cpu.bus.apu_mut().take_samples())
+    }
+
+    #[test]
+    fn test_save_state_replays_identically() {
+        let mut cpu = CPU::new(Bus::new(test_rom(busy_program())));
+        cpu.reset();
+        cpu.bus.apu_mut().set_sample_rate(44100);
+        run_frames(&mut cpu, 3);
+        cpu.run_cycles(1234);
+        cpu.bus.apu_mut().take_samples();
+
+        let state = cpu.save_state();
+        let first = run_frames(&mut cpu, 5);
+        let first_state = cpu.save_state();
+
+        cpu.load_state(&state).unwrap();
+        let second = run_frames(&mut cpu, 5);
+        assert!(first.0 == second.0);
+        assert!(first.1 == second.1);
+        assert!(!first.1.is_empty());
+        assert_eq!(first_state, cpu.save_state());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_load_state_into_fresh_machine() {
+        let mut cpu = CPU::new(Bus::new(test_rom(busy_program())));
+        cpu.reset();
+        run_frames(&mut cpu, 2);
+        let state = cpu.save_state();
+        let expected = run_frames(&mut cpu, 2);
+
+        let mut other = CPU::new(Bus::new(test_rom(busy_program())));
+        other.load_state(&state).unwrap();
+        assert!(run_frames(&mut other, 2).0 == expected.0);
+        assert_eq!(other.cycles, cpu.cycles);
+    }
+
+    #[test]
+    fn test_load_state_errors_leave_machine_untouched() {
+        let mut cpu = CPU::new(Bus::new(test_rom(busy_program())));
+        cpu.reset();
+        run_frames(&mut cpu, 1);
+        let state = cpu.save_state();
+
+        let mut other = CPU::new(Bus::new(test_rom(vec![0xea, 0x00])));
+        other.reset();
+        let before = other.save_state();
+        assert_eq!(other.load_state(&state), Err("Save state was made with a different ROM".to_string()));
+        assert_eq!(other.save_state(), before);
+
+        assert!(cpu.load_state(&state[..state.len() - 1]).is_err());
+        assert!(cpu.load_state(b"not a state").is_err());
+        assert_eq!(cpu.save_state(), state);
+    }
+}
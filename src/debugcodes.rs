@@ -161,7 +161,7 @@ lazy_static! {
         OpCodes{mnemonic: "dey", len: 1, mode: AddressingMode::NoneAddressing, cycles: 2},
         OpCodes{mnemonic: "*nop", len: 2, mode: AddressingMode::Immediate, cycles: 2},
         OpCodes{mnemonic: "txa", len: 1, mode: AddressingMode::NoneAddressing, cycles: 2},
-        OpCodes{mnemonic: "*xaa", len: 2, mode: AddressingMode::Immediate, cycles: 3},
+        OpCodes{mnemonic: "*xaa", len: 2, mode: AddressingMode::Immediate, cycles: 2},
         OpCodes{mnemonic: "sty", len: 3, mode: AddressingMode::Absolute, cycles: 4},
         OpCodes{mnemonic: "sta", len: 3, mode: AddressingMode::Absolute, cycles: 4},
         OpCodes{mnemonic: "stx", len: 3, mode: AddressingMode::Absolute, cycles: 4},
@@ -169,7 +169,7 @@ lazy_static! {
         OpCodes{mnemonic: "bcc", len: 2, mode: AddressingMode::NoneAddressing, cycles: 2},
         OpCodes{mnemonic: "sta", len: 2, mode: AddressingMode::Indirect_Y, cycles: 6},
         OpCodes{mnemonic: "*nop", len: 1, mode: AddressingMode::NoneAddressing, cycles: 2},
-        OpCodes{mnemonic: "*ahx", len: 2, mode: AddressingMode::Indirect_Y, cycles: 8},
+        OpCodes{mnemonic: "*ahx", len: 2, mode: AddressingMode::Indirect_Y, cycles: 6},
         OpCodes{mnemonic: "sty", len: 2, mode: AddressingMode::ZeroPage_X, cycles: 4},
         OpCodes{mnemonic: "sta", len: 2, mode: AddressingMode::ZeroPage_X, cycles: 4},
         OpCodes{mnemonic: "stx", len: 2, mode: AddressingMode::ZeroPage_Y, cycles: 4},
@@ -177,11 +177,11 @@ lazy_static! {
         OpCodes{mnemonic: "tya", len: 1, mode: AddressingMode::NoneAddressing, cycles: 2},
         OpCodes{mnemonic: "sta", len: 3, mode: AddressingMode::Absolute_Y, cycles: 5},
         OpCodes{mnemonic: "txs", len: 1, mode: AddressingMode::NoneAddressing, cycles: 2},
-        OpCodes{mnemonic: "*tas", len: 3, mode: AddressingMode::Absolute_Y, cycles: 2},
-        OpCodes{mnemonic: "*shy", len: 3, mode: AddressingMode::Absolute_X, cycles: 4},
+        OpCodes{mnemonic: "*tas", len: 3, mode: AddressingMode::Absolute_Y, cycles: 5},
+        OpCodes{mnemonic: "*shy", len: 3, mode: AddressingMode::Absolute_X, cycles: 5},
         OpCodes{mnemonic: "sta", len: 3, mode: AddressingMode::Absolute_X, cycles: 5},
-        OpCodes{mnemonic: "*shx", len: 3, mode: AddressingMode::Absolute_Y, cycles: 4},
-        OpCodes{mnemonic: "*ahx", len: 3, mode: AddressingMode::Absolute_Y, cycles: 4},
+        OpCodes{mnemonic: "*shx", len: 3, mode: AddressingMode::Absolute_Y, cycles: 5},
+        OpCodes{mnemonic: "*ahx", len: 3, mode: AddressingMode::Absolute_Y, cycles: 5},
         OpCodes{mnemonic: "ldy", len: 2, mode: AddressingMode::Immediate, cycles: 2},
         OpCodes{mnemonic: "lda", len: 2, mode: AddressingMode::Indirect_X, cycles: 6},
         OpCodes{mnemonic: "ldx", len: 2, mode: AddressingMode::Immediate, cycles: 2},
@@ -193,7 +193,7 @@ lazy_static! {
         OpCodes{mnemonic: "tay", len: 1, mode: AddressingMode::NoneAddressing, cycles: 2},
         OpCodes{mnemonic: "lda", len: 2, mode: AddressingMode::Immediate, cycles: 2},
         OpCodes{mnemonic: "tax", len: 1, mode: AddressingMode::NoneAddressing, cycles: 2},
-        OpCodes{mnemonic: "*lxa", len: 2, mode: AddressingMode::Immediate, cycles: 3},
+        OpCodes{mnemonic: "*lxa", len: 2, mode: AddressingMode::Immediate, cycles: 2},
         OpCodes{mnemonic: "ldy", len: 3, mode: AddressingMode::Absolute, cycles: 4},
         OpCodes{mnemonic: "lda", len: 3, mode: AddressingMode::Absolute, cycles: 4},
         OpCodes{mnemonic: "ldx", len: 3, mode: AddressingMode::Absolute, cycles: 4},
@@ -209,7 +209,7 @@ lazy_static! {
         OpCodes{mnemonic: "clv", len: 1, mode: AddressingMode::NoneAddressing, cycles: 2},
         OpCodes{mnemonic: "lda", len: 3, mode: AddressingMode::Absolute_Y, cycles: 4},
         OpCodes{mnemonic: "tsx", len: 1, mode: AddressingMode::NoneAddressing, cycles: 2},
-        OpCodes{mnemonic: "*las", len: 3, mode: AddressingMode::Absolute_Y, cycles: 2},
+        OpCodes{mnemonic: "*las", len: 3, mode: AddressingMode::Absolute_Y, cycles: 4},
         OpCodes{mnemonic: "ldy", len: 3, mode: AddressingMode::Absolute_X, cycles: 4},
         OpCodes{mnemonic: "lda", len: 3, mode: AddressingMode::Absolute_X, cycles: 4},
         OpCodes{mnemonic: "ldx", len: 3, mode: AddressingMode::Absolute_Y, cycles: 4},
@@ -0,0 +1,544 @@
+use std::collections::VecDeque;
+use crate::cpu::{Access, Mem, StatusFlags, Watchpoint, WatchHit, CPU};
+use crate::disasm;
+
+// Instructions kept for the "disassemble around PC" view, most recent last
+const HISTORY_LEN: usize = 8;
+
+const HELP: &str = "\
+step [n]              execute n instructions (default 1)        s
+next                  step over a JSR                           n
+out                   run until the current subroutine returns  o
+continue              run until a breakpoint                    c
+vblank                run until the next vblank starts          v
+break <addr>[-<end>]  break when executing in the range         b
+watch r|w|rw <addr>[-<end>]  break on reads/writes to the range  w
+delete <n>            remove breakpoint n                       d
+breakpoints           list breakpoints                          bl
+regs                  show registers                            r
+set <reg> <value>     set a, x, y, sp, p or pc
+mem <addr> [len]      dump memory                               m
+poke <addr> <byte>..  write bytes to memory
+dis [addr] [count]    disassemble (around PC by default)
+reset                 reset the console
+quit                                                            q
+Numbers are hex, with an optional $ or 0x prefix. An empty line repeats the last command.";
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Breakpoint {
+    pub start: u16,
+    pub end:   u16,
+    pub exec:  bool,
+    pub read:  bool,
+    pub write: bool,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DebugStop {
+    /// The step, step over/out or vblank target was reached
+    Done,
+    /// An execution breakpoint, by index, matched the program counter
+    Breakpoint(usize),
+    /// The last instruction accessed a watched address
+    Watch(WatchHit),
+}
+
+// Interactive debugger driven one command line at a time. The REPL front end lives in src/bin/debugger.rs,
+// everything here only needs a CPU so it can be scripted and tested without a terminal.
+#[derive(Default)]
+pub struct Debugger {
+    pub breakpoints: Vec<Breakpoint>,
+    history:         VecDeque<u16>,
+    last_command:    String,
+    quit:            bool,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger::default()
+    }
+
+    pub fn quit_requested(&self) -> bool {
+        self.quit
+    }
+
+    /// Runs one command line and returns the text to show
+    pub fn command(&mut self, cpu: &mut CPU, line: &str) -> Result<String, String> {
+        let line = if line.trim().is_empty() { self.last_command.clone() } else { line.trim().to_string() };
+        self.last_command = line.clone();
+
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let (name, args) = match args.split_first() {
+            Some((name, args)) => (*name, args),
+            None => return Ok(String::new()),
+        };
+
+        match name {
+            "s" | "step" => {
+                let mut count = args.first().map(|arg| parse_number(arg)).transpose()?.unwrap_or(1);
+                let stop = self.run(cpu, |_| {
+                    if count == 0 {
+                        return true;
+                    }
+                    count -= 1;
+                    false
+                });
+                Ok(self.describe_stop(cpu, stop))
+            }
+            "n" | "next" => {
+                let stop = self.step_over(cpu);
+                Ok(self.describe_stop(cpu, stop))
+            }
+            "o" | "out" | "finish" => {
+                let stop = self.step_out(cpu);
+                Ok(self.describe_stop(cpu, stop))
+            }
+            "c" | "continue" => {
+                let stop = self.run(cpu, |_| false);
+                Ok(self.describe_stop(cpu, stop))
+            }
+            "v" | "vblank" => {
+                let stop = self.run_to_vblank(cpu);
+                Ok(self.describe_stop(cpu, stop))
+            }
+            "b" | "break" => {
+                let (start, end) = parse_range(args.first().ok_or("usage: break <addr>[-<end>]")?)?;
+                self.add_breakpoint(Breakpoint { start, end, exec: true, read: false, write: false })
+            }
+            "w" | "watch" => {
+                if args.len() != 2 {
+                    return Err("usage: watch r|w|rw <addr>[-<end>]".to_string());
+                }
+                let (read, write) = match args[0] {
+                    "r" => (true, false),
+                    "w" => (false, true),
+                    "rw" => (true, true),
+                    other => return Err(format!("unknown watch kind '{}'", other)),
+                };
+                let (start, end) = parse_range(args[1])?;
+                self.add_breakpoint(Breakpoint { start, end, exec: false, read, write })
+            }
+            "d" | "delete" => {
+                let index = args.first().ok_or("usage: delete <n>")?.parse::<usize>()
+                    .map_err(|_| "breakpoint numbers are decimal".to_string())?;
+                if index >= self.breakpoints.len() {
+                    return Err(format!("no breakpoint {}", index));
+                }
+                self.breakpoints.remove(index);
+                Ok(format!("deleted breakpoint {}", index))
+            }
+            "bl" | "breakpoints" => Ok(self.list_breakpoints()),
+            "r" | "regs" => Ok(registers(cpu)),
+            "set" => {
+                if args.len() != 2 {
+                    return Err("usage: set <reg> <value>".to_string());
+                }
+                set_register(cpu, args[0], parse_number(args[1])?)?;
+                Ok(registers(cpu))
+            }
+            "m" | "mem" => {
+                let addr = parse_address(args.first().ok_or("usage: mem <addr> [len]")?)?;
+                let len = args.get(1).map(|arg| parse_number(arg)).transpose()?.unwrap_or(0x40);
+                Ok(dump_memory(cpu, addr, len))
+            }
+            "poke" => {
+                if args.len() < 2 {
+                    return Err("usage: poke <addr> <byte>..".to_string());
+                }
+                let addr = parse_address(args[0])?;
+                for (i, arg) in args[1..].iter().enumerate() {
+                    let data = parse_number(arg)?;
+                    if data > 0xff {
+                        return Err(format!("{} is not a byte", arg));
+                    }
+                    cpu.bus.mem_write(addr.wrapping_add(i as u16), data as u8);
+                }
+                Ok(dump_memory(cpu, addr, args.len() - 1))
+            }
+            "dis" => {
+                let count = args.get(1).map(|arg| parse_number(arg)).transpose()?.unwrap_or(10);
+                match args.first() {
+                    Some(arg) => Ok(disassemble_from(cpu, parse_address(arg)?, count)),
+                    None => Ok(self.disassemble_around_pc(cpu, count)),
+                }
+            }
+            "reset" => {
+                cpu.reset();
+                self.history.clear();
+                Ok(self.describe_stop(cpu, DebugStop::Done))
+            }
+            "h" | "help" | "?" => Ok(HELP.to_string()),
+            "q" | "quit" | "exit" => {
+                self.quit = true;
+                Ok(String::new())
+            }
+            other => Err(format!("unknown command '{}', try 'help'", other)),
+        }
+    }
+
+    /// Runs until `done` returns true or a breakpoint is hit. `done` is checked before every instruction,
+    /// an execution breakpoint at the starting PC is ignored so execution can continue past it
+    pub fn run<P>(&mut self, cpu: &mut CPU, mut done: P) -> DebugStop
+    where
+    P: FnMut(&mut CPU) -> bool,
+    {
+        cpu.watchpoints = self.breakpoints.iter()
+            .filter(|bp| bp.read || bp.write)
+            .map(|bp| Watchpoint { start: bp.start, end: bp.end, read: bp.read, write: bp.write })
+            .collect();
+        cpu.poll_watch_hit();
+
+        let mut stop = DebugStop::Done;
+        let mut first = true;
+        let breakpoints = &self.breakpoints;
+        let history = &mut self.history;
+
+        cpu.run_with_callback(|cpu| {
+            if let Some(hit) = cpu.poll_watch_hit() {
+                stop = DebugStop::Watch(hit);
+            } else if let Some(index) = breakpoints.iter()
+                .position(|bp| bp.exec && (bp.start..=bp.end).contains(&cpu.reg_pc))
+                .filter(|_| !first)
+            {
+                stop = DebugStop::Breakpoint(index);
+            } else if done(cpu) {
+                stop = DebugStop::Done;
+            } else {
+                first = false;
+                if history.len() == HISTORY_LEN {
+                    history.pop_front();
+                }
+                history.push_back(cpu.reg_pc);
+                return;
+            }
+            cpu.request_stop();
+        });
+
+        cpu.watchpoints.clear();
+        stop
+    }
+
+    /// Steps one instruction, running a JSR through to its return
+    pub fn step_over(&mut self, cpu: &mut CPU) -> DebugStop {
+        if cpu.bus.peek(cpu.reg_pc) != 0x20 {
+            let mut stepped = false;
+            return self.run(cpu, |_| std::mem::replace(&mut stepped, true));
+        }
+
+        let target = cpu.reg_pc.wrapping_add(3);
+        let stack_ptr = cpu.reg_stack_ptr;
+        self.run(cpu, |cpu| cpu.reg_pc == target && cpu.reg_stack_ptr == stack_ptr)
+    }
+
+    /// Runs until an RTS or RTI pops the stack above where it is now
+    pub fn step_out(&mut self, cpu: &mut CPU) -> DebugStop {
+        let stack_ptr = cpu.reg_stack_ptr;
+        let mut returning = false;
+        self.run(cpu, |cpu| {
+            if returning && cpu.reg_stack_ptr > stack_ptr {
+                return true;
+            }
+            returning = matches!(cpu.bus.peek(cpu.reg_pc), 0x60 | 0x40);
+            false
+        })
+    }
+
+    /// Runs until the PPU enters vblank, scanline 241
+    pub fn run_to_vblank(&mut self, cpu: &mut CPU) -> DebugStop {
+        let mut was_vblank = in_vblank(cpu);
+        self.run(cpu, |cpu| {
+            let vblank = in_vblank(cpu);
+            let started = vblank && !was_vblank;
+            was_vblank = vblank;
+            started
+        })
+    }
+
+    fn add_breakpoint(&mut self, bp: Breakpoint) -> Result<String, String> {
+        if bp.end < bp.start {
+            return Err("the range ends before it starts".to_string());
+        }
+        self.breakpoints.push(bp);
+        Ok(format!("{}: {}", self.breakpoints.len() - 1, describe_breakpoint(&bp)))
+    }
+
+    fn list_breakpoints(&self) -> String {
+        if self.breakpoints.is_empty() {
+            return "no breakpoints".to_string();
+        }
+        self.breakpoints.iter().enumerate()
+            .map(|(i, bp)| format!("{}: {}", i, describe_breakpoint(bp)))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    fn describe_stop(&self, cpu: &CPU, stop: DebugStop) -> String {
+        let reason = match stop {
+            DebugStop::Done => String::new(),
+            DebugStop::Breakpoint(index) => format!("breakpoint {}\n", index),
+            DebugStop::Watch(hit) => format!(
+                "watch: {} ${:04X} = {:02X}\n",
+                if hit.access == Access::Read { "read" } else { "write" }, hit.addr, hit.data),
+        };
+        format!("{}{}\n{}", reason, disassemble_from(cpu, cpu.reg_pc, 1), registers(cpu))
+    }
+
+    fn disassemble_around_pc(&self, cpu: &CPU, count: usize) -> String {
+        let previous: Vec<u16> = self.history.iter().copied().filter(|addr| *addr != cpu.reg_pc).collect();
+        let mut lines: Vec<String> = previous[previous.len().saturating_sub(count / 2)..].iter()
+            .map(|addr| format!("  {}", disassemble_from(cpu, *addr, 1)))
+            .collect();
+
+        let mut addr = cpu.reg_pc;
+        for i in 0..count.saturating_sub(lines.len()) {
+            let (text, len) = disassemble(cpu, addr);
+            lines.push(format!("{} {}", if i == 0 { ">" } else { " " }, text));
+            addr = addr.wrapping_add(len);
+        }
+        lines.join("\n")
+    }
+}
+
+fn in_vblank(cpu: &CPU) -> bool {
+    (241..=260).contains(&cpu.bus.ppu().scanline())
+}
+
+fn parse_number(text: &str) -> Result<usize, String> {
+    let digits = text.trim_start_matches('$').trim_start_matches("0x");
+    usize::from_str_radix(digits, 16).map_err(|_| format!("'{}' is not a hex number", text))
+}
+
+fn parse_address(text: &str) -> Result<u16, String> {
+    let addr = parse_number(text)?;
+    if addr > 0xffff {
+        return Err(format!("'{}' is outside the address space", text));
+    }
+    Ok(addr as u16)
+}
+
+fn parse_range(text: &str) -> Result<(u16, u16), String> {
+    match text.split_once('-') {
+        Some((start, end)) => Ok((parse_address(start)?, parse_address(end)?)),
+        None => {
+            let addr = parse_address(text)?;
+            Ok((addr, addr))
+        }
+    }
+}
+
+fn describe_breakpoint(bp: &Breakpoint) -> String {
+    let kind = match (bp.exec, bp.read, bp.write) {
+        (true, _, _) => "exec",
+        (false, true, true) => "read/write",
+        (false, true, false) => "read",
+        _ => "write",
+    };
+    if bp.start == bp.end {
+        format!("{} ${:04X}", kind, bp.start)
+    } else {
+        format!("{} ${:04X}-${:04X}", kind, bp.start, bp.end)
+    }
+}
+
+fn registers(cpu: &CPU) -> String {
+    let flags: String = "NV-BDIZC".chars().enumerate()
+        .map(|(i, name)| {
+            if cpu.reg_status.bits() & (0x80 >> i) != 0 { name } else { name.to_ascii_lowercase() }
+        })
+        .collect();
+    format!(
+        "A:{:02X} X:{:02X} Y:{:02X} P:{:02X} [{}] SP:{:02X} PC:{:04X} CYC:{} PPU:{},{}",
+        cpu.reg_acc, cpu.reg_x, cpu.reg_y, cpu.reg_status.bits(), flags, cpu.reg_stack_ptr, cpu.reg_pc,
+        cpu.cycles, cpu.bus.ppu().scanline(), cpu.bus.ppu().dot(),
+    )
+}
+
+fn set_register(cpu: &mut CPU, name: &str, value: usize) -> Result<(), String> {
+    let byte = || u8::try_from(value).map_err(|_| format!("{:X} does not fit in {}", value, name));
+    match name.to_ascii_lowercase().as_str() {
+        "a" => cpu.reg_acc = byte()?,
+        "x" => cpu.reg_x = byte()?,
+        "y" => cpu.reg_y = byte()?,
+        "sp" => cpu.reg_stack_ptr = byte()?,
+        "p" => cpu.reg_status = StatusFlags::from_bits_truncate(byte()?),
+        "pc" => cpu.reg_pc = u16::try_from(value).map_err(|_| format!("{:X} does not fit in pc", value))?,
+        _ => return Err(format!("unknown register '{}'", name)),
+    }
+    Ok(())
+}
+
+fn dump_memory(cpu: &CPU, addr: u16, len: usize) -> String {
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        let line_addr = addr.wrapping_add(offset as u16);
+        let bytes: Vec<String> = (0..16.min(len - offset))
+            .map(|i| format!("{:02X}", cpu.bus.peek(line_addr.wrapping_add(i as u16))))
+            .collect();
+        lines.push(format!("{:04X}: {}", line_addr, bytes.join(" ")));
+        offset += 16;
+    }
+    lines.join("\n")
+}
+
+fn disassemble_from(cpu: &CPU, mut addr: u16, count: usize) -> String {
+    let mut lines = Vec::new();
+    for _ in 0..count {
+        let (text, len) = disassemble(cpu, addr);
+        lines.push(text);
+        addr = addr.wrapping_add(len);
+    }
+    lines.join("\n")
+}
+
+// One instruction as "ADDR  BYTES  MNEMONIC OPERAND" and its length
+fn disassemble(cpu: &CPU, addr: u16) -> (String, u16) {
+    let bytes: Vec<u8> = (0..3).map(|i| cpu.bus.peek(addr.wrapping_add(i))).collect();
+    let ins = disasm::decode(&bytes, addr).expect("three bytes hold any instruction");
+    let hex = bytes[..ins.len as usize].iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<String>>()
+        .join(" ");
+    let mnemonic = ins.mnemonic.to_ascii_uppercase();
+    let text = format!("{:04X}  {:8} {: >4} {}", addr, hex, mnemonic, ins.operand_text());
+    (text.trim_end().to_string(), ins.len as u16)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+
+    // $8000 JSR $8008; INX; JMP $8003
+    // $8008 LDA $0300; STA $0301; RTS
+    fn debug_cpu() -> CPU {
+        let mut cpu = CPU::new(Bus::new(test_rom(vec![
+            0x20, 0x08, 0x80, 0xe8, 0x4c, 0x00, 0x80, 0xea,
+            0xad, 0x00, 0x03, 0x8d, 0x01, 0x03, 0x60,
+        ])));
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn test_step_and_step_over() {
+        let mut cpu = debug_cpu();
+        let mut debugger = Debugger::new();
+
+        debugger.command(&mut cpu, "step").unwrap();
+        assert_eq!(cpu.reg_pc, 0x8008);
+        debugger.command(&mut cpu, "step 2").unwrap();
+        assert_eq!(cpu.reg_pc, 0x800e);
+
+        debugger.command(&mut cpu, "reset").unwrap();
+        assert_eq!(debugger.step_over(&mut cpu), DebugStop::Done);
+        assert_eq!(cpu.reg_pc, 0x8003);
+        assert_eq!(cpu.reg_stack_ptr, 0xfd);
+        debugger.step_over(&mut cpu);
+        assert_eq!(cpu.reg_pc, 0x8004);
+    }
+
+    #[test]
+    fn test_step_out() {
+        let mut cpu = debug_cpu();
+        let mut debugger = Debugger::new();
+
+        debugger.command(&mut cpu, "s 2").unwrap();
+        assert_eq!(cpu.reg_pc, 0x800b);
+        assert_eq!(debugger.step_out(&mut cpu), DebugStop::Done);
+        assert_eq!(cpu.reg_pc, 0x8003);
+        assert_eq!(cpu.reg_stack_ptr, 0xfd);
+    }
+
+    #[test]
+    fn test_exec_breakpoint() {
+        let mut cpu = debug_cpu();
+        let mut debugger = Debugger::new();
+
+        debugger.command(&mut cpu, "break $8003").unwrap();
+        assert_eq!(debugger.run(&mut cpu, |_| false), DebugStop::Breakpoint(0));
+        assert_eq!(cpu.reg_pc, 0x8003);
+        assert_eq!(cpu.reg_x, 0);
+
+        // Continuing leaves the breakpoint and hits it again on the next loop
+        assert_eq!(debugger.run(&mut cpu, |_| false), DebugStop::Breakpoint(0));
+        assert_eq!(cpu.reg_pc, 0x8003);
+        assert_eq!(cpu.reg_x, 1);
+
+        debugger.command(&mut cpu, "delete 0").unwrap();
+        assert!(debugger.breakpoints.is_empty());
+
+        assert!(debugger.command(&mut cpu, "break 8004-8003").is_err());
+        assert!(debugger.command(&mut cpu, "watch w 10000").is_err());
+        assert!(debugger.breakpoints.is_empty());
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut cpu = debug_cpu();
+        let mut debugger = Debugger::new();
+
+        debugger.command(&mut cpu, "watch w 0301").unwrap();
+        let stop = debugger.run(&mut cpu, |_| false);
+        assert_eq!(stop, DebugStop::Watch(WatchHit { addr: 0x0301, access: Access::Write, data: 0 }));
+        assert_eq!(cpu.reg_pc, 0x800e);
+
+        debugger.command(&mut cpu, "delete 0").unwrap();
+        debugger.command(&mut cpu, "watch r 0300").unwrap();
+        let stop = debugger.run(&mut cpu, |_| false);
+        assert_eq!(stop, DebugStop::Watch(WatchHit { addr: 0x0300, access: Access::Read, data: 0 }));
+        assert_eq!(cpu.reg_pc, 0x800b);
+        assert!(cpu.watchpoints.is_empty());
+
+        // Operand fetches are not data reads
+        debugger.command(&mut cpu, "delete 0").unwrap();
+        debugger.command(&mut cpu, "watch r 8000-8010").unwrap();
+        let mut count = 100;
+        assert_eq!(debugger.run(&mut cpu, |_| { count -= 1; count == 0 }), DebugStop::Done);
+    }
+
+    #[test]
+    fn test_run_to_vblank() {
+        let mut cpu = debug_cpu();
+        let mut debugger = Debugger::new();
+
+        for _ in 0..2 {
+            assert_eq!(debugger.run_to_vblank(&mut cpu), DebugStop::Done);
+            assert_eq!(cpu.bus.ppu().scanline(), 241);
+        }
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let mut cpu = debug_cpu();
+        let mut debugger = Debugger::new();
+
+        debugger.command(&mut cpu, "set a 42").unwrap();
+        assert_eq!(cpu.reg_acc, 0x42);
+        assert!(debugger.command(&mut cpu, "set a 100").is_err());
+        debugger.command(&mut cpu, "set pc $8003").unwrap();
+        assert_eq!(cpu.reg_pc, 0x8003);
+
+        let dump = debugger.command(&mut cpu, "poke 10 ab cd").unwrap();
+        assert_eq!(dump, "0010: AB CD");
+        assert!(debugger.command(&mut cpu, "poke 10010 ab").is_err());
+        assert!(debugger.command(&mut cpu, "mem 10000").is_err());
+
+        // Peeking at the PPU status leaves vblank set
+        debugger.run_to_vblank(&mut cpu);
+        assert_eq!(debugger.command(&mut cpu, "mem 2002 1").unwrap(), "2002: 80");
+        assert_eq!(debugger.command(&mut cpu, "mem 2002 1").unwrap(), "2002: 80");
+
+        assert!(debugger.command(&mut cpu, "frobnicate").is_err());
+        debugger.command(&mut cpu, "quit").unwrap();
+        assert!(debugger.quit_requested());
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let cpu = debug_cpu();
+        assert_eq!(disassemble_from(&cpu, 0x8000, 3),
+            "8000  20 08 80  JSR $8008\n8003  E8        INX\n8004  4C 00 80  JMP $8000");
+    }
+}
@@ -0,0 +1,294 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use crate::debugcodes::{self, AddressingMode};
+
+// Pure 6502 disassembler over byte slices, it never touches a live bus. Decoding follows DEBUG_OPCODES,
+// refining its NoneAddressing entries into the implied, accumulator, relative and indirect forms.
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Mode {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPage_X,
+    ZeroPage_Y,
+    Absolute,
+    Absolute_X,
+    Absolute_Y,
+    Indirect,
+    Indirect_X,
+    Indirect_Y,
+    Relative,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Instruction {
+    pub addr:     u16,
+    pub opcode:   u8,
+    /// Lowercase, unofficial opcodes are prefixed with '*' like in nestest.log
+    pub mnemonic: &'static str,
+    pub mode:     Mode,
+    /// The operand byte or little-endian word, 0 when there is none
+    pub operand:  u16,
+    pub len:      u8,
+    /// Where a branch, JSR or absolute JMP goes
+    pub target:   Option<u16>,
+}
+
+impl Instruction {
+    pub fn is_official(&self) -> bool {
+        !self.mnemonic.starts_with('*')
+    }
+
+    /// Operand in standard assembler syntax, e.g. `#$10`, `($20),Y` or the target of a branch
+    pub fn operand_text(&self) -> String {
+        match self.mode {
+            Mode::Implied => String::new(),
+            Mode::Accumulator => "A".to_string(),
+            Mode::Immediate => format!("#${:02X}", self.operand),
+            Mode::ZeroPage => format!("${:02X}", self.operand),
+            Mode::ZeroPage_X => format!("${:02X},X", self.operand),
+            Mode::ZeroPage_Y => format!("${:02X},Y", self.operand),
+            Mode::Absolute => format!("${:04X}", self.operand),
+            Mode::Absolute_X => format!("${:04X},X", self.operand),
+            Mode::Absolute_Y => format!("${:04X},Y", self.operand),
+            Mode::Indirect => format!("(${:04X})", self.operand),
+            Mode::Indirect_X => format!("(${:02X},X)", self.operand),
+            Mode::Indirect_Y => format!("(${:02X}),Y", self.operand),
+            Mode::Relative => format!("${:04X}", self.target.unwrap_or(0)),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let operand = self.operand_text();
+        if operand.is_empty() {
+            write!(f, "{}", self.mnemonic.to_ascii_uppercase())
+        } else {
+            write!(f, "{} {}", self.mnemonic.to_ascii_uppercase(), operand)
+        }
+    }
+}
+
+/// Decodes the instruction at the start of `bytes`, which sits at `addr`. Returns None if `bytes` is too
+/// short to hold all of it
+pub fn decode(bytes: &[u8], addr: u16) -> Option<Instruction> {
+    let opcode = *bytes.first()?;
+    let ops = &debugcodes::DEBUG_OPCODES[opcode as usize];
+    if bytes.len() < ops.len as usize {
+        return None;
+    }
+
+    let operand = match ops.len {
+        2 => bytes[1] as u16,
+        3 => (bytes[2] as u16) << 8 | bytes[1] as u16,
+        _ => 0,
+    };
+
+    let mode = match ops.mode {
+        AddressingMode::Immediate => Mode::Immediate,
+        AddressingMode::ZeroPage => Mode::ZeroPage,
+        AddressingMode::ZeroPage_X => Mode::ZeroPage_X,
+        AddressingMode::ZeroPage_Y => Mode::ZeroPage_Y,
+        AddressingMode::Absolute => Mode::Absolute,
+        AddressingMode::Absolute_X => Mode::Absolute_X,
+        AddressingMode::Absolute_Y => Mode::Absolute_Y,
+        AddressingMode::Indirect_X => Mode::Indirect_X,
+        AddressingMode::Indirect_Y => Mode::Indirect_Y,
+        AddressingMode::NoneAddressing => match (ops.len, opcode) {
+            (1, 0x0a | 0x4a | 0x2a | 0x6a) => Mode::Accumulator,
+            (2, _) => Mode::Relative,
+            (3, 0x6c) => Mode::Indirect,
+            (3, _) => Mode::Absolute,
+            _ => Mode::Implied,
+        },
+    };
+
+    let target = match (mode, opcode) {
+        (Mode::Relative, _) => Some(addr.wrapping_add(2).wrapping_add(operand as u8 as i8 as u16)),
+        (Mode::Absolute, 0x20 | 0x4c) => Some(operand),
+        _ => None,
+    };
+
+    Some(Instruction { addr, opcode, mnemonic: ops.mnemonic, mode, operand, len: ops.len, target })
+}
+
+/// Linear sweep over `data` loaded at `origin`. Stops before an instruction cut off by the end of the slice
+pub fn disassemble(data: &[u8], origin: u16) -> Vec<Instruction> {
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    while let Some(instruction) = decode(&data[offset..], origin.wrapping_add(offset as u16)) {
+        offset += instruction.len as usize;
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+/// Names every branch and jump target that starts one of `instructions`, e.g. `LC5F5`
+pub fn labels(instructions: &[Instruction]) -> BTreeMap<u16, String> {
+    let starts: Vec<u16> = instructions.iter().map(|ins| ins.addr).collect();
+    instructions.iter()
+        .filter_map(|ins| ins.target)
+        .filter(|target| starts.binary_search(target).is_ok())
+        .map(|target| (target, format!("L{:04X}", target)))
+        .collect()
+}
+
+// Vectors occupy the last 6 bytes of the address space
+const VECTORS: [&str; 3] = ["NMI", "RESET", "IRQ"];
+const VECTORS_START: u32 = 0xfffa;
+
+/// ca65 source that assembles back to exactly `data` when placed at `origin`
+pub fn to_ca65(data: &[u8], origin: u16) -> String {
+    let end = origin as u32 + data.len() as u32;
+    let (code, vectors) = if end == 0x10000 && data.len() >= 6 {
+        data.split_at(data.len() - 6)
+    } else {
+        (data, &[][..])
+    };
+
+    let instructions = disassemble(code, origin);
+    let mut labels = labels(&instructions);
+    let vector_targets: Vec<u16> = vectors.chunks(2).map(|v| (v[1] as u16) << 8 | v[0] as u16).collect();
+    for (name, target) in VECTORS.iter().zip(&vector_targets) {
+        if instructions.iter().any(|ins| ins.addr == *target) {
+            labels.insert(*target, name.to_string());
+        }
+    }
+
+    let mut out = format!(".org ${:04X}\n", origin);
+    for ins in instructions.iter() {
+        if let Some(label) = labels.get(&ins.addr) {
+            out.push_str(&format!("{}:\n", label));
+        }
+        out.push_str(&format!("    {}\n", ca65_line(ins, code, origin, &labels)));
+    }
+
+    let decoded: usize = instructions.iter().map(|ins| ins.len as usize).sum();
+    for chunk in code[decoded..].chunks(8) {
+        out.push_str(&format!("    {}\n", byte_directive(chunk)));
+    }
+
+    if !vectors.is_empty() {
+        out.push_str(&format!(".org ${:04X}\n", VECTORS_START));
+        for (name, target) in VECTORS.iter().zip(&vector_targets) {
+            let value = labels.get(target).cloned().unwrap_or_else(|| format!("${:04X}", target));
+            out.push_str(&format!("    .word {} ; {}\n", value, name));
+        }
+    }
+    out
+}
+
+fn ca65_line(ins: &Instruction, code: &[u8], origin: u16, labels: &BTreeMap<u16, String>) -> String {
+    // ca65 has no spelling for every unofficial opcode, so those are kept as raw bytes
+    if !ins.is_official() {
+        let offset = ins.addr.wrapping_sub(origin) as usize;
+        let bytes = &code[offset..offset + ins.len as usize];
+        return format!("{} ; {}", byte_directive(bytes), ins.to_string().to_ascii_lowercase());
+    }
+
+    let word = |value: u16| -> String {
+        // Without `a:` ca65 would shrink an absolute address below $100 to zero page
+        if value < 0x100 { format!("a:${:04X}", value) } else { format!("${:04X}", value) }
+    };
+    let operand = match ins.mode {
+        Mode::Relative | Mode::Absolute if ins.target.is_some() => {
+            let target = ins.target.unwrap();
+            match labels.get(&target) {
+                Some(label) => label.clone(),
+                None if ins.mode == Mode::Absolute => word(target),
+                None => format!("${:04X}", target),
+            }
+        }
+        Mode::Absolute => word(ins.operand),
+        Mode::Absolute_X => format!("{},x", word(ins.operand)),
+        Mode::Absolute_Y => format!("{},y", word(ins.operand)),
+        Mode::Accumulator => "a".to_string(),
+        _ => ins.operand_text().replace(",X", ",x").replace(",Y", ",y"),
+    };
+
+    if operand.is_empty() {
+        ins.mnemonic.to_string()
+    } else {
+        format!("{} {}", ins.mnemonic, operand)
+    }
+}
+
+fn byte_directive(bytes: &[u8]) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|b| format!("${:02X}", b)).collect();
+    format!(".byte {}", bytes.join(", "))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode_modes() {
+        let cases: Vec<(Vec<u8>, Mode, &str)> = vec![
+            (vec![0xe8], Mode::Implied, "INX"),
+            (vec![0x0a], Mode::Accumulator, "ASL A"),
+            (vec![0xa9, 0x10], Mode::Immediate, "LDA #$10"),
+            (vec![0xb5, 0x10], Mode::ZeroPage_X, "LDA $10,X"),
+            (vec![0xbd, 0x34, 0x12], Mode::Absolute_X, "LDA $1234,X"),
+            (vec![0x6c, 0xff, 0x02], Mode::Indirect, "JMP ($02FF)"),
+            (vec![0xa1, 0x20], Mode::Indirect_X, "LDA ($20,X)"),
+            (vec![0xb1, 0x20], Mode::Indirect_Y, "LDA ($20),Y"),
+            (vec![0xd0, 0xfe], Mode::Relative, "BNE $8000"),
+            (vec![0x04, 0xa9], Mode::ZeroPage, "*NOP $A9"),
+        ];
+        for (bytes, mode, text) in cases {
+            let ins = decode(&bytes, 0x8000).unwrap();
+            assert_eq!(ins.mode, mode);
+            assert_eq!(ins.len as usize, bytes.len());
+            assert_eq!(ins.to_string(), text);
+        }
+
+        assert_eq!(decode(&[0x20, 0x2d, 0xc7], 0xc5fd).unwrap().target, Some(0xc72d));
+        assert_eq!(decode(&[0x10, 0x04], 0xc72f).unwrap().target, Some(0xc735));
+        assert_eq!(decode(&[0x6c, 0x00, 0x02], 0x8000).unwrap().target, None);
+        assert_eq!(decode(&[0xad, 0x00], 0x8000), None);
+    }
+
+    #[test]
+    fn test_disassemble_and_labels() {
+        // loop: INX; BNE loop; JSR $9000; JMP loop
+        let code = [0xe8, 0xd0, 0xfd, 0x20, 0x00, 0x90, 0x4c, 0x00, 0x80, 0xad];
+        let instructions = disassemble(&code, 0x8000);
+        assert_eq!(instructions.len(), 4);
+        assert_eq!(instructions[3].addr, 0x8006);
+
+        let labels = labels(&instructions);
+        assert_eq!(labels.len(), 1);
+        assert_eq!(labels[&0x8000], "L8000");
+    }
+
+    #[test]
+    fn test_to_ca65() {
+        let code = [0xe8, 0xd0, 0xfd, 0xad, 0x10, 0x00, 0x04, 0xa9, 0x4c, 0x00, 0x80, 0xad];
+        assert_eq!(to_ca65(&code, 0x8000), "\
+.org $8000
+L8000:
+    inx
+    bne L8000
+    lda a:$0010
+    .byte $04, $A9 ; *nop $a9
+    jmp L8000
+    .byte $AD
+");
+    }
+
+    #[test]
+    fn test_to_ca65_vectors() {
+        let mut bank = vec![0xea; 0x4000];
+        bank[0] = 0x40;
+        bank[0x3ffa..].copy_from_slice(&[0x00, 0xc0, 0x01, 0xc0, 0x34, 0x12]);
+        let source = to_ca65(&bank, 0xc000);
+
+        assert!(source.starts_with(".org $C000\nNMI:\n    rti\nRESET:\n    nop\n"));
+        assert!(source.ends_with(".org $FFFA\n    .word NMI ; NMI\n    .word RESET ; RESET\n    .word $1234 ; IRQ\n"));
+    }
+}
@@ -0,0 +1,87 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use crate::palette::{Palette, DEFAULT_PALETTE};
+
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 240;
+
+// Output of the PPU. Each pixel holds the 6-bit colour value read from palette RAM in bits 0-5 and the
+// red, green and blue emphasis bits of $2001 in bits 6-8, which the front end converts to RGB when presenting
+pub struct Frame {
+    pub pixels: Vec<u16>,
+}
+
+impl Frame {
+    pub fn new() -> Self {
+        Frame {
+            pixels: vec![0; WIDTH * HEIGHT],
+        }
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: u16) {
+        self.pixels[y * WIDTH + x] = color & 0x1FF;
+    }
+
+    pub fn get_pixel(&self, x: usize, y: usize) -> u16 {
+        self.pixels[y * WIDTH + x]
+    }
+
+    /// Packed RGB24 using the default palette, suitable for an SDL texture with a pitch of WIDTH * 3
+    pub fn to_rgb(&self) -> Vec<u8> {
+        self.to_rgb_with(&DEFAULT_PALETTE)
+    }
+
+    pub fn to_rgb_with(&self, palette: &Palette) -> Vec<u8> {
+        let mut rgb = Vec::with_capacity(WIDTH * HEIGHT * 3);
+        for &pixel in self.pixels.iter() {
+            let (r, g, b) = palette.rgb(pixel);
+            rgb.extend_from_slice(&[r, g, b]);
+        }
+        rgb
+    }
+
+    /// Writes the frame as an 8-bit RGB PNG, coloured with `palette`
+    pub fn save_png(&self, path: &Path, palette: &Palette) -> Result<(), String> {
+        let error = |e: &dyn std::fmt::Display| format!("Failed to write {}: {}", path.display(), e);
+        let file = File::create(path).map_err(|e| error(&e))?;
+
+        let mut encoder = png::Encoder::new(BufWriter::new(file), WIDTH as u32, HEIGHT as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().map_err(|e| error(&e))?;
+        writer.write_image_data(&self.to_rgb_with(palette)).map_err(|e| error(&e))
+    }
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Frame::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_save_png() {
+        let path = std::env::temp_dir().join(format!("nes-frame-test-{}.png", std::process::id()));
+        let mut frame = Frame::new();
+        frame.set_pixel(10, 20, 0x16);
+        // A palette other than the default, so the PNG has to come out in its colours
+        let data: Vec<u8> = (0..64 * 3).map(|i| (i / 3 * 4) as u8).collect();
+        let palette = Palette::from_pal(&data).unwrap();
+        frame.save_png(&path, &palette).unwrap();
+
+        let decoder = png::Decoder::new(File::open(&path).unwrap());
+        let mut reader = decoder.read_info().unwrap();
+        let mut rgb = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut rgb).unwrap();
+        assert_eq!((reader.info().width, reader.info().height), (WIDTH as u32, HEIGHT as u32));
+        assert_eq!(rgb, frame.to_rgb_with(&palette));
+        assert_eq!(&rgb[(20 * WIDTH + 10) * 3..][..3], &[0x58, 0x58, 0x58]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
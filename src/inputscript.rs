@@ -0,0 +1,115 @@
+use crate::bus::Bus;
+use crate::joypad::JoypadButton;
+
+// Scripted controller input for headless runs. Each line gives the frame it takes effect on and the buttons
+// held on controller 1, optionally followed by `;` and the buttons for controller 2. Buttons stay held until
+// a later line changes them. `-` releases everything and `#` starts a comment:
+//
+//     0    -
+//     60   start
+//     62   -
+//     120  a right ; b
+pub struct InputScript {
+    // Sorted by frame
+    events: Vec<(usize, [JoypadButton; 2])>,
+}
+
+impl InputScript {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut events: Vec<(usize, [JoypadButton; 2])> = Vec::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |message: String| format!("Input script line {}: {}", number + 1, message);
+
+            let (frame, buttons) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let frame = frame.parse::<usize>().map_err(|_| error(format!("'{}' is not a frame number", frame)))?;
+            if events.last().is_some_and(|(last, _)| *last >= frame) {
+                return Err(error("frames must be increasing".to_string()));
+            }
+
+            let mut pads = [JoypadButton::empty(); 2];
+            let mut ports = buttons.split(';');
+            for pad in pads.iter_mut() {
+                *pad = parse_buttons(ports.next().unwrap_or("")).map_err(error)?;
+            }
+            if ports.next().is_some() {
+                return Err(error("there are only two controllers".to_string()));
+            }
+            events.push((frame, pads));
+        }
+        Ok(InputScript { events })
+    }
+
+    /// Buttons held on both controllers during `frame`
+    pub fn buttons_at(&self, frame: usize) -> [JoypadButton; 2] {
+        self.events.iter()
+            .take_while(|(start, _)| *start <= frame)
+            .last()
+            .map_or([JoypadButton::empty(); 2], |(_, pads)| *pads)
+    }
+
+    /// Presses the buttons for `frame`, call before running it
+    pub fn apply(&self, frame: usize, bus: &mut Bus) {
+        for (port, buttons) in self.buttons_at(frame).iter().enumerate() {
+            let joypad = bus.joypad_mut(port);
+            joypad.set_button_pressed_status(JoypadButton::all(), false);
+            joypad.set_button_pressed_status(*buttons, true);
+        }
+    }
+}
+
+fn parse_buttons(text: &str) -> Result<JoypadButton, String> {
+    let mut buttons = JoypadButton::empty();
+    for name in text.split(|c: char| c == ',' || c.is_whitespace()).filter(|name| !name.is_empty()) {
+        buttons |= match name.to_ascii_lowercase().as_str() {
+            "-" => JoypadButton::empty(),
+            "a" => JoypadButton::BUTTON_A,
+            "b" => JoypadButton::BUTTON_B,
+            "select" => JoypadButton::SELECT,
+            "start" => JoypadButton::START,
+            "up" => JoypadButton::UP,
+            "down" => JoypadButton::DOWN,
+            "left" => JoypadButton::LEFT,
+            "right" => JoypadButton::RIGHT,
+            _ => return Err(format!("unknown button '{}'", name)),
+        };
+    }
+    Ok(buttons)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_hold() {
+        let script = InputScript::parse("# title screen\n0 -\n60 start\n62 -\n120 A, right ; b\n").unwrap();
+
+        assert_eq!(script.buttons_at(0), [JoypadButton::empty(); 2]);
+        assert_eq!(script.buttons_at(61), [JoypadButton::START, JoypadButton::empty()]);
+        assert_eq!(script.buttons_at(100), [JoypadButton::empty(); 2]);
+        assert_eq!(script.buttons_at(5000), [JoypadButton::BUTTON_A | JoypadButton::RIGHT, JoypadButton::BUTTON_B]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(InputScript::parse("x start").is_err());
+        assert!(InputScript::parse("10 jump").is_err());
+        assert!(InputScript::parse("10 a\n5 b").is_err());
+        assert!(InputScript::parse("10 a ; b ; start").is_err());
+    }
+
+    #[test]
+    fn test_apply() {
+        let script = InputScript::parse("0 up\n1 down").unwrap();
+        let mut bus = Bus::new(crate::cartridge::test::test_rom(vec![]));
+
+        script.apply(0, &mut bus);
+        script.apply(1, &mut bus);
+        assert_eq!(bus.joypad_mut(0).button_status(), JoypadButton::DOWN);
+    }
+}
@@ -0,0 +1,144 @@
+// From bugzmanov nes_ebook
+use crate::savestate::{StateReader, StateWriter, Stateful};
+
+// Standard controller: writing 1 to $4016 holds the strobe and keeps reloading the buttons,
+// writing 0 latches them so that each read shifts out one button in the order A, B, Select, Start, Up, Down, Left, Right
+bitflags! {
+    pub struct JoypadButton: u8 {
+        const RIGHT    = 0b10000000;
+        const LEFT     = 0b01000000;
+        const DOWN     = 0b00100000;
+        const UP       = 0b00010000;
+        const START    = 0b00001000;
+        const SELECT   = 0b00000100;
+        const BUTTON_B = 0b00000010;
+        const BUTTON_A = 0b00000001;
+    }
+}
+
+pub struct Joypad {
+    strobe: bool,
+    button_index: u8,
+    button_status: JoypadButton,
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Joypad {
+            strobe: false,
+            button_index: 0,
+            button_status: JoypadButton::from_bits_truncate(0),
+        }
+    }
+
+    pub fn write(&mut self, data: u8) {
+        self.strobe = data & 1 == 1;
+        if self.strobe {
+            self.button_index = 0
+        }
+    }
+
+    pub fn read(&mut self) -> u8 {
+        let response = self.peek();
+        if !self.strobe && self.button_index <= 7 {
+            self.button_index += 1;
+        }
+        response
+    }
+
+    /// The bit the next read returns, without shifting to the next button
+    pub fn peek(&self) -> u8 {
+        // Official controllers return 1 once all eight buttons have been read
+        if self.button_index > 7 {
+            return 1;
+        }
+        (self.button_status.bits & (1 << self.button_index)) >> self.button_index
+    }
+
+    pub fn set_button_pressed_status(&mut self, button: JoypadButton, pressed: bool) {
+        self.button_status.set(button, pressed);
+    }
+
+    pub fn button_status(&self) -> JoypadButton {
+        self.button_status
+    }
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Joypad::new()
+    }
+}
+
+impl Stateful for Joypad {
+    fn save(&self, w: &mut StateWriter) {
+        w.write_bool(self.strobe);
+        w.write_u8(self.button_index);
+        w.write_u8(self.button_status.bits());
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.strobe = r.read_bool()?;
+        self.button_index = r.read_u8()?;
+        self.button_status = JoypadButton::from_bits_truncate(r.read_u8()?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+    use crate::cpu::Mem;
+
+    #[test]
+    fn test_strobe_mode() {
+        let mut joypad = Joypad::new();
+        joypad.write(1);
+        joypad.set_button_pressed_status(JoypadButton::BUTTON_A, true);
+        for _ in 0..10 {
+            assert_eq!(joypad.read(), 1);
+        }
+    }
+
+    #[test]
+    fn test_strobe_mode_on_off() {
+        let mut joypad = Joypad::new();
+
+        joypad.write(0);
+        joypad.set_button_pressed_status(JoypadButton::RIGHT, true);
+        joypad.set_button_pressed_status(JoypadButton::LEFT, true);
+        joypad.set_button_pressed_status(JoypadButton::SELECT, true);
+        joypad.set_button_pressed_status(JoypadButton::BUTTON_B, true);
+
+        for _ in 0..=1 {
+            assert_eq!(joypad.read(), 0);
+            assert_eq!(joypad.read(), 1);
+            assert_eq!(joypad.read(), 1);
+            assert_eq!(joypad.read(), 0);
+            assert_eq!(joypad.read(), 0);
+            assert_eq!(joypad.read(), 0);
+            assert_eq!(joypad.read(), 1);
+            assert_eq!(joypad.read(), 1);
+
+            for _ in 0..10 {
+                assert_eq!(joypad.read(), 1);
+            }
+            joypad.write(1);
+            joypad.write(0);
+        }
+    }
+
+    #[test]
+    fn test_bus_controller_ports() {
+        let mut bus = Bus::new(test_rom(vec![]));
+        bus.joypad_mut(1).set_button_pressed_status(JoypadButton::BUTTON_A, true);
+
+        bus.mem_write(0x4016, 1);
+        bus.mem_write(0x4016, 0);
+        assert_eq!(bus.mem_read(0x4016), 0);
+        assert_eq!(bus.mem_read(0x4017), 1);
+        assert_eq!(bus.mem_read(0x4017), 0);
+    }
+}
@@ -0,0 +1,25 @@
+pub mod cpu;
+pub mod opcodes;
+pub mod bus;
+pub mod cartridge;
+pub mod mapper;
+pub mod trace;
+pub mod debugcodes;
+pub mod disasm;
+pub mod debugger;
+pub mod ppu;
+pub mod apu;
+pub mod joypad;
+pub mod savestate;
+pub mod rewind;
+pub mod testrom;
+pub mod inputscript;
+pub mod frame;
+pub mod palette;
+pub mod ntsc;
+
+#[macro_use]
+extern crate lazy_static;
+
+#[macro_use]
+extern crate bitflags;
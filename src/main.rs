@@ -1,145 +1,421 @@
-pub mod cpu;
-pub mod opcodes;
-pub mod bus;
-pub mod cartridge;
-pub mod trace;
-pub mod debugcodes;
-pub mod ppu;
-
-use cpu::CPU;
-use cpu::Mem;
-use bus::Bus;
-use cartridge::Rom;
-use trace::trace;
-//use rand::Rng;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
+use nes_emulator::bus::Bus;
+use nes_emulator::cartridge::Rom;
+use nes_emulator::cpu::CPU;
+use nes_emulator::frame::{HEIGHT, WIDTH};
+use nes_emulator::joypad::JoypadButton;
+use nes_emulator::ntsc::{self, NtscFilter, NtscSettings};
+use nes_emulator::palette::Palette;
+use nes_emulator::rewind::Rewind;
+use nes_emulator::savestate;
+
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::controller::{Axis, Button, GameController};
 use sdl2::event::Event;
-use sdl2::EventPump;
-use sdl2::keyboard::Keycode;
-use sdl2::pixels::Color;
-use sdl2::pixels::PixelFormatEnum;
-// use std::time::Duration;
-
-// Temporary main implementation from bugzmanov nes_ebook for bug testing opcodes
-#[macro_use]
-extern crate lazy_static;
-
-#[macro_use]
-extern crate bitflags;
-
-fn color(byte: u8) -> Color {
-    match byte {
-        0 => sdl2::pixels::Color::BLACK,
-        1 => sdl2::pixels::Color::WHITE,
-        2 | 9 => sdl2::pixels::Color::GREY,
-        3 | 10 => sdl2::pixels::Color::RED,
-        4 | 11 => sdl2::pixels::Color::GREEN,
-        5 | 12 => sdl2::pixels::Color::BLUE,
-        6 | 13 => sdl2::pixels::Color::MAGENTA,
-        7 | 14 => sdl2::pixels::Color::YELLOW,
-        _ => sdl2::pixels::Color::CYAN,
-    }
+use sdl2::keyboard::{Keycode, Mod};
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::Rect;
+use sdl2::render::{Canvas, Texture};
+use sdl2::video::{FullscreenType, Window};
+
+// NTSC runs 39375000/11 * 3 / 2 / 341 / 262 - 0.5 frames per second, counting the dot skipped on odd frames
+const FRAME_RATE: f64 = 60.0988;
+// NES pixels are slightly wider than they are tall
+const PIXEL_ASPECT: f64 = 8.0 / 7.0;
+const DEFAULT_SCALE: u32 = 3;
+
+const SAMPLE_RATE: i32 = 44100;
+// Frames of audio allowed to pile up in the queue before new samples are dropped
+const MAX_AUDIO_FRAMES: u32 = 4;
+
+// Rewind keeps a snapshot every other frame within 64MB. Only the newest snapshot is whole, ~135K, the rest
+// of the budget goes to the much smaller deltas
+const REWIND_INTERVAL: usize = 2;
+const REWIND_BUDGET: usize = 64 * 1024 * 1024;
+
+// Left stick travel that counts as a d-pad press
+const STICK_THRESHOLD: i16 = 16000;
+
+const USAGE: &str = "\
+usage: nes-emulator <rom.nes> [--scale <n>] [--palette <file.pal>]
+                    [--ntsc] [--sharpness <-1..1>] [--saturation <-1..1>] [--hue <-1..1>]
+  arrows d-pad, X A, Z B, right shift Select, enter Start
+  P pause, F3 reset, F9 NTSC filter, F11 or alt+enter fullscreen, esc quit
+  F5 save state, F7 load state, 0-9 pick the save slot, hold backspace to rewind";
+
+struct Options {
+    rom: PathBuf,
+    scale: u32,
+    palette: Option<PathBuf>,
+    ntsc: bool,
+    ntsc_settings: NtscSettings,
+}
+
+// Buttons currently held, per controller port, from the keyboard and from game controllers
+struct Input {
+    keyboard: JoypadButton,
+    controllers: [JoypadButton; 2],
+    sticks: [JoypadButton; 2],
 }
 
-fn read_screen_state(cpu: &mut CPU, frame: &mut [u8; 32 * 3 * 32]) -> bool {
-    let mut frame_idx = 0;
-    let mut update = false;
-    for i in 0x0200..0x600 {
-        let color_idx = cpu.mem_read(i as u16);
-        let (b1, b2, b3) = color(color_idx).rgb();
-        if frame[frame_idx] != b1 || frame[frame_idx + 1] != b2 || frame[frame_idx + 2] != b3 {
-            frame[frame_idx] = b1;
-            frame[frame_idx + 1] = b2;
-            frame[frame_idx + 2] = b3;
-            update = true;
+impl Input {
+    fn new() -> Self {
+        Input {
+            keyboard: JoypadButton::empty(),
+            controllers: [JoypadButton::empty(); 2],
+            sticks: [JoypadButton::empty(); 2],
         }
-        frame_idx += 3;
     }
-    update
+
+    fn buttons(&self, port: usize) -> JoypadButton {
+        let keyboard = if port == 0 { self.keyboard } else { JoypadButton::empty() };
+        keyboard | self.controllers[port] | self.sticks[port]
+    }
 }
 
-fn handle_user_input(cpu: &mut CPU, event_pump: &mut EventPump) {
-    for event in event_pump.poll_iter() {
-        match event {
-            Event::Quit { .. }
-            | Event::KeyDown {
-                keycode: Some(Keycode::Escape),
-                ..
-            } => std::process::exit(0),
-            Event::KeyDown {
-                keycode: Some(Keycode::W),
-                ..
-            } => {
-                cpu.mem_write(0xff, 0x77);
-            }
-            Event::KeyDown {
-                keycode: Some(Keycode::S),
-                ..
-            } => {
-                cpu.mem_write(0xff, 0x73);
-            }
-            Event::KeyDown {
-                keycode: Some(Keycode::A),
-                ..
-            } => {
-                cpu.mem_write(0xff, 0x61);
-            }
-            Event::KeyDown {
-                keycode: Some(Keycode::D),
-                ..
-            } => {
-                cpu.mem_write(0xff, 0x64);
-            }
-            _ => { /* do nothing */ }
+fn main() {
+    let options = match parse_args(std::env::args().skip(1).collect()) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            std::process::exit(1);
         }
+    };
+
+    if let Err(e) = run(&options) {
+        eprintln!("{}", e);
+        std::process::exit(1);
     }
 }
 
-fn main() {
-    // init sdl2
-    let sdl_context = sdl2::init().unwrap();
-    let video_subsystem = sdl_context.video().unwrap();
+fn run(options: &Options) -> Result<(), String> {
+    let bytes = std::fs::read(&options.rom).map_err(|e| format!("Failed to read {}: {}", options.rom.display(), e))?;
+    let rom = Rom::new(&bytes)?;
+    let mut cpu = CPU::new(Bus::with_save_file(rom, &options.rom)?);
+    cpu.reset();
+    let palette = match &options.palette {
+        Some(path) => Palette::load(path)?,
+        None => Palette::default(),
+    };
+
+    let sdl_context = sdl2::init()?;
+    let video_subsystem = sdl_context.video()?;
+    let controller_subsystem = sdl_context.game_controller()?;
+
+    let title = options.rom.file_stem().map_or("NES".to_string(), |name| name.to_string_lossy().to_string());
     let window = video_subsystem
-        .window("Snake game", (32.0 * 10.0) as u32, (32.0 * 10.0) as u32)
+        .window(&title, (WIDTH as f64 * options.scale as f64 * PIXEL_ASPECT).round() as u32, HEIGHT as u32 * options.scale)
         .position_centered()
+        .resizable()
         .build()
-        .unwrap();
-
-    let mut canvas = window.into_canvas().present_vsync().build().unwrap();
-    let mut event_pump = sdl_context.event_pump().unwrap();
-    canvas.set_scale(10.0, 10.0).unwrap();
+        .map_err(|e| e.to_string())?;
 
+    let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
     let creator = canvas.texture_creator();
     let mut texture = creator
-        .create_texture_target(PixelFormatEnum::RGB24, 32, 32)
-        .unwrap();
+        .create_texture_streaming(PixelFormatEnum::RGB24, WIDTH as u32, HEIGHT as u32)
+        .map_err(|e| e.to_string())?;
+    // The filtered image is wider but covers the same area of the screen
+    let mut ntsc_texture = creator
+        .create_texture_streaming(PixelFormatEnum::RGB24, ntsc::OUT_WIDTH as u32, HEIGHT as u32)
+        .map_err(|e| e.to_string())?;
+    let ntsc_filter = NtscFilter::new(options.ntsc_settings);
+    let mut use_ntsc = options.ntsc;
+    let mut frames = 0;
 
-    //load the game
-    let bytes: Vec<u8> = std::fs::read("nestest.nes").unwrap();
-    let rom = Rom::new(&bytes).unwrap();
+    // Keep running without sound when there is no audio device
+    let audio = match open_audio(&sdl_context) {
+        Ok(queue) => {
+            cpu.bus.apu_mut().set_sample_rate(queue.spec().freq as u32);
+            Some(queue)
+        }
+        Err(e) => {
+            eprintln!("Audio disabled: {}", e);
+            None
+        }
+    };
 
-    let bus = Bus::new(rom);
-    let mut cpu = CPU::new(bus);
-    cpu.reset();
-    cpu.reg_pc = 0xC000;
-    // let mut screen_state = [0 as u8; 32 * 3 * 32];
-    // let mut rng = rand::thread_rng();
+    let mut event_pump = sdl_context.event_pump()?;
+    let mut controllers: HashMap<u32, (GameController, usize)> = HashMap::new();
+    let mut input = Input::new();
+    let mut rewind = Rewind::new(REWIND_INTERVAL, REWIND_BUDGET);
+
+    let mut paused = false;
+    let mut rewinding = false;
+    let mut slot = 1;
+    let frame_time = Duration::from_secs_f64(1.0 / FRAME_RATE);
+    let mut next_frame = Instant::now();
 
-    // run the game cycle
-    cpu.run_with_callback(move |cpu| {
-        println!("{}", trace(cpu));
-        // handle_user_input(cpu, &mut event_pump);
+    'running: loop {
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. } | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => break 'running,
 
-        // cpu.mem_write(0xfe, rng.gen_range(1..16));
+                Event::KeyDown { keycode: Some(key), keymod, repeat: false, .. } => match key {
+                    Keycode::P => {
+                        paused = !paused;
+                        if let Some(audio) = &audio {
+                            audio.clear();
+                        }
+                    }
+                    Keycode::F3 => {
+                        cpu.reset();
+                        rewind.clear();
+                    }
+                    Keycode::F9 => use_ntsc = !use_ntsc,
+                    Keycode::F11 => toggle_fullscreen(&mut canvas)?,
+                    Keycode::Return if keymod.intersects(Mod::LALTMOD | Mod::RALTMOD) => toggle_fullscreen(&mut canvas)?,
+                    Keycode::F5 => match savestate::save_slot(&cpu, &options.rom, slot) {
+                        Ok(()) => eprintln!("Saved state to slot {}", slot),
+                        Err(e) => eprintln!("{}", e),
+                    },
+                    Keycode::F7 => match savestate::load_slot(&mut cpu, &options.rom, slot) {
+                        Ok(()) => {
+                            rewind.clear();
+                            eprintln!("Loaded state from slot {}", slot);
+                        }
+                        Err(e) => eprintln!("{}", e),
+                    },
+                    Keycode::Backspace => rewinding = true,
+                    _ => {
+                        if let Some(digit) = slot_key(key) {
+                            slot = digit;
+                            eprintln!("Save slot {}", slot);
+                        }
+                        if let Some(button) = keyboard_button(key) {
+                            input.keyboard.insert(button);
+                        }
+                    }
+                },
 
-        // if read_screen_state(cpu, &mut screen_state) {
-        //     texture.update(None, &screen_state, 32 * 3).unwrap();
+                Event::KeyUp { keycode: Some(key), .. } => {
+                    if key == Keycode::Backspace {
+                        rewinding = false;
+                    }
+                    if let Some(button) = keyboard_button(key) {
+                        input.keyboard.remove(button);
+                    }
+                }
 
-        //     canvas.copy(&texture, None, None).unwrap();
+                Event::ControllerDeviceAdded { which, .. } => {
+                    let used: Vec<usize> = controllers.values().map(|(_, port)| *port).collect();
+                    if let Some(port) = (0..2).find(|port| !used.contains(port)) {
+                        match controller_subsystem.open(which) {
+                            Ok(controller) => {
+                                eprintln!("{} is controller {}", controller.name(), port + 1);
+                                controllers.insert(controller.instance_id(), (controller, port));
+                            }
+                            Err(e) => eprintln!("Failed to open game controller: {}", e),
+                        }
+                    }
+                }
 
-        //     canvas.present();
-        // }
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    if let Some((_, port)) = controllers.remove(&which) {
+                        input.controllers[port] = JoypadButton::empty();
+                        input.sticks[port] = JoypadButton::empty();
+                    }
+                }
 
-        // ::std::thread::sleep(std::time::Duration::new(0, 70_000));
-    });
-}
\ No newline at end of file
+                Event::ControllerButtonDown { which, button, .. } => {
+                    if let (Some((_, port)), Some(button)) = (controllers.get(&which), controller_button(button)) {
+                        input.controllers[*port].insert(button);
+                    }
+                }
+
+                Event::ControllerButtonUp { which, button, .. } => {
+                    if let (Some((_, port)), Some(button)) = (controllers.get(&which), controller_button(button)) {
+                        input.controllers[*port].remove(button);
+                    }
+                }
+
+                Event::ControllerAxisMotion { which, axis, value, .. } => {
+                    if let Some((_, port)) = controllers.get(&which) {
+                        let stick = &mut input.sticks[*port];
+                        match axis {
+                            Axis::LeftX => {
+                                stick.set(JoypadButton::LEFT, value < -STICK_THRESHOLD);
+                                stick.set(JoypadButton::RIGHT, value > STICK_THRESHOLD);
+                            }
+                            Axis::LeftY => {
+                                stick.set(JoypadButton::UP, value < -STICK_THRESHOLD);
+                                stick.set(JoypadButton::DOWN, value > STICK_THRESHOLD);
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+
+                _ => {}
+            }
+        }
+
+        if !paused {
+            if rewinding {
+                if let Err(e) = rewind.step_back(&mut cpu) {
+                    eprintln!("{}", e);
+                    rewind.clear();
+                }
+                cpu.bus.apu_mut().take_samples();
+            } else {
+                for port in 0..2 {
+                    let joypad = cpu.bus.joypad_mut(port);
+                    joypad.set_button_pressed_status(JoypadButton::all(), false);
+                    joypad.set_button_pressed_status(input.buttons(port), true);
+                }
+                cpu.run_frame();
+                rewind.capture(&cpu);
+                frames += 1;
+
+                let samples = cpu.bus.apu_mut().take_samples();
+                if let Some(audio) = &audio {
+                    let max_queued = MAX_AUDIO_FRAMES * (audio.spec().freq as f64 / FRAME_RATE) as u32 * 4;
+                    if audio.size() < max_queued {
+                        audio.queue_audio(&samples)?;
+                    }
+                }
+            }
+        }
+
+        let frame = &cpu.bus.ppu().frame;
+        if use_ntsc {
+            // The colour burst phase moves on every frame, which makes the dot crawl
+            let rgb = ntsc_filter.filter(frame, frames % 3);
+            ntsc_texture.update(None, &rgb, ntsc::OUT_WIDTH * 3).map_err(|e| e.to_string())?;
+            present(&mut canvas, &ntsc_texture)?;
+        } else {
+            texture.update(None, &frame.to_rgb_with(&palette), WIDTH * 3).map_err(|e| e.to_string())?;
+            present(&mut canvas, &texture)?;
+        }
+
+        // Pace against the clock rather than vsync, since monitors rarely run at exactly 60.0988Hz.
+        // After a long stall (window drag, breakpoint) start over instead of racing to catch up
+        next_frame += frame_time;
+        let now = Instant::now();
+        if next_frame > now {
+            std::thread::sleep(next_frame - now);
+        } else if now - next_frame > frame_time * 4 {
+            next_frame = now;
+        }
+    }
+
+    cpu.bus.flush_save()
+}
+
+fn open_audio(sdl_context: &sdl2::Sdl) -> Result<AudioQueue<f32>, String> {
+    let audio_subsystem = sdl_context.audio()?;
+    let desired = AudioSpecDesired {
+        freq: Some(SAMPLE_RATE),
+        channels: Some(1),
+        samples: Some(1024),
+    };
+    let queue = audio_subsystem.open_queue::<f32, _>(None, &desired)?;
+    queue.resume();
+    Ok(queue)
+}
+
+// Scales the frame by the largest whole factor that fits the window, stretching it horizontally to the
+// NES pixel aspect ratio, and centres it
+fn present(canvas: &mut Canvas<Window>, texture: &Texture) -> Result<(), String> {
+    let (window_width, window_height) = canvas.output_size()?;
+    let aspect_width = WIDTH as f64 * PIXEL_ASPECT;
+    let scale = ((window_width as f64 / aspect_width).min(window_height as f64 / HEIGHT as f64).floor() as u32).max(1);
+
+    let width = (aspect_width * scale as f64).round() as u32;
+    let height = HEIGHT as u32 * scale;
+    let x = (window_width as i32 - width as i32) / 2;
+    let y = (window_height as i32 - height as i32) / 2;
+
+    canvas.set_draw_color(Color::BLACK);
+    canvas.clear();
+    canvas.copy(texture, None, Rect::new(x, y, width, height))?;
+    canvas.present();
+    Ok(())
+}
+
+fn toggle_fullscreen(canvas: &mut Canvas<Window>) -> Result<(), String> {
+    let window = canvas.window_mut();
+    let mode = match window.fullscreen_state() {
+        FullscreenType::Off => FullscreenType::Desktop,
+        _ => FullscreenType::Off,
+    };
+    window.set_fullscreen(mode)
+}
+
+fn keyboard_button(key: Keycode) -> Option<JoypadButton> {
+    match key {
+        Keycode::Up => Some(JoypadButton::UP),
+        Keycode::Down => Some(JoypadButton::DOWN),
+        Keycode::Left => Some(JoypadButton::LEFT),
+        Keycode::Right => Some(JoypadButton::RIGHT),
+        Keycode::X => Some(JoypadButton::BUTTON_A),
+        Keycode::Z => Some(JoypadButton::BUTTON_B),
+        Keycode::RShift => Some(JoypadButton::SELECT),
+        Keycode::Return => Some(JoypadButton::START),
+        _ => None,
+    }
+}
+
+// NES B and A sit where the bottom and right face buttons of a modern pad are
+fn controller_button(button: Button) -> Option<JoypadButton> {
+    match button {
+        Button::DPadUp => Some(JoypadButton::UP),
+        Button::DPadDown => Some(JoypadButton::DOWN),
+        Button::DPadLeft => Some(JoypadButton::LEFT),
+        Button::DPadRight => Some(JoypadButton::RIGHT),
+        Button::B => Some(JoypadButton::BUTTON_A),
+        Button::A | Button::X => Some(JoypadButton::BUTTON_B),
+        Button::Back => Some(JoypadButton::SELECT),
+        Button::Start => Some(JoypadButton::START),
+        _ => None,
+    }
+}
+
+fn slot_key(key: Keycode) -> Option<u8> {
+    let keys = [
+        Keycode::Num0, Keycode::Num1, Keycode::Num2, Keycode::Num3, Keycode::Num4,
+        Keycode::Num5, Keycode::Num6, Keycode::Num7, Keycode::Num8, Keycode::Num9,
+    ];
+    keys.iter().position(|k| *k == key).map(|digit| digit as u8)
+}
+
+fn parse_args(args: Vec<String>) -> Result<Options, String> {
+    let mut rom = None;
+    let mut scale = DEFAULT_SCALE;
+    let mut palette = None;
+    let mut ntsc = false;
+    let mut ntsc_settings = NtscSettings::default();
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--scale" => {
+                let value = args.next().ok_or("--scale needs a value")?;
+                scale = value.parse().ok().filter(|scale| *scale > 0).ok_or("--scale takes a positive number")?;
+            }
+            "--palette" => palette = Some(PathBuf::from(args.next().ok_or("--palette needs a file")?)),
+            "--ntsc" => ntsc = true,
+            "--sharpness" => ntsc_settings.sharpness = parse_setting(&arg, args.next())?,
+            "--saturation" => ntsc_settings.saturation = parse_setting(&arg, args.next())?,
+            "--hue" => ntsc_settings.hue = parse_setting(&arg, args.next())?,
+            _ if rom.is_none() && !arg.starts_with("--") => rom = Some(PathBuf::from(arg)),
+            _ => return Err(format!("Unexpected argument '{}'", arg)),
+        }
+    }
+
+    let rom = rom.ok_or("No ROM given")?;
+    if !Path::new(&rom).is_file() {
+        return Err(format!("{} does not exist", rom.display()));
+    }
+    Ok(Options { rom, scale, palette, ntsc, ntsc_settings })
+}
+
+fn parse_setting(name: &str, value: Option<String>) -> Result<f32, String> {
+    value
+        .and_then(|value| value.parse::<f32>().ok())
+        .filter(|value| (-1.0..=1.0).contains(value))
+        .ok_or(format!("{} takes a number from -1 to 1", name))
+}
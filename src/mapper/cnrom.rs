@@ -0,0 +1,101 @@
+use crate::cartridge::{Mirroring, Rom};
+use crate::savestate::{StateReader, StateWriter, Stateful};
+use super::{chr_memory, load_memory, save_memory, read_bank, read_prg_ram, write_bank, write_prg_ram, Mapper};
+
+// Mapper 3: fixed PRG like NROM, any write selects the 8K CHR bank
+// https://www.nesdev.org/wiki/CNROM
+pub struct Cnrom {
+    prg_rom: Vec<u8>,
+    // CHR-ROM, or CHR-RAM when `chr_ram` is set
+    chr: Vec<u8>,
+    chr_ram: bool,
+    prg_ram: Vec<u8>,
+    mirroring: Mirroring,
+    chr_bank: usize,
+}
+
+impl Cnrom {
+    pub fn new(rom: Rom) -> Self {
+        let (chr, chr_ram) = chr_memory(rom.chr_rom, rom.chr_ram_size + rom.chr_nvram_size);
+        Cnrom {
+            prg_ram: vec![0; rom.prg_ram_size + rom.prg_nvram_size],
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_ram,
+            mirroring: rom.screen_mirroring,
+            chr_bank: 0,
+        }
+    }
+}
+
+impl Mapper for Cnrom {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7fff => read_prg_ram(&self.prg_ram, addr),
+            _ => read_bank(&self.prg_rom, 0, 0x8000, addr),
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7fff => write_prg_ram(&mut self.prg_ram, addr, data),
+            _ => self.chr_bank = data as usize,
+        }
+    }
+
+    fn ppu_read(&self, addr: u16) -> u8 {
+        read_bank(&self.chr, self.chr_bank, 0x2000, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_ram {
+            write_bank(&mut self.chr, self.chr_bank, 0x2000, addr, data);
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+}
+
+impl Stateful for Cnrom {
+    fn save(&self, w: &mut StateWriter) {
+        save_memory(w, &self.prg_ram, &self.chr, self.chr_ram);
+        w.write_usize(self.chr_bank);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+        load_memory(r, &mut self.prg_ram, &mut self.chr, self.chr_ram)?;
+        self.chr_bank = r.read_usize()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::test_rom;
+
+    #[test]
+    fn test_cnrom_switches_chr_bank() {
+        // 1K test banks, so the 8K bank n starts with 1K bank 8n
+        let mut cnrom = Cnrom::new(test_rom(3, 4, 32));
+        assert_eq!(cnrom.ppu_read(0x0000), 0);
+
+        cnrom.cpu_write(0x8000, 3);
+        assert_eq!(cnrom.ppu_read(0x0000), 24);
+        assert_eq!(cnrom.ppu_read(0x1fff), 31);
+
+        // Only two bank bits exist on a 32K CHR board
+        cnrom.cpu_write(0x8000, 5);
+        assert_eq!(cnrom.ppu_read(0x0000), 8);
+    }
+}
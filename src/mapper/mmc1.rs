@@ -0,0 +1,241 @@
+use crate::cartridge::{Mirroring, Rom};
+use crate::savestate::{StateReader, StateWriter, Stateful};
+use super::{chr_memory, load_memory, save_memory, read_bank, read_prg_ram, write_bank, write_prg_ram, Mapper};
+
+// Mapper 1: registers are loaded one bit at a time through a 5-bit serial port at $8000-$FFFF.
+// The fifth write copies the shift register into the register selected by address bits 13-14
+// https://www.nesdev.org/wiki/MMC1
+pub struct Mmc1 {
+    prg_rom: Vec<u8>,
+    // CHR-ROM, or CHR-RAM when `chr_ram` is set
+    chr: Vec<u8>,
+    chr_ram: bool,
+    prg_ram: Vec<u8>,
+
+    // Starts as 0b10000, the marker bit reaches bit 0 once four bits have been shifted in
+    shift: u8,
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8,
+}
+
+impl Mmc1 {
+    pub fn new(rom: Rom) -> Self {
+        let (chr, chr_ram) = chr_memory(rom.chr_rom, rom.chr_ram_size + rom.chr_nvram_size);
+        Mmc1 {
+            prg_ram: vec![0; rom.prg_ram_size + rom.prg_nvram_size],
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_ram,
+
+            shift: 0b10000,
+            // Power on with the last PRG bank fixed at $C000 so the reset vector is reachable
+            control: 0b01100,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+        }
+    }
+
+    // Bit 4 of the PRG bank register disables the work RAM
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_bank & 0b10000 == 0
+    }
+
+    // CHR bank and window size for a pattern table address
+    fn chr_bank(&self, addr: u16) -> (usize, usize) {
+        if self.control & 0b10000 == 0 {
+            // 8K mode ignores the low bit of the bank number
+            return ((self.chr_bank_0 >> 1) as usize, 0x2000);
+        }
+
+        match addr {
+            0..=0x0fff => (self.chr_bank_0 as usize, 0x1000),
+            _ => (self.chr_bank_1 as usize, 0x1000),
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x8000..=0x9fff => self.control = data,
+            0xa000..=0xbfff => self.chr_bank_0 = data,
+            0xc000..=0xdfff => self.chr_bank_1 = data,
+            _ => self.prg_bank = data,
+        }
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        if let 0x6000..=0x7fff = addr {
+            return if self.prg_ram_enabled() { read_prg_ram(&self.prg_ram, addr) } else { 0 };
+        }
+
+        let bank = (self.prg_bank & 0x0f) as usize;
+        let last = (self.prg_rom.len() / 0x4000).saturating_sub(1);
+
+        match ((self.control >> 2) & 0b11, addr) {
+            // 32K mode ignores the low bit of the bank number
+            (0 | 1, _) => read_bank(&self.prg_rom, bank >> 1, 0x8000, addr),
+            (2, 0x8000..=0xbfff) => read_bank(&self.prg_rom, 0, 0x4000, addr),
+            (2, _) => read_bank(&self.prg_rom, bank, 0x4000, addr),
+            (_, 0x8000..=0xbfff) => read_bank(&self.prg_rom, bank, 0x4000, addr),
+            (_, _) => read_bank(&self.prg_rom, last, 0x4000, addr),
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if let 0x6000..=0x7fff = addr {
+            if self.prg_ram_enabled() {
+                write_prg_ram(&mut self.prg_ram, addr, data);
+            }
+            return;
+        }
+
+        if data & 0x80 != 0 {
+            self.shift = 0b10000;
+            self.control |= 0b01100;
+            return;
+        }
+
+        let complete = self.shift & 1 != 0;
+        self.shift = (self.shift >> 1) | ((data & 1) << 4);
+
+        if complete {
+            self.write_register(addr, self.shift);
+            self.shift = 0b10000;
+        }
+    }
+
+    fn ppu_read(&self, addr: u16) -> u8 {
+        let (bank, size) = self.chr_bank(addr);
+        read_bank(&self.chr, bank, size, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_ram {
+            let (bank, size) = self.chr_bank(addr);
+            write_bank(&mut self.chr, bank, size, addr, data);
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0b11 {
+            0 => Mirroring::ONESCREEN_LO,
+            1 => Mirroring::ONESCREEN_HI,
+            2 => Mirroring::VERTICAL,
+            _ => Mirroring::HORIZONTAL,
+        }
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+}
+
+impl Stateful for Mmc1 {
+    fn save(&self, w: &mut StateWriter) {
+        save_memory(w, &self.prg_ram, &self.chr, self.chr_ram);
+        w.write_u8(self.shift);
+        w.write_u8(self.control);
+        w.write_u8(self.chr_bank_0);
+        w.write_u8(self.chr_bank_1);
+        w.write_u8(self.prg_bank);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+        load_memory(r, &mut self.prg_ram, &mut self.chr, self.chr_ram)?;
+        self.shift = r.read_u8()?;
+        self.control = r.read_u8()?;
+        self.chr_bank_0 = r.read_u8()?;
+        self.chr_bank_1 = r.read_u8()?;
+        self.prg_bank = r.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::test_rom;
+
+    fn serial_write(mmc1: &mut Mmc1, addr: u16, value: u8) {
+        for bit in 0..5 {
+            mmc1.cpu_write(addr, (value >> bit) & 1);
+        }
+    }
+
+    #[test]
+    fn test_mmc1_power_on_fixes_last_bank() {
+        let mmc1 = Mmc1::new(test_rom(1, 16, 16));
+        assert_eq!(mmc1.cpu_read(0x8000), 0);
+        assert_eq!(mmc1.cpu_read(0xc000), 14);
+        assert_eq!(mmc1.cpu_read(0xe000), 15);
+    }
+
+    #[test]
+    fn test_mmc1_serial_bank_switching() {
+        let mut mmc1 = Mmc1::new(test_rom(1, 16, 32));
+
+        serial_write(&mut mmc1, 0xe000, 3);
+        assert_eq!(mmc1.cpu_read(0x8000), 6);
+        assert_eq!(mmc1.cpu_read(0xc000), 14);
+
+        // Fix the first bank at $8000 and switch $C000 instead
+        serial_write(&mut mmc1, 0x8000, 0b01010);
+        assert_eq!(mmc1.cpu_read(0x8000), 0);
+        assert_eq!(mmc1.cpu_read(0xc000), 6);
+        assert_eq!(mmc1.mirroring(), Mirroring::VERTICAL);
+
+        // 32K mode drops the low bank bit
+        serial_write(&mut mmc1, 0x8000, 0b00011);
+        assert_eq!(mmc1.cpu_read(0x8000), 4);
+        assert_eq!(mmc1.cpu_read(0xe000), 7);
+        assert_eq!(mmc1.mirroring(), Mirroring::HORIZONTAL);
+    }
+
+    #[test]
+    fn test_mmc1_chr_modes() {
+        let mut mmc1 = Mmc1::new(test_rom(1, 4, 32));
+
+        serial_write(&mut mmc1, 0xa000, 3);
+        assert_eq!(mmc1.ppu_read(0x0000), 8);
+        assert_eq!(mmc1.ppu_read(0x1000), 12);
+
+        serial_write(&mut mmc1, 0x8000, 0b11100);
+        serial_write(&mut mmc1, 0xc000, 5);
+        assert_eq!(mmc1.ppu_read(0x0000), 12);
+        assert_eq!(mmc1.ppu_read(0x1000), 20);
+        assert_eq!(mmc1.mirroring(), Mirroring::ONESCREEN_LO);
+    }
+
+    #[test]
+    fn test_mmc1_reset_bit_clears_shift_register() {
+        let mut mmc1 = Mmc1::new(test_rom(1, 16, 16));
+        mmc1.cpu_write(0xe000, 1);
+        mmc1.cpu_write(0xe000, 1);
+        mmc1.cpu_write(0xe000, 0x80);
+
+        serial_write(&mut mmc1, 0xe000, 2);
+        assert_eq!(mmc1.cpu_read(0x8000), 4);
+    }
+
+    #[test]
+    fn test_mmc1_prg_ram_disable() {
+        let mut mmc1 = Mmc1::new(test_rom(1, 16, 16));
+        mmc1.cpu_write(0x6000, 0x42);
+        assert_eq!(mmc1.cpu_read(0x6000), 0x42);
+
+        serial_write(&mut mmc1, 0xe000, 0b10000);
+        mmc1.cpu_write(0x6000, 0x24);
+        assert_eq!(mmc1.cpu_read(0x6000), 0);
+
+        serial_write(&mut mmc1, 0xe000, 0);
+        assert_eq!(mmc1.cpu_read(0x6000), 0x42);
+    }
+}
@@ -0,0 +1,286 @@
+use crate::cartridge::{Mirroring, Rom};
+use crate::savestate::{StateReader, StateWriter, Stateful};
+use super::{chr_memory, load_memory, save_memory, read_bank, read_prg_ram, write_bank, write_prg_ram, Mapper};
+
+// Mapper 4: eight bank registers selected through $8000, 8K PRG and 1K/2K CHR windows,
+// and a scanline counter that raises an IRQ when it reaches zero
+// https://www.nesdev.org/wiki/MMC3
+pub struct Mmc3 {
+    prg_rom: Vec<u8>,
+    // CHR-ROM, or CHR-RAM when `chr_ram` is set
+    chr: Vec<u8>,
+    chr_ram: bool,
+    prg_ram: Vec<u8>,
+    mirroring: Mirroring,
+
+    bank_select: u8,
+    banks: [u8; 8],
+    // $A001: bit 7 enables the work RAM, bit 6 write protects it
+    prg_ram_protect: u8,
+
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+}
+
+impl Mmc3 {
+    pub fn new(rom: Rom) -> Self {
+        let (chr, chr_ram) = chr_memory(rom.chr_rom, rom.chr_ram_size + rom.chr_nvram_size);
+        Mmc3 {
+            prg_ram: vec![0; rom.prg_ram_size + rom.prg_nvram_size],
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_ram,
+            mirroring: rom.screen_mirroring,
+
+            bank_select: 0,
+            banks: [0; 8],
+            prg_ram_protect: 0b1000_0000,
+
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+        }
+    }
+}
+
+impl Mmc3 {
+    // 1K CHR bank for a pattern table address
+    fn chr_bank(&self, addr: u16) -> usize {
+        // Bit 7 swaps the 2K and 1K halves of the pattern tables
+        let mut window = (addr / 0x400) as usize;
+        if self.bank_select & 0x80 != 0 {
+            window ^= 0b100;
+        }
+
+        match window {
+            0 | 1 => (self.banks[0] & 0xfe) as usize + window,
+            2 | 3 => (self.banks[1] & 0xfe) as usize + window - 2,
+            _ => self.banks[window - 2] as usize,
+        }
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        if let 0x6000..=0x7fff = addr {
+            let enabled = self.prg_ram_protect & 0b1000_0000 != 0;
+            return if enabled { read_prg_ram(&self.prg_ram, addr) } else { 0 };
+        }
+
+        let last = (self.prg_rom.len() / 0x2000).saturating_sub(1);
+        let swap = self.bank_select & 0x40 != 0;
+
+        // R6 and the second to last bank trade places between $8000 and $C000 depending on bit 6
+        let bank = match (addr, swap) {
+            (0x8000..=0x9fff, false) | (0xc000..=0xdfff, true) => self.banks[6] as usize,
+            (0x8000..=0x9fff, true) | (0xc000..=0xdfff, false) => last.saturating_sub(1),
+            (0xa000..=0xbfff, _) => self.banks[7] as usize,
+            _ => last,
+        };
+        read_bank(&self.prg_rom, bank, 0x2000, addr)
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        let even = addr & 1 == 0;
+
+        match (addr, even) {
+            (0x6000..=0x7fff, _) => {
+                if self.prg_ram_protect & 0b1100_0000 == 0b1000_0000 {
+                    write_prg_ram(&mut self.prg_ram, addr, data);
+                }
+            }
+
+            (0x8000..=0x9fff, true) => self.bank_select = data,
+            (0x8000..=0x9fff, false) => self.banks[(self.bank_select & 0b111) as usize] = data,
+
+            (0xa000..=0xbfff, true) => {
+                // Four-screen boards have hardwired VRAM and ignore the mirroring register
+                if self.mirroring != Mirroring::FOURSCREEN {
+                    self.mirroring = if data & 1 == 0 { Mirroring::VERTICAL } else { Mirroring::HORIZONTAL };
+                }
+            }
+            (0xa000..=0xbfff, false) => self.prg_ram_protect = data,
+
+            (0xc000..=0xdfff, true) => self.irq_latch = data,
+            (0xc000..=0xdfff, false) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+
+            (_, true) => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            }
+            (_, false) => self.irq_enabled = true,
+        }
+    }
+
+    fn ppu_read(&self, addr: u16) -> u8 {
+        read_bank(&self.chr, self.chr_bank(addr), 0x400, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_ram {
+            let bank = self.chr_bank(addr);
+            write_bank(&mut self.chr, bank, 0x400, addr, data);
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn scanline(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+}
+
+impl Stateful for Mmc3 {
+    fn save(&self, w: &mut StateWriter) {
+        save_memory(w, &self.prg_ram, &self.chr, self.chr_ram);
+        w.write_bool(self.mirroring == Mirroring::HORIZONTAL);
+        w.write_u8(self.bank_select);
+        w.write_bytes(&self.banks);
+        w.write_u8(self.prg_ram_protect);
+
+        w.write_u8(self.irq_latch);
+        w.write_u8(self.irq_counter);
+        w.write_bool(self.irq_reload);
+        w.write_bool(self.irq_enabled);
+        w.write_bool(self.irq_pending);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+        load_memory(r, &mut self.prg_ram, &mut self.chr, self.chr_ram)?;
+        let horizontal = r.read_bool()?;
+        if self.mirroring != Mirroring::FOURSCREEN {
+            self.mirroring = if horizontal { Mirroring::HORIZONTAL } else { Mirroring::VERTICAL };
+        }
+        self.bank_select = r.read_u8()?;
+        r.read_bytes_into(&mut self.banks)?;
+        self.prg_ram_protect = r.read_u8()?;
+
+        self.irq_latch = r.read_u8()?;
+        self.irq_counter = r.read_u8()?;
+        self.irq_reload = r.read_bool()?;
+        self.irq_enabled = r.read_bool()?;
+        self.irq_pending = r.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::test_rom;
+
+    #[test]
+    fn test_mmc3_prg_banks() {
+        let mut mmc3 = Mmc3::new(test_rom(4, 16, 8));
+        mmc3.cpu_write(0x8000, 6);
+        mmc3.cpu_write(0x8001, 3);
+        mmc3.cpu_write(0x8000, 7);
+        mmc3.cpu_write(0x8001, 5);
+
+        assert_eq!(mmc3.cpu_read(0x8000), 3);
+        assert_eq!(mmc3.cpu_read(0xa000), 5);
+        assert_eq!(mmc3.cpu_read(0xc000), 14);
+        assert_eq!(mmc3.cpu_read(0xe000), 15);
+
+        mmc3.cpu_write(0x8000, 0x40);
+        assert_eq!(mmc3.cpu_read(0x8000), 14);
+        assert_eq!(mmc3.cpu_read(0xc000), 3);
+    }
+
+    #[test]
+    fn test_mmc3_chr_banks_and_inversion() {
+        let mut mmc3 = Mmc3::new(test_rom(4, 4, 16));
+        for (register, bank) in [(0, 3), (1, 6), (2, 8), (5, 11)] {
+            mmc3.cpu_write(0x8000, register);
+            mmc3.cpu_write(0x8001, bank);
+        }
+
+        // 2K banks ignore the low bit
+        assert_eq!(mmc3.ppu_read(0x0000), 2);
+        assert_eq!(mmc3.ppu_read(0x0400), 3);
+        assert_eq!(mmc3.ppu_read(0x0800), 6);
+        assert_eq!(mmc3.ppu_read(0x1000), 8);
+        assert_eq!(mmc3.ppu_read(0x1c00), 11);
+
+        mmc3.cpu_write(0x8000, 0x80);
+        assert_eq!(mmc3.ppu_read(0x0000), 8);
+        assert_eq!(mmc3.ppu_read(0x1000), 2);
+        assert_eq!(mmc3.ppu_read(0x1800), 6);
+    }
+
+    #[test]
+    fn test_mmc3_mirroring() {
+        let mut mmc3 = Mmc3::new(test_rom(4, 4, 8));
+        mmc3.cpu_write(0xa000, 1);
+        assert_eq!(mmc3.mirroring(), Mirroring::HORIZONTAL);
+        mmc3.cpu_write(0xa000, 0);
+        assert_eq!(mmc3.mirroring(), Mirroring::VERTICAL);
+    }
+
+    #[test]
+    fn test_mmc3_prg_ram_protect() {
+        let mut mmc3 = Mmc3::new(test_rom(4, 4, 8));
+        mmc3.cpu_write(0x6000, 0x42);
+        assert_eq!(mmc3.cpu_read(0x6000), 0x42);
+
+        mmc3.cpu_write(0xa001, 0b1100_0000);
+        mmc3.cpu_write(0x6000, 0x24);
+        assert_eq!(mmc3.cpu_read(0x6000), 0x42);
+
+        mmc3.cpu_write(0xa001, 0);
+        assert_eq!(mmc3.cpu_read(0x6000), 0);
+    }
+
+    #[test]
+    fn test_mmc3_scanline_irq() {
+        let mut mmc3 = Mmc3::new(test_rom(4, 4, 8));
+        mmc3.cpu_write(0xc000, 2);
+        mmc3.cpu_write(0xc001, 0);
+        mmc3.cpu_write(0xe001, 0);
+
+        mmc3.scanline();
+        mmc3.scanline();
+        assert!(!mmc3.irq_pending());
+        mmc3.scanline();
+        assert!(mmc3.irq_pending());
+
+        // Writing $E000 acknowledges and disables
+        mmc3.cpu_write(0xe000, 0);
+        assert!(!mmc3.irq_pending());
+        for _ in 0..6 {
+            mmc3.scanline();
+        }
+        assert!(!mmc3.irq_pending());
+    }
+}
@@ -0,0 +1,206 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use crate::cartridge::{Mirroring, Rom};
+use crate::savestate::{StateReader, StateWriter, Stateful};
+
+mod nrom;
+mod mmc1;
+mod uxrom;
+mod cnrom;
+mod mmc3;
+
+pub use nrom::Nrom;
+pub use mmc1::Mmc1;
+pub use uxrom::Uxrom;
+pub use cnrom::Cnrom;
+pub use mmc3::Mmc3;
+
+// Cartridge hardware sitting between the ROM chips and the CPU/PPU buses. Mappers decode the cartridge
+// address ranges, switch banks in response to register writes and can drive mirroring and IRQs.
+// https://www.nesdev.org/wiki/Mapper
+// Mapper state is part of save states, which covers bank registers, PRG-RAM and CHR-RAM
+pub trait Mapper: Stateful {
+    /// CPU read from PRG-RAM ($6000-$7FFF) or PRG-ROM ($8000-$FFFF)
+    fn cpu_read(&self, addr: u16) -> u8;
+
+    /// CPU write to PRG-RAM ($6000-$7FFF) or PRG-ROM ($8000-$FFFF), which is where mapper registers live
+    fn cpu_write(&mut self, addr: u16, data: u8);
+
+    /// PPU read from the pattern tables ($0000-$1FFF)
+    fn ppu_read(&self, addr: u16) -> u8;
+
+    /// What `cpu_read` would return, for debuggers. Boards whose reads have side effects must override it
+    fn cpu_peek(&self, addr: u16) -> u8 {
+        self.cpu_read(addr)
+    }
+
+    /// What `ppu_read` would return, for debuggers. Boards whose reads have side effects must override it
+    fn ppu_peek(&self, addr: u16) -> u8 {
+        self.ppu_read(addr)
+    }
+
+    /// PPU write to the pattern tables, only boards with CHR-RAM store it
+    fn ppu_write(&mut self, addr: u16, data: u8);
+
+    fn mirroring(&self) -> Mirroring;
+
+    /// Work RAM at $6000-$7FFF, including the battery-backed part that gets saved
+    fn prg_ram(&self) -> &[u8];
+
+    fn prg_ram_mut(&mut self) -> &mut [u8];
+
+    fn irq_pending(&self) -> bool {
+        false
+    }
+
+    /// Clocked by the PPU once per rendered scanline, at the point where A12 rises for sprite fetches
+    fn scanline(&mut self) {}
+}
+
+pub fn is_supported(mapper: u16) -> bool {
+    matches!(mapper, 0..=4)
+}
+
+/// Builds the mapper for the given cartridge. It is shared between the CPU bus and the PPU
+pub fn from_rom(rom: Rom) -> Rc<RefCell<dyn Mapper>> {
+    match rom.mapper {
+        0 => Rc::new(RefCell::new(Nrom::new(rom))),
+        1 => Rc::new(RefCell::new(Mmc1::new(rom))),
+        2 => Rc::new(RefCell::new(Uxrom::new(rom))),
+        3 => Rc::new(RefCell::new(Cnrom::new(rom))),
+        4 => Rc::new(RefCell::new(Mmc3::new(rom))),
+        mapper => panic!("mapper {} is not supported", mapper),
+    }
+}
+
+// Offset of `addr` inside a window of `size` bytes mapped to `bank`. Bank numbers past the end of the chip wrap
+// around, as the unused high bank bits are simply not connected on smaller boards
+fn bank_offset(len: usize, bank: usize, size: usize, addr: u16) -> usize {
+    let banks = (len / size).max(1);
+    ((bank % banks) * size + addr as usize % size) % len
+}
+
+fn read_bank(data: &[u8], bank: usize, size: usize, addr: u16) -> u8 {
+    if data.is_empty() {
+        return 0;
+    }
+    data[bank_offset(data.len(), bank, size, addr)]
+}
+
+fn write_bank(data: &mut [u8], bank: usize, size: usize, addr: u16, value: u8) {
+    if !data.is_empty() {
+        let offset = bank_offset(data.len(), bank, size, addr);
+        data[offset] = value;
+    }
+}
+
+// Pattern table memory: the CHR-ROM from the file, or writable CHR-RAM when the cartridge has none.
+// CHR-RAM defaults to 8K unless an NES 2.0 header asks for another size
+fn chr_memory(chr_rom: Vec<u8>, chr_ram_size: usize) -> (Vec<u8>, bool) {
+    if !chr_rom.is_empty() {
+        return (chr_rom, false);
+    }
+    let size = if chr_ram_size == 0 { 0x2000 } else { chr_ram_size };
+    (vec![0; size], true)
+}
+
+// PRG-RAM as seen at $6000-$7FFF, smaller chips are mirrored and boards without RAM read back 0
+fn read_prg_ram(prg_ram: &[u8], addr: u16) -> u8 {
+    if prg_ram.is_empty() {
+        return 0;
+    }
+    prg_ram[(addr as usize - 0x6000) % prg_ram.len()]
+}
+
+fn write_prg_ram(prg_ram: &mut [u8], addr: u16, data: u8) {
+    if !prg_ram.is_empty() {
+        let len = prg_ram.len();
+        prg_ram[(addr as usize - 0x6000) % len] = data;
+    }
+}
+
+// RAM shared by every board in save states. CHR-ROM never changes so it is left out
+fn save_memory(w: &mut StateWriter, prg_ram: &[u8], chr: &[u8], chr_ram: bool) {
+    w.write_bytes(prg_ram);
+    if chr_ram {
+        w.write_bytes(chr);
+    }
+}
+
+fn load_memory(r: &mut StateReader, prg_ram: &mut [u8], chr: &mut [u8], chr_ram: bool) -> Result<(), String> {
+    r.read_bytes_into(prg_ram)?;
+    if chr_ram {
+        r.read_bytes_into(chr)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    // Fills every bank of `size` bytes with its own index so reads show which bank is mapped
+    pub fn numbered_banks(count: usize, size: usize) -> Vec<u8> {
+        (0..count).flat_map(|bank| vec![bank as u8; size]).collect()
+    }
+
+    pub fn test_rom(mapper: u16, prg_banks: usize, chr_banks: usize) -> Rom {
+        Rom {
+            prg_rom: numbered_banks(prg_banks, 0x2000),
+            chr_rom: numbered_banks(chr_banks, 0x400),
+            mapper,
+            screen_mirroring: Mirroring::VERTICAL,
+            prg_ram_size: 0x2000,
+            ..Rom::default()
+        }
+    }
+
+    #[test]
+    fn test_read_bank_wraps() {
+        let data = numbered_banks(4, 0x400);
+        assert_eq!(read_bank(&data, 1, 0x400, 0x0000), 1);
+        assert_eq!(read_bank(&data, 5, 0x400, 0x13ff), 1);
+        assert_eq!(read_bank(&[], 3, 0x400, 0x0000), 0);
+    }
+
+    #[test]
+    fn test_prg_smaller_than_a_bank() {
+        // NES 2.0 allows PRG sizes that are not whole banks, the fixed banks wrap instead of underflowing
+        for number in [0, 1, 2, 3, 4] {
+            let mapper = from_rom(test_rom(number, 1, 8));
+            for addr in [0x8000, 0xa000, 0xc000, 0xfffc] {
+                assert_eq!(mapper.borrow().cpu_read(addr), 0);
+            }
+
+            let empty = from_rom(test_rom(number, 0, 8));
+            assert_eq!(empty.borrow().cpu_read(0xfffc), 0);
+        }
+    }
+
+    #[test]
+    fn test_chr_ram() {
+        let mut rom = test_rom(2, 8, 0);
+        rom.chr_ram_size = 0x2000;
+        let mapper = from_rom(rom);
+        mapper.borrow_mut().ppu_write(0x1234, 0x55);
+        assert_eq!(mapper.borrow().ppu_read(0x1234), 0x55);
+
+        // CHR-ROM ignores writes
+        let mapper = from_rom(test_rom(2, 8, 8));
+        mapper.borrow_mut().ppu_write(0x0400, 0x55);
+        assert_eq!(mapper.borrow().ppu_read(0x0400), 1);
+    }
+
+    #[test]
+    fn test_prg_ram() {
+        let mapper = from_rom(test_rom(0, 2, 8));
+        mapper.borrow_mut().cpu_write(0x6001, 0x55);
+        assert_eq!(mapper.borrow().cpu_read(0x6001), 0x55);
+        assert_eq!(mapper.borrow().prg_ram()[1], 0x55);
+
+        let mut ram = vec![0; 0x800];
+        write_prg_ram(&mut ram, 0x7801, 0x66);
+        assert_eq!(read_prg_ram(&ram, 0x6001), 0x66);
+        assert_eq!(read_prg_ram(&[], 0x6001), 0);
+    }
+}
@@ -0,0 +1,90 @@
+use crate::cartridge::{Mirroring, Rom};
+use crate::savestate::{StateReader, StateWriter, Stateful};
+use super::{chr_memory, load_memory, save_memory, read_bank, read_prg_ram, write_bank, write_prg_ram, Mapper};
+
+// Mapper 0: no bank switching. 16K PRG boards mirror the single bank into $C000-$FFFF
+// https://www.nesdev.org/wiki/NROM
+pub struct Nrom {
+    prg_rom: Vec<u8>,
+    // CHR-ROM, or CHR-RAM when `chr_ram` is set
+    chr: Vec<u8>,
+    chr_ram: bool,
+    prg_ram: Vec<u8>,
+    mirroring: Mirroring,
+}
+
+impl Nrom {
+    pub fn new(rom: Rom) -> Self {
+        let (chr, chr_ram) = chr_memory(rom.chr_rom, rom.chr_ram_size + rom.chr_nvram_size);
+        Nrom {
+            prg_ram: vec![0; rom.prg_ram_size + rom.prg_nvram_size],
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_ram,
+            mirroring: rom.screen_mirroring,
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7fff => read_prg_ram(&self.prg_ram, addr),
+            _ => read_bank(&self.prg_rom, 0, 0x8000, addr),
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        if let 0x6000..=0x7fff = addr {
+            write_prg_ram(&mut self.prg_ram, addr, data);
+        }
+    }
+
+    fn ppu_read(&self, addr: u16) -> u8 {
+        read_bank(&self.chr, 0, 0x2000, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_ram {
+            write_bank(&mut self.chr, 0, 0x2000, addr, data);
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+}
+
+impl Stateful for Nrom {
+    fn save(&self, w: &mut StateWriter) {
+        save_memory(w, &self.prg_ram, &self.chr, self.chr_ram);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+        load_memory(r, &mut self.prg_ram, &mut self.chr, self.chr_ram)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::test_rom;
+
+    #[test]
+    fn test_nrom_mirrors_16k_prg() {
+        let nrom = Nrom::new(test_rom(0, 2, 8));
+        assert_eq!(nrom.cpu_read(0x8000), 0);
+        assert_eq!(nrom.cpu_read(0xa000), 1);
+        assert_eq!(nrom.cpu_read(0xc000), 0);
+        assert_eq!(nrom.cpu_read(0xffff), 1);
+    }
+}
@@ -0,0 +1,103 @@
+use crate::cartridge::{Mirroring, Rom};
+use crate::savestate::{StateReader, StateWriter, Stateful};
+use super::{chr_memory, load_memory, save_memory, read_bank, read_prg_ram, write_bank, write_prg_ram, Mapper};
+
+// Mapper 2: any write selects the 16K bank at $8000, the last bank is fixed at $C000
+// https://www.nesdev.org/wiki/UxROM
+pub struct Uxrom {
+    prg_rom: Vec<u8>,
+    // CHR-ROM, or CHR-RAM when `chr_ram` is set
+    chr: Vec<u8>,
+    chr_ram: bool,
+    prg_ram: Vec<u8>,
+    mirroring: Mirroring,
+    prg_bank: usize,
+}
+
+impl Uxrom {
+    pub fn new(rom: Rom) -> Self {
+        let (chr, chr_ram) = chr_memory(rom.chr_rom, rom.chr_ram_size + rom.chr_nvram_size);
+        Uxrom {
+            prg_ram: vec![0; rom.prg_ram_size + rom.prg_nvram_size],
+            prg_rom: rom.prg_rom,
+            chr,
+            chr_ram,
+            mirroring: rom.screen_mirroring,
+            prg_bank: 0,
+        }
+    }
+}
+
+impl Mapper for Uxrom {
+    fn cpu_read(&self, addr: u16) -> u8 {
+        match addr {
+            0x6000..=0x7fff => read_prg_ram(&self.prg_ram, addr),
+            0x8000..=0xbfff => read_bank(&self.prg_rom, self.prg_bank, 0x4000, addr),
+            _ => {
+                let last = (self.prg_rom.len() / 0x4000).saturating_sub(1);
+                read_bank(&self.prg_rom, last, 0x4000, addr)
+            }
+        }
+    }
+
+    fn cpu_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x6000..=0x7fff => write_prg_ram(&mut self.prg_ram, addr, data),
+            _ => self.prg_bank = data as usize,
+        }
+    }
+
+    fn ppu_read(&self, addr: u16) -> u8 {
+        read_bank(&self.chr, 0, 0x2000, addr)
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) {
+        if self.chr_ram {
+            write_bank(&mut self.chr, 0, 0x2000, addr, data);
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+}
+
+impl Stateful for Uxrom {
+    fn save(&self, w: &mut StateWriter) {
+        save_memory(w, &self.prg_ram, &self.chr, self.chr_ram);
+        w.write_usize(self.prg_bank);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+        load_memory(r, &mut self.prg_ram, &mut self.chr, self.chr_ram)?;
+        self.prg_bank = r.read_usize()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mapper::test::test_rom;
+
+    #[test]
+    fn test_uxrom_switches_low_bank() {
+        // 8K test banks, so the 16K bank n starts with 8K bank 2n
+        let mut uxrom = Uxrom::new(test_rom(2, 8, 8));
+        assert_eq!(uxrom.cpu_read(0x8000), 0);
+        assert_eq!(uxrom.cpu_read(0xc000), 6);
+
+        uxrom.cpu_write(0x8000, 2);
+        assert_eq!(uxrom.cpu_read(0x8000), 4);
+        assert_eq!(uxrom.cpu_read(0xa000), 5);
+        assert_eq!(uxrom.cpu_read(0xe000), 7);
+    }
+}
@@ -0,0 +1,238 @@
+use std::f32::consts::PI;
+use crate::frame::{Frame, HEIGHT, WIDTH};
+
+// NTSC composite video filter. Rather than looking colours up in a palette, every pixel is turned back into the
+// signal the PPU puts on the video line and decoded the way a TV would, which gives the colour fringes on sharp
+// edges, the rainbow on dithered patterns and the dot crawl that games were drawn for.
+// https://www.nesdev.org/wiki/NTSC_video
+//
+// The PPU outputs 8 samples per pixel on a colour subcarrier that lasts 12 samples, so a pixel covers
+// two thirds of a colour cycle. The decoder low-passes the signal for luma and demodulates one subcarrier period
+// for chroma, sampled 602 times across the 2048 samples of a line like blargg's nes_ntsc.
+
+/// Width of the filtered image. It covers the same screen width as the 256 pixel frame
+pub const OUT_WIDTH: usize = 602;
+
+const SAMPLES_PER_PIXEL: usize = 8;
+const PHASES: usize = 12;
+const LINE_SAMPLES: usize = WIDTH * SAMPLES_PER_PIXEL;
+
+// Line voltages relative to sync for the four brightness levels, low and high halves of the square wave
+const SIGNAL_LOW: [f32; 4] = [0.350, 0.518, 0.962, 1.550];
+const SIGNAL_HIGH: [f32; 4] = [1.094, 1.506, 1.962, 1.962];
+const BLACK: f32 = 0.518;
+const WHITE: f32 = 1.962;
+const EMPHASIS_ATTENUATION: f32 = 0.746;
+
+// Phase offset of the decoder's reference, in samples, that lines the colours up with the standard palette
+const HUE_OFFSET: f32 = 3.9;
+// The PPU signal is generated for a gamma 1.8 display, TVs and monitors use 2.2
+const GAMMA: f32 = 2.2 / 1.8;
+
+/// Knobs of the decoder, each in -1.0..=1.0 with 0.0 being a plain TV
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct NtscSettings {
+    /// Negative blurs the image, positive sharpens it and makes the colour fringes stronger
+    pub sharpness: f32,
+    /// -1.0 is greyscale, 1.0 is twice the saturation
+    pub saturation: f32,
+    /// Rotates every colour, -1.0 and 1.0 being 180 degrees
+    pub hue: f32,
+}
+
+pub struct NtscFilter {
+    settings: NtscSettings,
+    // Signal for each 9-bit pixel at each subcarrier phase
+    signal: Vec<[f32; PHASES]>,
+    // Demodulation reference for each phase, hue included
+    cos: [f32; PHASES],
+    sin: [f32; PHASES],
+}
+
+impl NtscFilter {
+    pub fn new(settings: NtscSettings) -> Self {
+        let signal = (0..512u16)
+            .map(|pixel| {
+                let mut phases = [0.0; PHASES];
+                for (phase, level) in phases.iter_mut().enumerate() {
+                    *level = (signal_level(pixel, phase) - BLACK) / (WHITE - BLACK);
+                }
+                phases
+            })
+            .collect();
+
+        let mut filter = NtscFilter { settings, signal, cos: [0.0; PHASES], sin: [0.0; PHASES] };
+        filter.set_settings(settings);
+        filter
+    }
+
+    pub fn settings(&self) -> NtscSettings {
+        self.settings
+    }
+
+    pub fn set_settings(&mut self, settings: NtscSettings) {
+        self.settings = settings;
+        for phase in 0..PHASES {
+            let angle = PI * (phase as f32 + HUE_OFFSET) / 6.0 + PI * settings.hue;
+            self.cos[phase] = angle.cos();
+            self.sin[phase] = angle.sin();
+        }
+    }
+
+    /// Packed RGB24 of OUT_WIDTH by HEIGHT. `burst_phase` is the frame's colour burst phase, 0 to 2,
+    /// cycling it from one frame to the next makes the dot crawl
+    pub fn filter(&self, frame: &Frame, burst_phase: usize) -> Vec<u8> {
+        let mut rgb = Vec::with_capacity(OUT_WIDTH * HEIGHT * 3);
+
+        // Samples of the line with running sums of the signal and its products with the reference, so every
+        // output pixel's window averages in constant time
+        let mut luma = vec![0.0f32; LINE_SAMPLES + 1];
+        let mut in_phase = vec![0.0f32; LINE_SAMPLES + 1];
+        let mut quadrature = vec![0.0f32; LINE_SAMPLES + 1];
+
+        // A sharper picture means a shorter luma window, which lets more of the subcarrier through
+        let luma_window = ((PHASES as f32 * (1.0 - 0.5 * self.settings.sharpness)).round() as usize).clamp(4, 24);
+        let saturation = 1.0 + self.settings.saturation;
+
+        for y in 0..HEIGHT {
+            // A line is 341 dots of 8 samples, which leaves the subcarrier 4 samples further on each line
+            let start = (burst_phase * 4 + y * 4) % PHASES;
+
+            for x in 0..WIDTH {
+                let signal = &self.signal[frame.get_pixel(x, y) as usize];
+                for i in 0..SAMPLES_PER_PIXEL {
+                    let n = x * SAMPLES_PER_PIXEL + i;
+                    let phase = (start + n) % PHASES;
+                    let level = signal[phase];
+                    luma[n + 1] = luma[n] + level;
+                    in_phase[n + 1] = in_phase[n] + level * self.cos[phase];
+                    quadrature[n + 1] = quadrature[n] + level * self.sin[phase];
+                }
+            }
+
+            for out in 0..OUT_WIDTH {
+                let center = ((out as f32 + 0.5) * LINE_SAMPLES as f32 / OUT_WIDTH as f32) as usize;
+
+                let y_level = window_average(&luma, center, luma_window);
+                // Averaging over a whole period keeps only the subcarrier, doubled to get its amplitude back
+                let i_level = window_average(&in_phase, center, PHASES) * 2.0 * saturation;
+                let q_level = window_average(&quadrature, center, PHASES) * 2.0 * saturation;
+
+                let (r, g, b) = yiq_to_rgb(y_level, i_level, q_level);
+                rgb.extend_from_slice(&[r, g, b]);
+            }
+        }
+        rgb
+    }
+}
+
+impl Default for NtscFilter {
+    fn default() -> Self {
+        NtscFilter::new(NtscSettings::default())
+    }
+}
+
+// Voltage of `pixel` at subcarrier `phase`. Hue 0 is a flat grey at the high level, hues 13-15 at the low one,
+// hues 1-12 a square wave between the two, each 30 degrees further round. Emphasis attenuates the signal during
+// the half of the cycle belonging to its colour's complement.
+fn signal_level(pixel: u16, phase: usize) -> f32 {
+    let hue = (pixel & 0x0F) as usize;
+    let level = if hue > 13 { 1 } else { ((pixel >> 4) & 0b11) as usize };
+    let emphasis = pixel >> 6;
+
+    let in_color_phase = |hue: usize| (hue + phase) % PHASES < 6;
+
+    let low = if hue == 0 { SIGNAL_HIGH[level] } else { SIGNAL_LOW[level] };
+    let high = if hue > 12 { SIGNAL_LOW[level] } else { SIGNAL_HIGH[level] };
+    let mut signal = if in_color_phase(hue) { high } else { low };
+
+    if (emphasis & 0b001 != 0 && in_color_phase(0))
+        || (emphasis & 0b010 != 0 && in_color_phase(4))
+        || (emphasis & 0b100 != 0 && in_color_phase(8))
+    {
+        signal *= EMPHASIS_ATTENUATION;
+    }
+    signal
+}
+
+// Mean of the samples in a window of `len` centred on `center`, clipped to the line
+fn window_average(sums: &[f32], center: usize, len: usize) -> f32 {
+    let start = center.saturating_sub(len / 2);
+    let end = (start + len).min(sums.len() - 1);
+    (sums[end] - sums[start]) / len as f32
+}
+
+fn yiq_to_rgb(y: f32, i: f32, q: f32) -> (u8, u8, u8) {
+    let channel = |value: f32| (value.max(0.0).powf(GAMMA) * 255.0).round().min(255.0) as u8;
+    (
+        channel(y + 0.946882 * i + 0.623557 * q),
+        channel(y - 0.274788 * i - 0.635691 * q),
+        channel(y - 1.108545 * i + 1.709007 * q),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn solid_frame(color: u16) -> Frame {
+        let mut frame = Frame::new();
+        frame.pixels.iter_mut().for_each(|pixel| *pixel = color);
+        frame
+    }
+
+    // Colour in the middle of a frame filled with `color`
+    fn filtered_color(filter: &NtscFilter, color: u16) -> (u8, u8, u8) {
+        let rgb = filter.filter(&solid_frame(color), 0);
+        let at = (HEIGHT / 2 * OUT_WIDTH + OUT_WIDTH / 2) * 3;
+        (rgb[at], rgb[at + 1], rgb[at + 2])
+    }
+
+    #[test]
+    fn test_output_size() {
+        let rgb = NtscFilter::default().filter(&Frame::new(), 0);
+        assert_eq!(rgb.len(), OUT_WIDTH * HEIGHT * 3);
+    }
+
+    #[test]
+    fn test_greys_and_hues() {
+        let filter = NtscFilter::default();
+
+        assert_eq!(filtered_color(&filter, 0x0F), (0, 0, 0));
+        let (r, g, b) = filtered_color(&filter, 0x30);
+        assert!(r > 240 && g > 240 && b > 240);
+
+        let (r, g, b) = filtered_color(&filter, 0x16);
+        assert!(r > g && r > b, "$16 should be red, got {:?}", (r, g, b));
+        let (r, g, b) = filtered_color(&filter, 0x1A);
+        assert!(g > r && g > b, "$1A should be green, got {:?}", (r, g, b));
+        let (r, g, b) = filtered_color(&filter, 0x12);
+        assert!(b > r && b > g, "$12 should be blue, got {:?}", (r, g, b));
+    }
+
+    #[test]
+    fn test_settings() {
+        let mut filter = NtscFilter::default();
+        filter.set_settings(NtscSettings { saturation: -1.0, ..Default::default() });
+        let (r, g, b) = filtered_color(&filter, 0x16);
+        assert!(r.abs_diff(g) <= 1 && g.abs_diff(b) <= 1);
+
+        // Half a turn of hue takes red to the opposite side of the wheel
+        filter.set_settings(NtscSettings { hue: 1.0, ..Default::default() });
+        let (r, g, b) = filtered_color(&filter, 0x16);
+        assert!(r < g && r < b);
+    }
+
+    #[test]
+    fn test_dot_crawl() {
+        // A one pixel checkerboard is mostly subcarrier, its colours move with the burst phase
+        let mut frame = Frame::new();
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                frame.set_pixel(x, y, if (x + y) % 2 == 0 { 0x30 } else { 0x0F });
+            }
+        }
+        let filter = NtscFilter::default();
+        assert_ne!(filter.filter(&frame, 0), filter.filter(&frame, 1));
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{cpu::{CPU, StatusFlags, Mem}};
+use crate::{cpu::{CPU, StatusFlags, Mem, Interrupt}};
 
 pub struct Instruction {
     opcode:   Box<dyn Fn(&InstructionSet, &mut CPU)>,
@@ -157,7 +157,7 @@ impl InstructionSet {
                 Instruction{opcode: Box::new(InstructionSet::dey), addrmode: Box::new(InstructionSet::imp), cycle: 2},
                 Instruction{opcode: Box::new(InstructionSet::nop), addrmode: Box::new(InstructionSet::imm), cycle: 2},
                 Instruction{opcode: Box::new(InstructionSet::txa), addrmode: Box::new(InstructionSet::imp), cycle: 2},
-                Instruction{opcode: Box::new(InstructionSet::xaa), addrmode: Box::new(InstructionSet::imm), cycle: 3},
+                Instruction{opcode: Box::new(InstructionSet::xaa), addrmode: Box::new(InstructionSet::imm), cycle: 2},
                 Instruction{opcode: Box::new(InstructionSet::sty), addrmode: Box::new(InstructionSet::abs), cycle: 4},
                 Instruction{opcode: Box::new(InstructionSet::sta), addrmode: Box::new(InstructionSet::abs), cycle: 4},
                 Instruction{opcode: Box::new(InstructionSet::stx), addrmode: Box::new(InstructionSet::abs), cycle: 4},
@@ -165,7 +165,7 @@ impl InstructionSet {
                 Instruction{opcode: Box::new(InstructionSet::bcc), addrmode: Box::new(InstructionSet::rel), cycle: 2},
                 Instruction{opcode: Box::new(InstructionSet::sta), addrmode: Box::new(InstructionSet::izy), cycle: 6},
                 Instruction{opcode: Box::new(InstructionSet::nop), addrmode: Box::new(InstructionSet::imp), cycle: 2},
-                Instruction{opcode: Box::new(InstructionSet::ahx), addrmode: Box::new(InstructionSet::izy), cycle: 8},
+                Instruction{opcode: Box::new(InstructionSet::ahx), addrmode: Box::new(InstructionSet::izy), cycle: 6},
                 Instruction{opcode: Box::new(InstructionSet::sty), addrmode: Box::new(InstructionSet::zpx), cycle: 4},
                 Instruction{opcode: Box::new(InstructionSet::sta), addrmode: Box::new(InstructionSet::zpx), cycle: 4},
                 Instruction{opcode: Box::new(InstructionSet::stx), addrmode: Box::new(InstructionSet::zpy), cycle: 4},
@@ -173,11 +173,11 @@ impl InstructionSet {
                 Instruction{opcode: Box::new(InstructionSet::tya), addrmode: Box::new(InstructionSet::imp), cycle: 2},
                 Instruction{opcode: Box::new(InstructionSet::sta), addrmode: Box::new(InstructionSet::aby), cycle: 5},
                 Instruction{opcode: Box::new(InstructionSet::txs), addrmode: Box::new(InstructionSet::imp), cycle: 2},
-                Instruction{opcode: Box::new(InstructionSet::tas), addrmode: Box::new(InstructionSet::aby), cycle: 2},
-                Instruction{opcode: Box::new(InstructionSet::shy), addrmode: Box::new(InstructionSet::abx), cycle: 4},
+                Instruction{opcode: Box::new(InstructionSet::tas), addrmode: Box::new(InstructionSet::aby), cycle: 5},
+                Instruction{opcode: Box::new(InstructionSet::shy), addrmode: Box::new(InstructionSet::abx), cycle: 5},
                 Instruction{opcode: Box::new(InstructionSet::sta), addrmode: Box::new(InstructionSet::abx), cycle: 5},
-                Instruction{opcode: Box::new(InstructionSet::shx), addrmode: Box::new(InstructionSet::aby), cycle: 4},
-                Instruction{opcode: Box::new(InstructionSet::ahx), addrmode: Box::new(InstructionSet::aby), cycle: 4},
+                Instruction{opcode: Box::new(InstructionSet::shx), addrmode: Box::new(InstructionSet::aby), cycle: 5},
+                Instruction{opcode: Box::new(InstructionSet::ahx), addrmode: Box::new(InstructionSet::aby), cycle: 5},
                 Instruction{opcode: Box::new(InstructionSet::ldy), addrmode: Box::new(InstructionSet::imm), cycle: 2},
                 Instruction{opcode: Box::new(InstructionSet::lda), addrmode: Box::new(InstructionSet::izx), cycle: 6},
                 Instruction{opcode: Box::new(InstructionSet::ldx), addrmode: Box::new(InstructionSet::imm), cycle: 2},
@@ -189,7 +189,7 @@ impl InstructionSet {
                 Instruction{opcode: Box::new(InstructionSet::tay), addrmode: Box::new(InstructionSet::imp), cycle: 2},
                 Instruction{opcode: Box::new(InstructionSet::lda), addrmode: Box::new(InstructionSet::imm), cycle: 2},
                 Instruction{opcode: Box::new(InstructionSet::tax), addrmode: Box::new(InstructionSet::imp), cycle: 2},
-                Instruction{opcode: Box::new(InstructionSet::lxa), addrmode: Box::new(InstructionSet::imm), cycle: 3},
+                Instruction{opcode: Box::new(InstructionSet::lxa), addrmode: Box::new(InstructionSet::imm), cycle: 2},
                 Instruction{opcode: Box::new(InstructionSet::ldy), addrmode: Box::new(InstructionSet::abs), cycle: 4},
                 Instruction{opcode: Box::new(InstructionSet::lda), addrmode: Box::new(InstructionSet::abs), cycle: 4},
                 Instruction{opcode: Box::new(InstructionSet::ldx), addrmode: Box::new(InstructionSet::abs), cycle: 4},
@@ -205,7 +205,7 @@ impl InstructionSet {
                 Instruction{opcode: Box::new(InstructionSet::clv), addrmode: Box::new(InstructionSet::imp), cycle: 2},
                 Instruction{opcode: Box::new(InstructionSet::lda), addrmode: Box::new(InstructionSet::aby), cycle: 4},
                 Instruction{opcode: Box::new(InstructionSet::tsx), addrmode: Box::new(InstructionSet::imp), cycle: 2},
-                Instruction{opcode: Box::new(InstructionSet::las), addrmode: Box::new(InstructionSet::aby), cycle: 2},
+                Instruction{opcode: Box::new(InstructionSet::las), addrmode: Box::new(InstructionSet::aby), cycle: 4},
                 Instruction{opcode: Box::new(InstructionSet::ldy), addrmode: Box::new(InstructionSet::abx), cycle: 4},
                 Instruction{opcode: Box::new(InstructionSet::lda), addrmode: Box::new(InstructionSet::abx), cycle: 4},
                 Instruction{opcode: Box::new(InstructionSet::ldx), addrmode: Box::new(InstructionSet::aby), cycle: 4},
@@ -291,6 +291,32 @@ impl InstructionSet {
         return self.matrix[code as usize].cycle;
     }
 
+    // Read instructions using Absolute X/Y or Indirect Indexed Y take an extra cycle when the indexed address
+    // lands on a different page than the base address. Stores and read-modify-write instructions always take
+    // the longer path, so their penalty is already part of the cycle count in the matrix
+    pub fn has_page_penalty(&self, code: u8) -> bool {
+        matches!(code,
+            0x7d | 0x79 | 0x71 | 0x3d | 0x39 | 0x31 | 0xdd | 0xd9 | 0xd1 |
+            0x5d | 0x59 | 0x51 | 0xbd | 0xb9 | 0xb1 | 0xbe | 0xbc | 0x1d |
+            0x19 | 0x11 | 0xfd | 0xf9 | 0xf1 | 0xbf | 0xb3 | 0xbb | 0x1c |
+            0x3c | 0x5c | 0x7c | 0xdc | 0xfc
+        )
+    }
+
+    // Taken branches cost one extra cycle, and one more if the destination is on a different page
+    // than the instruction following the branch
+    fn branch(cpu: &mut CPU, address: u16) {
+        let jump_addr = cpu.mem_read(address) as i8;
+        let next_pc = cpu.reg_pc.wrapping_add(1);
+        let target = next_pc.wrapping_add(jump_addr as u16);
+
+        cpu.extra_cycles += 1;
+        if next_pc & 0xFF00 != target & 0xFF00 {
+            cpu.extra_cycles += 1;
+        }
+        cpu.reg_pc = target - 1;
+    }
+
     // Addressing Mode: Implicit / Accumulator
     // Implicit addressing mode requires no additional logic to obtain address
     // Thus, we can use it for opcodes that require the Accumulator addressing mode as well
@@ -349,6 +375,7 @@ impl InstructionSet {
         cpu.reg_pc += 1;
         let base = cpu.mem_read_u16(cpu.reg_pc);
         let address = base.wrapping_add(cpu.reg_x as u16);
+        cpu.page_crossed = base & 0xFF00 != address & 0xFF00;
         cpu.reg_pc += 1;
         address
     }
@@ -358,6 +385,7 @@ impl InstructionSet {
         cpu.reg_pc += 1;
         let base = cpu.mem_read_u16(cpu.reg_pc);
         let address = base.wrapping_add(cpu.reg_y as u16);
+        cpu.page_crossed = base & 0xFF00 != address & 0xFF00;
         cpu.reg_pc += 1;
         address
     }	
@@ -389,6 +417,7 @@ impl InstructionSet {
         let hi = cpu.mem_read((base as u8).wrapping_add(1) as u16);
         let deref_base = (hi as u16) << 8 | (lo as u16);
         let deref = deref_base.wrapping_add(cpu.reg_y as u16);
+        cpu.page_crossed = deref_base & 0xFF00 != deref & 0xFF00;
         deref
     }
 
@@ -538,8 +567,7 @@ impl InstructionSet {
         // Every branching instruction requires an offset of -1 byte to the program counter in order to not skip the 
         // next instruction
         if !cpu.reg_status.contains(StatusFlags::CARRY) {
-            let jump_addr = cpu.mem_read(address) as i8;
-            cpu.reg_pc = cpu.reg_pc.wrapping_add(1).wrapping_add(jump_addr as u16) - 1;
+            InstructionSet::branch(cpu, address);
         }
     }
 
@@ -548,8 +576,7 @@ impl InstructionSet {
         let address = self.get_address(cpu.mem_read(cpu.reg_pc))(cpu);
 
         if cpu.reg_status.contains(StatusFlags::CARRY) {
-            let jump_addr = cpu.mem_read(address) as i8;
-            cpu.reg_pc = cpu.reg_pc.wrapping_add(1).wrapping_add(jump_addr as u16) - 1;
+            InstructionSet::branch(cpu, address);
         }
     }
 
@@ -558,8 +585,7 @@ impl InstructionSet {
         let address = self.get_address(cpu.mem_read(cpu.reg_pc))(cpu);
 
         if cpu.reg_status.contains(StatusFlags::ZERO) {
-            let jump_addr = cpu.mem_read(address) as i8;
-            cpu.reg_pc = cpu.reg_pc.wrapping_add(1).wrapping_add(jump_addr as u16) - 1;
+            InstructionSet::branch(cpu, address);
         }
     }
 
@@ -578,8 +604,7 @@ impl InstructionSet {
         let address = self.get_address(cpu.mem_read(cpu.reg_pc))(cpu);
 
         if cpu.reg_status.contains(StatusFlags::NEGATIVE) {
-            let jump_addr = cpu.mem_read(address) as i8;
-            cpu.reg_pc = cpu.reg_pc.wrapping_add(1).wrapping_add(jump_addr as u16) - 1;
+            InstructionSet::branch(cpu, address);
         }
     }
 
@@ -588,8 +613,7 @@ impl InstructionSet {
         let address = self.get_address(cpu.mem_read(cpu.reg_pc))(cpu);
 
         if !cpu.reg_status.contains(StatusFlags::ZERO) {
-            let jump_addr = cpu.mem_read(address) as i8;
-            cpu.reg_pc = cpu.reg_pc.wrapping_add(1).wrapping_add(jump_addr as u16) - 1;
+            InstructionSet::branch(cpu, address);
         }
     }
 
@@ -598,14 +622,17 @@ impl InstructionSet {
         let address = self.get_address(cpu.mem_read(cpu.reg_pc))(cpu);
 
         if !cpu.reg_status.contains(StatusFlags::NEGATIVE) {
-            let jump_addr = cpu.mem_read(address) as i8;
-            cpu.reg_pc = cpu.reg_pc.wrapping_add(1).wrapping_add(jump_addr as u16) - 1;
+            InstructionSet::branch(cpu, address);
         }
     }
 
     // Instruction: Force Interrupt
-    fn brk(&self, _cpu: &mut CPU) {
-        return;
+    // BRK skips a padding byte, so the return address pushed is the opcode address + 2. Both ends of the address
+    // space are reachable here, BRK at $FFFE or an unprogrammed vector of $0000, so the adjustments wrap
+    fn brk(&self, cpu: &mut CPU) {
+        cpu.reg_pc = cpu.reg_pc.wrapping_add(2);
+        cpu.interrupt(Interrupt::BRK);
+        cpu.reg_pc = cpu.reg_pc.wrapping_sub(1);
     }
 
     // Instruction: Branch if Overflow Clear
@@ -613,8 +640,7 @@ impl InstructionSet {
         let address = self.get_address(cpu.mem_read(cpu.reg_pc))(cpu);
 
         if !cpu.reg_status.contains(StatusFlags::OVERFLOW) {
-            let jump_addr = cpu.mem_read(address) as i8;
-            cpu.reg_pc = cpu.reg_pc.wrapping_add(1).wrapping_add(jump_addr as u16) - 1;
+            InstructionSet::branch(cpu, address);
         }
     }
 
@@ -623,8 +649,7 @@ impl InstructionSet {
         let address = self.get_address(cpu.mem_read(cpu.reg_pc))(cpu);
 
         if cpu.reg_status.contains(StatusFlags::OVERFLOW) {
-            let jump_addr = cpu.mem_read(address) as i8;
-            cpu.reg_pc = cpu.reg_pc.wrapping_add(1).wrapping_add(jump_addr as u16) - 1;     
+            InstructionSet::branch(cpu, address);
         }
     }
 
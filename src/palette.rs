@@ -0,0 +1,125 @@
+use std::path::Path;
+
+// System palette from bugzmanov nes_ebook
+// Maps the 6-bit colour values stored in palette RAM to RGB
+pub static SYSTEM_PALETTE: [(u8, u8, u8); 64] = [
+    (0x80, 0x80, 0x80), (0x00, 0x3D, 0xA6), (0x00, 0x12, 0xB0), (0x44, 0x00, 0x96),
+    (0xA1, 0x00, 0x5E), (0xC7, 0x00, 0x28), (0xBA, 0x06, 0x00), (0x8C, 0x17, 0x00),
+    (0x5C, 0x2F, 0x00), (0x10, 0x45, 0x00), (0x05, 0x4A, 0x00), (0x00, 0x47, 0x2E),
+    (0x00, 0x41, 0x66), (0x00, 0x00, 0x00), (0x05, 0x05, 0x05), (0x05, 0x05, 0x05),
+    (0xC7, 0xC7, 0xC7), (0x00, 0x77, 0xFF), (0x21, 0x55, 0xFF), (0x82, 0x37, 0xFA),
+    (0xEB, 0x2F, 0xB5), (0xFF, 0x29, 0x50), (0xFF, 0x22, 0x00), (0xD6, 0x32, 0x00),
+    (0xC4, 0x62, 0x00), (0x35, 0x80, 0x00), (0x05, 0x8F, 0x00), (0x00, 0x8A, 0x55),
+    (0x00, 0x99, 0xCC), (0x21, 0x21, 0x21), (0x09, 0x09, 0x09), (0x09, 0x09, 0x09),
+    (0xFF, 0xFF, 0xFF), (0x0F, 0xD7, 0xFF), (0x69, 0xA2, 0xFF), (0xD4, 0x80, 0xFF),
+    (0xFF, 0x45, 0xF3), (0xFF, 0x61, 0x8B), (0xFF, 0x88, 0x33), (0xFF, 0x9C, 0x12),
+    (0xFA, 0xBC, 0x20), (0x9F, 0xE3, 0x0E), (0x2B, 0xF0, 0x35), (0x0C, 0xF0, 0xA4),
+    (0x05, 0xFB, 0xFF), (0x5E, 0x5E, 0x5E), (0x0D, 0x0D, 0x0D), (0x0D, 0x0D, 0x0D),
+    (0xFF, 0xFF, 0xFF), (0xA6, 0xFC, 0xFF), (0xB3, 0xEC, 0xFF), (0xDA, 0xAB, 0xEB),
+    (0xFF, 0xA8, 0xF9), (0xFF, 0xAB, 0xB3), (0xFF, 0xD2, 0xB0), (0xFF, 0xEF, 0xA6),
+    (0xFF, 0xF7, 0x9C), (0xD7, 0xE8, 0x95), (0xA6, 0xED, 0xAF), (0xA2, 0xF2, 0xDA),
+    (0x99, 0xFF, 0xFC), (0xDD, 0xDD, 0xDD), (0x11, 0x11, 0x11), (0x11, 0x11, 0x11),
+];
+
+// Colours per emphasis combination, and the three $2001 emphasis bits on top of them
+pub const COLORS: usize = 64;
+pub const EMPHASIS_COLORS: usize = COLORS * 8;
+
+// How much an emphasis bit darkens the two channels it does not emphasise. The PPU attenuates the whole
+// signal during the phases of the other colours, this is the usual RGB approximation of that.
+// https://www.nesdev.org/wiki/NTSC_video#Color_Tint_Bits
+const EMPHASIS_ATTENUATION: f32 = 0.746;
+
+lazy_static! {
+    pub static ref DEFAULT_PALETTE: Palette = Palette::from_colors(&SYSTEM_PALETTE);
+}
+
+/// RGB for every pixel the PPU can output: a 6-bit colour plus the red, green and blue emphasis bits
+/// as bits 6-8, the same layout as a 512 entry .pal file
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Palette {
+    colors: Vec<(u8, u8, u8)>,
+}
+
+impl Palette {
+    /// Builds the emphasis variants from the 64 base colours
+    pub fn from_colors(base: &[(u8, u8, u8); COLORS]) -> Self {
+        let colors = (0..EMPHASIS_COLORS)
+            .map(|index| emphasize(base[index % COLORS], (index / COLORS) as u8))
+            .collect();
+        Palette { colors }
+    }
+
+    /// Parses a .pal file: 64 or 512 RGB triplets. Emphasis is generated for 64 colour files
+    pub fn from_pal(data: &[u8]) -> Result<Self, String> {
+        let triplets: Vec<(u8, u8, u8)> = data.chunks_exact(3).map(|rgb| (rgb[0], rgb[1], rgb[2])).collect();
+        match data.len() {
+            len if len == COLORS * 3 => Ok(Palette::from_colors(&triplets.try_into().unwrap())),
+            len if len == EMPHASIS_COLORS * 3 => Ok(Palette { colors: triplets }),
+            len => Err(format!(
+                "Palette has {} bytes, expected {} or {}", len, COLORS * 3, EMPHASIS_COLORS * 3)),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let data = std::fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        Palette::from_pal(&data).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// RGB for a pixel from `Frame`, emphasis bits included
+    pub fn rgb(&self, pixel: u16) -> (u8, u8, u8) {
+        self.colors[pixel as usize % EMPHASIS_COLORS]
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        DEFAULT_PALETTE.clone()
+    }
+}
+
+// `emphasis` holds the red, green and blue bits in that order from the lowest. Channels without their bit set
+// are darkened. With all three bits set the attenuation windows cover the whole colour wheel, so every channel
+// is darkened
+fn emphasize((r, g, b): (u8, u8, u8), emphasis: u8) -> (u8, u8, u8) {
+    if emphasis == 0 {
+        return (r, g, b);
+    }
+    let attenuate = |value: u8, bit: u8| {
+        if emphasis & bit != 0 && emphasis != 0b111 {
+            value
+        } else {
+            (value as f32 * EMPHASIS_ATTENUATION).round() as u8
+        }
+    };
+    (attenuate(r, 0b001), attenuate(g, 0b010), attenuate(b, 0b100))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_default_palette_emphasis() {
+        let palette = Palette::default();
+        assert_eq!(palette.rgb(0x30), (0xFF, 0xFF, 0xFF));
+        // Red emphasis keeps red and darkens the rest
+        assert_eq!(palette.rgb(0x30 | 0b001 << 6), (0xFF, 0xBE, 0xBE));
+        // All three bits darken every channel
+        assert_eq!(palette.rgb(0x30 | 0b111 << 6), (0xBE, 0xBE, 0xBE));
+    }
+
+    #[test]
+    fn test_pal_files() {
+        let small: Vec<u8> = (0..COLORS * 3).map(|i| i as u8).collect();
+        let palette = Palette::from_pal(&small).unwrap();
+        assert_eq!(palette.rgb(1), (3, 4, 5));
+        assert_eq!(palette.rgb(1 | 0b100 << 6), emphasize((3, 4, 5), 0b100));
+
+        let full: Vec<u8> = (0..EMPHASIS_COLORS * 3).map(|i| (i / 3) as u8).collect();
+        let palette = Palette::from_pal(&full).unwrap();
+        assert_eq!(palette.rgb(0x141), (0x41, 0x41, 0x41));
+
+        assert!(Palette::from_pal(&[0; 100]).is_err());
+    }
+}
@@ -1,4 +1,9 @@
-use crate::cartridge::Mirroring;
+use std::cell::RefCell;
+use std::rc::Rc;
+use crate::cartridge::{Mirroring, Rom};
+use crate::frame::Frame;
+use crate::mapper::{self, Mapper};
+use crate::savestate::{StateReader, StateWriter, Stateful};
 
 bitflags! {
     pub struct CtrlRegister: u8 {
@@ -47,52 +52,94 @@ pub struct PPU {
     pub reg_status:     StatRegister,
     pub reg_oam_addr:   u8,
     pub reg_oam_data:   [u8; 256],
-    pub reg_scroll_x:   u8,
-    pub reg_scroll_y:   u8,
-    latch_scroll:       bool,
-    pub reg_addr:       u16,
-    latch_hi_byte:      bool,
+
+    // Internal scroll registers shared by PPUSCROLL and PPUADDR, named after loopy's document:
+    // https://www.nesdev.org/wiki/PPU_scrolling
+    //   v/t: yyy NN YYYYY XXXXX (fine Y, nametable select, coarse Y, coarse X)
+    pub vram_addr:      u16,
+    pub temp_addr:      u16,
+    pub fine_x:         u8,
+    write_latch:        bool,
 
     pub palette_tbl:    [u8; 32],
-    pub mirroring:      Mirroring,
-    pub vram:           [u8; 2048],
-    pub chr_rom:        Vec<u8>,
+    // Four-screen boards add another 2K of VRAM, kept here after the console's own 2K
+    pub vram:           [u8; 4096],
+    cartridge:          Rc<RefCell<dyn Mapper>>,
 
     pub nmi_interrupt:  Option<u8>,
     scanline:           u16,
-    cycles:             usize,
+    dot:                u16,
+    odd_frame:          bool,
 
     internal_data_buf:  u8,
+    pub frame:          Frame,
+
+    // Background pipeline: the tile being fetched and the shift registers feeding the pixel output
+    bg_next_tile:       u8,
+    bg_next_attr:       u8,
+    bg_next_lo:         u8,
+    bg_next_hi:         u8,
+    bg_shift_lo:        u16,
+    bg_shift_hi:        u16,
+    bg_shift_attr_lo:   u16,
+    bg_shift_attr_hi:   u16,
+
+    // Sprites found for the next scanline, stored as raw OAM entries plus their pattern shifters
+    sprite_count:       usize,
+    sprite_line:        [[u8; 4]; 8],
+    sprite_shift_lo:    [u8; 8],
+    sprite_shift_hi:    [u8; 8],
+    sprite_zero_line:   bool,
 }
 
 impl PPU {
     pub fn new_empty_rom()  -> Self {
-        PPU::new(vec![0; 2048], Mirroring::HORIZONTAL)
+        PPU::new(mapper::from_rom(Rom {
+            chr_rom: vec![0; 2048],
+            screen_mirroring: Mirroring::HORIZONTAL,
+            ..Rom::default()
+        }))
     }
 
-    pub fn new(chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+    pub fn new(cartridge: Rc<RefCell<dyn Mapper>>) -> Self {
         PPU {
             reg_ctrl:          CtrlRegister::from_bits_truncate(0b00000000),
             reg_mask:          MaskRegister::from_bits_truncate(0b00000000),
             reg_status:        StatRegister::from_bits_truncate(0b00000000),
             reg_oam_addr:      0,
             reg_oam_data:      [0; 256],
-            reg_scroll_x:      0,
-            reg_scroll_y:      0,
-            latch_scroll:      false,
-            reg_addr:          0,
-            latch_hi_byte:     true,
+
+            vram_addr:         0,
+            temp_addr:         0,
+            fine_x:            0,
+            write_latch:       false,
 
             palette_tbl:       [0; 32],
-            mirroring:         mirroring,
-            vram:              [0; 2048],
-            chr_rom:           chr_rom,
+            vram:              [0; 4096],
+            cartridge,
 
             nmi_interrupt:     None,
             scanline:          0,
-            cycles:            0,
+            dot:               0,
+            odd_frame:         false,
 
             internal_data_buf: 0,
+            frame:             Frame::new(),
+
+            bg_next_tile:      0,
+            bg_next_attr:      0,
+            bg_next_lo:        0,
+            bg_next_hi:        0,
+            bg_shift_lo:       0,
+            bg_shift_hi:       0,
+            bg_shift_attr_lo:  0,
+            bg_shift_attr_hi:  0,
+
+            sprite_count:      0,
+            sprite_line:       [[0xff; 4]; 8],
+            sprite_shift_lo:   [0; 8],
+            sprite_shift_hi:   [0; 8],
+            sprite_zero_line:  false,
         }
     }
 
@@ -100,62 +147,361 @@ impl PPU {
         let mirrored_vram = addr & 0b10111111111111; // mirror down 0x3000-0x3eff to 0x2000 - 0x2eff
         let vram_index = mirrored_vram - 0x2000; // to vram vector
         let name_table = vram_index / 0x400;
-        match (&self.mirroring, name_table) {
+        let mirroring = self.cartridge.borrow().mirroring();
+        match (mirroring, name_table) {
             (Mirroring::VERTICAL, 2) | (Mirroring::VERTICAL, 3) => vram_index - 0x800,
             (Mirroring::HORIZONTAL, 1) => vram_index - 0x400,
             (Mirroring::HORIZONTAL, 2) => vram_index - 0x400,
             (Mirroring::HORIZONTAL, 3) => vram_index - 0x800,
+            (Mirroring::ONESCREEN_LO, _) => vram_index & 0x3ff,
+            (Mirroring::ONESCREEN_HI, _) => 0x400 | (vram_index & 0x3ff),
             _ => vram_index,
         }
     }
 
+    /// Advances the PPU by the given number of dots. Returns true when a frame has been completed
     pub fn tick(&mut self, cycles: u8) -> bool {
-        self.cycles += cycles as usize;
-        if self.cycles >= 341 {
-            if self.is_sprite_0_hit(self.cycles) {
-                self.stat_sprt_zero_hit(true);
+        let mut frame_complete = false;
+        for _ in 0..cycles {
+            frame_complete |= self.step_dot();
+        }
+        frame_complete
+    }
+
+    pub fn poll_nmi_interrupt(&mut self) -> Option<u8> {
+        self.nmi_interrupt.take()
+    }
+
+    pub fn scanline(&self) -> u16 {
+        self.scanline
+    }
+
+    pub fn dot(&self) -> u16 {
+        self.dot
+    }
+
+    fn rendering_enabled(&self) -> bool {
+        self.mask_show_bg() || self.mask_show_sprt()
+    }
+
+    // One PPU clock. Scanlines 0-239 are visible, 240 is idle, 241-260 are vblank and 261 is the pre-render line
+    // which primes the pipeline for the next frame. Timing follows https://www.nesdev.org/wiki/PPU_rendering
+    fn step_dot(&mut self) -> bool {
+        let visible = self.scanline < 240;
+        let pre_render = self.scanline == 261;
+
+        if pre_render && self.dot == 1 {
+            self.stat_reset_vblank();
+            self.stat_sprt_zero_hit(false);
+            self.stat_sprt_overflow(false);
+        }
+
+        if self.rendering_enabled() && (visible || pre_render) {
+            self.render_dot(visible, pre_render);
+        }
+
+        if visible && (1..=256).contains(&self.dot) {
+            self.output_pixel();
+        }
+
+        if self.scanline == 241 && self.dot == 1 {
+            self.stat_vblank(true);
+            if self.ctrl_generate_vblank_nmi() {
+                self.nmi_interrupt = Some(1);
             }
+        }
+
+        self.dot += 1;
 
-            self.cycles = self.cycles - 341;
+        // Odd frames skip the last dot of the pre-render line while rendering is enabled
+        if pre_render && self.dot == 340 && self.odd_frame && self.rendering_enabled() {
+            self.dot += 1;
+        }
+
+        if self.dot > 340 {
+            self.dot = 0;
             self.scanline += 1;
 
-            if self.scanline == 241 {
-                self.stat_vblank(true);
-                self.stat_sprt_zero_hit(false);
-                if self.ctrl_generate_vblank_nmi() {
-                    self.nmi_interrupt = Some(1);
+            if self.scanline > 261 {
+                self.scanline = 0;
+                self.odd_frame = !self.odd_frame;
+                return true;
+            }
+        }
+        false
+    }
+
+    fn render_dot(&mut self, visible: bool, pre_render: bool) {
+        let dot = self.dot;
+
+        if (2..=257).contains(&dot) || (321..=337).contains(&dot) {
+            self.shift_registers();
+
+            match (dot - 1) % 8 {
+                0 => {
+                    self.load_bg_shifters();
+                    self.bg_next_tile = self.ppu_read(0x2000 | (self.vram_addr & 0x0fff));
                 }
+                2 => {
+                    let v = self.vram_addr;
+                    let attr = self.ppu_read(0x23c0 | (v & 0x0c00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07));
+                    // Pick the quadrant of the attribute byte using bit 1 of coarse X and coarse Y
+                    let shift = ((v >> 4) & 0b100) | (v & 0b10);
+                    self.bg_next_attr = (attr >> shift) & 0b11;
+                }
+                4 => {
+                    let addr = self.bg_pattern_addr();
+                    self.bg_next_lo = self.ppu_read(addr);
+                }
+                6 => {
+                    let addr = self.bg_pattern_addr() + 8;
+                    self.bg_next_hi = self.ppu_read(addr);
+                }
+                7 => self.increment_coarse_x(),
+                _ => {}
             }
+        }
 
-            if self.scanline >= 262 {
-                self.scanline = 0;
-                self.nmi_interrupt = None;
-                self.stat_sprt_zero_hit(false);
-                self.stat_reset_vblank();
-                return true;
+        if dot == 256 {
+            self.increment_y();
+        }
+
+        if dot == 257 {
+            self.load_bg_shifters();
+            // Copy the horizontal bits of t into v
+            self.vram_addr = (self.vram_addr & !0x041f) | (self.temp_addr & 0x041f);
+
+            if visible {
+                self.evaluate_sprites();
+            } else {
+                self.sprite_count = 0;
             }
         }
-        return false;
+
+        if pre_render && (280..=304).contains(&dot) {
+            // Copy the vertical bits of t into v
+            self.vram_addr = (self.vram_addr & !0x7be0) | (self.temp_addr & 0x7be0);
+        }
+
+        if dot == 260 {
+            self.cartridge.borrow_mut().scanline();
+        }
+
+        if dot == 340 {
+            self.fetch_sprite_patterns();
+        }
     }
 
-    pub fn poll_nmi_interrupt(&mut self) -> Option<u8> {
-        self.nmi_interrupt.take()
+    fn bg_pattern_addr(&self) -> u16 {
+        let fine_y = (self.vram_addr >> 12) & 0b111;
+        self.ctrl_bg_addr() + (self.bg_next_tile as u16) * 16 + fine_y
     }
-    
-    fn increment_vram_addr(&mut self) {
-        let inc = self.ctrl_vram_addr();
-        self.reg_addr = self.reg_addr.wrapping_add(inc as u16);
-        self.reg_addr = self.reg_addr & 0b11111111111111;
+
+    fn load_bg_shifters(&mut self) {
+        self.bg_shift_lo = (self.bg_shift_lo & 0xff00) | self.bg_next_lo as u16;
+        self.bg_shift_hi = (self.bg_shift_hi & 0xff00) | self.bg_next_hi as u16;
+
+        // Attribute bits are expanded to a full byte so they shift in step with the pattern bits
+        let attr_lo = if self.bg_next_attr & 0b01 != 0 { 0xff } else { 0x00 };
+        let attr_hi = if self.bg_next_attr & 0b10 != 0 { 0xff } else { 0x00 };
+        self.bg_shift_attr_lo = (self.bg_shift_attr_lo & 0xff00) | attr_lo;
+        self.bg_shift_attr_hi = (self.bg_shift_attr_hi & 0xff00) | attr_hi;
+    }
+
+    fn shift_registers(&mut self) {
+        if self.mask_show_bg() {
+            self.bg_shift_lo <<= 1;
+            self.bg_shift_hi <<= 1;
+            self.bg_shift_attr_lo <<= 1;
+            self.bg_shift_attr_hi <<= 1;
+        }
+
+        // Sprites wait for their X counter to run out before shifting out pixels
+        if self.mask_show_sprt() && self.dot <= 257 {
+            for i in 0..self.sprite_count {
+                if self.sprite_line[i][3] > 0 {
+                    self.sprite_line[i][3] -= 1;
+                } else {
+                    self.sprite_shift_lo[i] <<= 1;
+                    self.sprite_shift_hi[i] <<= 1;
+                }
+            }
+        }
+    }
+
+    fn increment_coarse_x(&mut self) {
+        if self.vram_addr & 0x001f == 31 {
+            // Wrap coarse X and switch horizontal nametable
+            self.vram_addr &= !0x001f;
+            self.vram_addr ^= 0x0400;
+        } else {
+            self.vram_addr += 1;
+        }
+    }
+
+    fn increment_y(&mut self) {
+        if self.vram_addr & 0x7000 != 0x7000 {
+            self.vram_addr += 0x1000;
+            return;
+        }
+
+        self.vram_addr &= !0x7000;
+        let mut coarse_y = (self.vram_addr & 0x03e0) >> 5;
+        if coarse_y == 29 {
+            // Row 29 is the last row of tiles, switch vertical nametable
+            coarse_y = 0;
+            self.vram_addr ^= 0x0800;
+        } else if coarse_y == 31 {
+            // Coarse Y set out of bounds wraps without switching nametables
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.vram_addr = (self.vram_addr & !0x03e0) | (coarse_y << 5);
+    }
+
+    // Finds the first 8 sprites in OAM that are visible on the next scanline
+    fn evaluate_sprites(&mut self) {
+        let height = self.ctrl_sprt_size() as u16;
+        self.sprite_count = 0;
+        self.sprite_zero_line = false;
+
+        for i in 0..64 {
+            let row = self.scanline.wrapping_sub(self.reg_oam_data[i * 4] as u16);
+            if row >= height {
+                continue;
+            }
+
+            if self.sprite_count == 8 {
+                self.stat_sprt_overflow(true);
+                break;
+            }
+
+            if i == 0 {
+                self.sprite_zero_line = true;
+            }
+            self.sprite_line[self.sprite_count].copy_from_slice(&self.reg_oam_data[i * 4..i * 4 + 4]);
+            self.sprite_count += 1;
+        }
+    }
+
+    fn fetch_sprite_patterns(&mut self) {
+        let height = self.ctrl_sprt_size() as u16;
+
+        for i in 0..self.sprite_count {
+            let [sprite_y, tile, attr, _] = self.sprite_line[i];
+            let flip_vertical = attr & 0b1000_0000 != 0;
+            let flip_horizontal = attr & 0b0100_0000 != 0;
+
+            let mut row = self.scanline.wrapping_sub(sprite_y as u16) & 0xf;
+            if flip_vertical {
+                row = height - 1 - row;
+            }
+
+            // 8x16 sprites take their pattern table from bit 0 of the tile index and use two consecutive tiles
+            let addr = if height == 16 {
+                let table = (tile as u16 & 1) * 0x1000;
+                let tile = (tile as u16 & 0xfe) + row / 8;
+                table + tile * 16 + row % 8
+            } else {
+                self.ctrl_sprt_addr() + tile as u16 * 16 + row
+            };
+
+            let mut lo = self.ppu_read(addr);
+            let mut hi = self.ppu_read(addr + 8);
+            if flip_horizontal {
+                lo = lo.reverse_bits();
+                hi = hi.reverse_bits();
+            }
+            self.sprite_shift_lo[i] = lo;
+            self.sprite_shift_hi[i] = hi;
+        }
+    }
+
+    // Combines the background and sprite pipelines into the pixel for the current dot
+    fn output_pixel(&mut self) {
+        let x = (self.dot - 1) as usize;
+        let y = self.scanline as usize;
+
+        let mut bg_value = 0;
+        let mut bg_palette = 0;
+        if self.mask_show_bg() && (x >= 8 || self.mask_leftmost_bg()) {
+            let bit = 0x8000 >> self.fine_x;
+            bg_value = (((self.bg_shift_hi & bit) != 0) as u8) << 1 | ((self.bg_shift_lo & bit) != 0) as u8;
+            bg_palette = (((self.bg_shift_attr_hi & bit) != 0) as u8) << 1 | ((self.bg_shift_attr_lo & bit) != 0) as u8;
+        }
+
+        // Sprites earlier in OAM win, even when they are behind the background
+        let mut sprite = None;
+        if self.mask_show_sprt() && (x >= 8 || self.mask_leftmost_sprt()) {
+            for i in 0..self.sprite_count {
+                if self.sprite_line[i][3] != 0 {
+                    continue;
+                }
+
+                let value = ((self.sprite_shift_hi[i] >> 7) << 1) | (self.sprite_shift_lo[i] >> 7);
+                if value != 0 {
+                    sprite = Some((i, value, self.sprite_line[i][2]));
+                    break;
+                }
+            }
+        }
+
+        let palette_addr = match sprite {
+            Some((i, value, attr)) => {
+                if i == 0 && self.sprite_zero_line && bg_value != 0 && x != 255 {
+                    self.stat_sprt_zero_hit(true);
+                }
+
+                if attr & 0b0010_0000 != 0 && bg_value != 0 {
+                    bg_palette * 4 + bg_value
+                } else {
+                    0x10 + (attr & 0b11) * 4 + value
+                }
+            }
+            None if bg_value != 0 => bg_palette * 4 + bg_value,
+            None => 0,
+        };
+
+        // Greyscale keeps only the brightness column of the colour, emphasis rides along in bits 6-8
+        let mut color = self.palette_tbl[palette_addr as usize];
+        if self.mask_greyscale() {
+            color &= 0x30;
+        }
+        self.frame.set_pixel(x, y, (self.mask_emphasis() as u16) << 6 | color as u16);
+    }
+
+    // Read on the PPU address bus as done by the rendering pipeline
+    fn ppu_read(&self, addr: u16) -> u8 {
+        match addr {
+            0..=0x1fff => self.cartridge.borrow().ppu_read(addr),
+            _ => self.vram[self.mirror_vram_addr(addr) as usize],
+        }
     }
 
-    fn is_sprite_0_hit(&self, cycle: usize) -> bool {
-        let y = self.reg_oam_data[0] as usize;
-        let x = self.reg_oam_data[3] as usize;
-        (y == self.scanline as usize) && x <= cycle && self.mask_show_sprt()
+    fn increment_vram_addr(&mut self) {
+        // Accessing PPUDATA while rendering glitches the scroll counters instead of adding the increment
+        if self.rendering_enabled() && (self.scanline < 240 || self.scanline == 261) {
+            self.increment_coarse_x();
+            self.increment_y();
+            return;
+        }
+
+        let inc = self.ctrl_vram_addr();
+        self.vram_addr = self.vram_addr.wrapping_add(inc as u16) & 0x3fff;
     }
 
     pub fn write_ctrl(&mut self, data: u8) {
+        let nmi_before = self.ctrl_generate_vblank_nmi();
         self.reg_ctrl.bits = data;
+
+        // The nametable select bits go to t
+        self.temp_addr = (self.temp_addr & !0x0c00) | ((data as u16 & 0b11) << 10);
+
+        // Enabling NMI while the vblank flag is still set triggers an NMI immediately
+        if !nmi_before && self.ctrl_generate_vblank_nmi() && self.stat_vblank_started() {
+            self.nmi_interrupt = Some(1);
+        }
     }
 
     pub fn write_mask(&mut self, data: u8) {
@@ -165,8 +511,7 @@ impl PPU {
     pub fn read_status(&mut self) -> u8 {
         let data = self.stat_snapshot();
         self.stat_reset_vblank();
-        self.addr_reset_latch();
-        self.scrll_reset_latch();
+        self.reset_write_latch();
         data
     }
 
@@ -184,46 +529,46 @@ impl PPU {
     }
 
     pub fn write_scroll(&mut self, data: u8) {
-        if !self.latch_scroll {
-            self.reg_scroll_x = data;
+        if !self.write_latch {
+            // First write: coarse X into t, fine X into x
+            self.temp_addr = (self.temp_addr & !0x001f) | (data as u16 >> 3);
+            self.fine_x = data & 0b111;
         } else {
-            self.reg_scroll_y = data;
+            // Second write: coarse Y and fine Y into t
+            self.temp_addr = (self.temp_addr & !0x73e0) | ((data as u16 & 0b111) << 12) | ((data as u16 >> 3) << 5);
         }
-        self.latch_scroll = !self.latch_scroll;
+        self.write_latch = !self.write_latch;
     }
 
     pub fn write_ppu_addr(&mut self, data: u8) {
-        if self.latch_hi_byte {
-            let hi = (data as u16) << 8;
-            let lo = self.reg_addr & 0xff;
-            self.reg_addr = hi | lo;
+        if !self.write_latch {
+            // First write: high 6 bits of t, bit 14 is cleared
+            self.temp_addr = (self.temp_addr & 0x00ff) | ((data as u16 & 0x3f) << 8);
         } else {
-            let hi = self.reg_addr & 0xff00;
-            let lo = data as u16;
-            self.reg_addr = hi | lo;
+            // Second write: low byte of t, then t is copied into v
+            self.temp_addr = (self.temp_addr & 0xff00) | data as u16;
+            self.vram_addr = self.temp_addr;
         }
-        self.reg_addr = self.reg_addr & 0b11111111111111;
-        self.latch_hi_byte = !self.latch_hi_byte;
+        self.write_latch = !self.write_latch;
     } 
 
     pub fn write_data(&mut self, data: u8) {
-        let addr = self.reg_addr;
+        let addr = self.vram_addr & 0x3fff;
         match addr {
-            0..=0x1fff => println!("attempt to write to chr rom space {}", addr), 
+            0..=0x1fff => self.cartridge.borrow_mut().ppu_write(addr, data),
 
-            0x2000..=0x2fff => {
+            0x2000..=0x3eff => {
                 self.vram[self.mirror_vram_addr(addr) as usize] = data;
             }
 
-            0x3000..=0x3eff => unimplemented!("addr {} shouldn't be used in reality", addr),
-
+            // Palette entries are 6 bits wide, the top two never reach the emphasis bits of the pixel
             0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
                 let add_mirror = addr - 0x10;
-                self.palette_tbl[(add_mirror - 0x3f00) as usize] = data;
+                self.palette_tbl[(add_mirror - 0x3f00) as usize] = data & 0x3f;
             }
 
             0x3f00..=0x3fff => {
-                self.palette_tbl[(addr - 0x3f00) as usize] = data;
+                self.palette_tbl[((addr - 0x3f00) % 32) as usize] = data & 0x3f;
             }
 
             _ => panic!("unexpected access to mirrored space {}", addr),
@@ -232,32 +577,60 @@ impl PPU {
     }
 
     pub fn read_data(&mut self) -> u8 {
-        let addr = self.reg_addr;
+        let addr = self.vram_addr & 0x3fff;
         self.increment_vram_addr();
 
         match addr {
             0..=0x1fff => {
                 let result = self.internal_data_buf;
-                self.internal_data_buf = self.chr_rom[addr as usize];
+                self.internal_data_buf = self.cartridge.borrow().ppu_read(addr);
                 result
             }
-            0x2000..=0x2fff => {
+            0x2000..=0x3eff => {
                 let result = self.internal_data_buf;
                 self.internal_data_buf = self.vram[self.mirror_vram_addr(addr) as usize];
                 result
             }
-            0x3000..=0x3eff => unimplemented!("addr {} shouldn't be used in reallity", addr),
 
             0x3f10 | 0x3f14 | 0x3f18 | 0x3f1c => {
                 let add_mirror = addr - 0x10;
                 self.palette_tbl[(add_mirror - 0x3f00) as usize]
             }
 
-            0x3f00..=0x3fff => self.palette_tbl[(addr - 0x3f00) as usize],
+            0x3f00..=0x3fff => self.palette_tbl[((addr - 0x3f00) % 32) as usize],
             _ => panic!("unexpected access to mirrored space {}", addr),
         }
     }
 
+    /// What a read of $2002 would return, without clearing vblank or the write toggle
+    pub fn peek_status(&self) -> u8 {
+        self.stat_snapshot()
+    }
+
+    /// What a read of $2007 would return, without advancing the VRAM address or refilling the read buffer
+    pub fn peek_data(&self) -> u8 {
+        match self.vram_addr & 0x3fff {
+            0..=0x3eff => self.internal_data_buf,
+            addr => self.peek_vram(addr),
+        }
+    }
+
+    /// Byte at `addr` on the PPU bus: pattern tables, nametables or palette
+    pub fn peek_vram(&self, addr: u16) -> u8 {
+        match addr & 0x3fff {
+            addr @ 0..=0x1fff => self.cartridge.borrow().ppu_peek(addr),
+            addr @ 0x2000..=0x3eff => self.vram[self.mirror_vram_addr(addr) as usize],
+            addr => {
+                // $3F10/$3F14/$3F18/$3F1C mirror the backdrop entries below them
+                let mut index = (addr - 0x3f00) % 32;
+                if index >= 16 && index % 4 == 0 {
+                    index -= 16;
+                }
+                self.palette_tbl[index as usize]
+            }
+        }
+    }
+
     pub fn write_oam_dma(&mut self, data: &[u8; 256]) {
         for x in data.iter() {
             self.reg_oam_data[self.reg_oam_addr as usize] = *x;
@@ -353,6 +726,11 @@ impl PPU {
         self.reg_mask.contains(MaskRegister::SHOW_SPRITES)
     }
 
+    /// Emphasis bits as red, green, blue from the lowest bit, the layout used for bits 6-8 of `Frame` pixels
+    pub fn mask_emphasis(&self) -> u8 {
+        self.reg_mask.bits() >> 5
+    }
+
     /// Emphasize red || blue || green
     pub fn mask_emphasize_color(&self) -> Vec<Color> {
         let mut result = Vec::<Color>::new();
@@ -402,12 +780,109 @@ impl PPU {
         self.reg_status.bits
     }
 
-    pub fn scrll_reset_latch(&mut self) {
-        self.latch_scroll = false;
+    /// Resets the first/second write toggle shared by PPUSCROLL and PPUADDR
+    pub fn reset_write_latch(&mut self) {
+        self.write_latch = false;
     }
+}
 
-    pub fn addr_reset_latch(&mut self) {
-        self.latch_hi_byte = true;
+impl Stateful for PPU {
+    fn save(&self, w: &mut StateWriter) {
+        w.write_u8(self.reg_ctrl.bits());
+        w.write_u8(self.reg_mask.bits());
+        w.write_u8(self.reg_status.bits());
+        w.write_u8(self.reg_oam_addr);
+        w.write_bytes(&self.reg_oam_data);
+
+        w.write_u16(self.vram_addr);
+        w.write_u16(self.temp_addr);
+        w.write_u8(self.fine_x);
+        w.write_bool(self.write_latch);
+
+        w.write_bytes(&self.palette_tbl);
+        w.write_bytes(&self.vram);
+
+        w.write_bool(self.nmi_interrupt.is_some());
+        w.write_u8(self.nmi_interrupt.unwrap_or(0));
+        w.write_u16(self.scanline);
+        w.write_u16(self.dot);
+        w.write_bool(self.odd_frame);
+
+        w.write_u8(self.internal_data_buf);
+        for &pixel in self.frame.pixels.iter() {
+            w.write_u16(pixel);
+        }
+
+        w.write_u8(self.bg_next_tile);
+        w.write_u8(self.bg_next_attr);
+        w.write_u8(self.bg_next_lo);
+        w.write_u8(self.bg_next_hi);
+        w.write_u16(self.bg_shift_lo);
+        w.write_u16(self.bg_shift_hi);
+        w.write_u16(self.bg_shift_attr_lo);
+        w.write_u16(self.bg_shift_attr_hi);
+
+        w.write_usize(self.sprite_count);
+        w.write_bytes(&self.sprite_line.concat());
+        w.write_bytes(&self.sprite_shift_lo);
+        w.write_bytes(&self.sprite_shift_hi);
+        w.write_bool(self.sprite_zero_line);
+    }
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), String> {
+        self.reg_ctrl = CtrlRegister::from_bits_truncate(r.read_u8()?);
+        self.reg_mask = MaskRegister::from_bits_truncate(r.read_u8()?);
+        self.reg_status = StatRegister::from_bits_truncate(r.read_u8()?);
+        self.reg_oam_addr = r.read_u8()?;
+        r.read_bytes_into(&mut self.reg_oam_data)?;
+
+        self.vram_addr = r.read_u16()?;
+        self.temp_addr = r.read_u16()?;
+        self.fine_x = r.read_u8()?;
+        self.write_latch = r.read_bool()?;
+
+        r.read_bytes_into(&mut self.palette_tbl)?;
+        self.palette_tbl.iter_mut().for_each(|entry| *entry &= 0x3f);
+        r.read_bytes_into(&mut self.vram)?;
+
+        let nmi_pending = r.read_bool()?;
+        let nmi = r.read_u8()?;
+        self.nmi_interrupt = if nmi_pending { Some(nmi) } else { None };
+        // The counters index the frame and the sprite buffers, so a corrupt state must not get them out of range
+        self.scanline = r.read_u16()?;
+        self.dot = r.read_u16()?;
+        if self.scanline > 261 || self.dot > 340 {
+            return Err(format!("Save state has PPU position {}:{} outside the frame", self.scanline, self.dot));
+        }
+        self.odd_frame = r.read_bool()?;
+
+        self.internal_data_buf = r.read_u8()?;
+        for pixel in self.frame.pixels.iter_mut() {
+            *pixel = r.read_u16()?;
+        }
+
+        self.bg_next_tile = r.read_u8()?;
+        self.bg_next_attr = r.read_u8()?;
+        self.bg_next_lo = r.read_u8()?;
+        self.bg_next_hi = r.read_u8()?;
+        self.bg_shift_lo = r.read_u16()?;
+        self.bg_shift_hi = r.read_u16()?;
+        self.bg_shift_attr_lo = r.read_u16()?;
+        self.bg_shift_attr_hi = r.read_u16()?;
+
+        self.sprite_count = r.read_usize()?;
+        if self.sprite_count > self.sprite_line.len() {
+            return Err(format!("Save state has {} sprites on a line, the PPU holds 8", self.sprite_count));
+        }
+        let mut sprite_line = [0; 32];
+        r.read_bytes_into(&mut sprite_line)?;
+        for (sprite, bytes) in self.sprite_line.iter_mut().zip(sprite_line.chunks(4)) {
+            sprite.copy_from_slice(bytes);
+        }
+        r.read_bytes_into(&mut self.sprite_shift_lo)?;
+        r.read_bytes_into(&mut self.sprite_shift_hi)?;
+        self.sprite_zero_line = r.read_bool()?;
+        Ok(())
     }
 }
 
@@ -415,6 +890,14 @@ impl PPU {
 pub mod test {
     use super::*;
 
+    fn test_ppu(chr_rom: Vec<u8>, mirroring: Mirroring) -> PPU {
+        PPU::new(mapper::from_rom(Rom {
+            chr_rom,
+            screen_mirroring: mirroring,
+            ..Rom::default()
+        }))
+    }
+
     #[test]
     fn test_ppu_vram_writes() {
         let mut ppu = PPU::new_empty_rom();
@@ -435,7 +918,7 @@ pub mod test {
         ppu.write_ppu_addr(0x05);
 
         ppu.read_data(); //load_into_buffer
-        assert_eq!(ppu.reg_addr, 0x2306);
+        assert_eq!(ppu.vram_addr, 0x2306);
         assert_eq!(ppu.read_data(), 0x66);
     }
 
@@ -505,7 +988,7 @@ pub mod test {
     //   [0x2800 a ] [0x2C00 b ]
     #[test]
     fn test_vram_vertical_mirror() {
-        let mut ppu = PPU::new(vec![0; 2048], Mirroring::VERTICAL);
+        let mut ppu = test_ppu(vec![0; 2048], Mirroring::VERTICAL);
 
         ppu.write_ppu_addr(0x20);
         ppu.write_ppu_addr(0x05);
@@ -551,6 +1034,35 @@ pub mod test {
         assert_eq!(ppu.read_data(), 0x66);
     }
 
+    #[test]
+    fn test_chr_ram_writes() {
+        let mut ppu = test_ppu(vec![], Mirroring::HORIZONTAL);
+        ppu.write_ppu_addr(0x01);
+        ppu.write_ppu_addr(0x10);
+        ppu.write_data(0x66);
+
+        ppu.write_ppu_addr(0x01);
+        ppu.write_ppu_addr(0x10);
+        ppu.read_data(); //load into_buffer
+        assert_eq!(ppu.read_data(), 0x66);
+    }
+
+    #[test]
+    fn test_vram_four_screen() {
+        let mut ppu = test_ppu(vec![0; 2048], Mirroring::FOURSCREEN);
+
+        for (i, nametable) in [0x20, 0x24, 0x28, 0x2c].iter().enumerate() {
+            ppu.write_ppu_addr(*nametable);
+            ppu.write_ppu_addr(0x05);
+            ppu.write_data(i as u8 + 1);
+        }
+
+        assert_eq!(ppu.vram[0x005], 1);
+        assert_eq!(ppu.vram[0x405], 2);
+        assert_eq!(ppu.vram[0x805], 3);
+        assert_eq!(ppu.vram[0xc05], 4);
+    }
+
     #[test]
     fn test_ppu_vram_mirroring() {
         let mut ppu = PPU::new_empty_rom();
@@ -609,4 +1121,223 @@ pub mod test {
         ppu.write_oam_addr(0x11);
         ppu.write_oam_addr(0x66);
     }
+
+    // CHR with tile 1 fully set in the low plane (value 1) and tile 2 with only its leftmost column set
+    fn render_test_ppu() -> PPU {
+        let mut chr_rom = vec![0; 0x2000];
+        for row in 0..8 {
+            chr_rom[16 + row] = 0xff;
+            chr_rom[32 + row] = 0x80;
+            chr_rom[0x1000 + 48 + 8 + row] = 0xff;
+        }
+
+        let mut ppu = test_ppu(chr_rom, Mirroring::HORIZONTAL);
+        ppu.palette_tbl[0] = 0x0f;
+        ppu.palette_tbl[1] = 0x21;
+        ppu.palette_tbl[9] = 0x16;
+        ppu.palette_tbl[0x12] = 0x30;
+        ppu.palette_tbl[0x15] = 0x27;
+        ppu
+    }
+
+    // Runs through a full frame so the pre-render line loads the scroll registers, then renders the next frame
+    // up to vblank, before the pre-render line clears the status flags
+    fn render_frame(ppu: &mut PPU) {
+        while !ppu.tick(3) {}
+        while !ppu.stat_vblank_started() {
+            ppu.tick(3);
+        }
+    }
+
+    #[test]
+    fn test_render_background() {
+        let mut ppu = render_test_ppu();
+        ppu.vram[0] = 1;
+        ppu.vram[0x22] = 1;
+        ppu.vram[0x3c0] = 0b0000_1000;
+        ppu.write_mask(0b0000_1010);
+
+        render_frame(&mut ppu);
+
+        assert_eq!(ppu.frame.get_pixel(0, 0), 0x21);
+        assert_eq!(ppu.frame.get_pixel(7, 7), 0x21);
+        assert_eq!(ppu.frame.get_pixel(8, 0), 0x0f);
+        // Third tile of the second row sits in the top right quadrant of the attribute byte
+        assert_eq!(ppu.frame.get_pixel(8, 8), 0x0f);
+        assert_eq!(ppu.frame.get_pixel(16, 8), 0x16);
+    }
+
+    #[test]
+    fn test_palette_writes_keep_out_of_emphasis() {
+        let mut ppu = render_test_ppu();
+        ppu.write_ppu_addr(0x3f);
+        ppu.write_ppu_addr(0x01);
+        ppu.write_data(0xff);
+        ppu.write_ppu_addr(0x00);
+        ppu.write_ppu_addr(0x00);
+        ppu.vram[0] = 1;
+        ppu.write_mask(0b0000_1010);
+
+        render_frame(&mut ppu);
+
+        assert_eq!(ppu.frame.get_pixel(0, 0), 0x3f);
+    }
+
+    #[test]
+    fn test_render_background_scroll() {
+        let mut ppu = render_test_ppu();
+        ppu.vram[1] = 1;
+        ppu.write_mask(0b0000_1010);
+        ppu.write_scroll(4);
+        ppu.write_scroll(0);
+
+        render_frame(&mut ppu);
+
+        assert_eq!(ppu.frame.get_pixel(3, 0), 0x0f);
+        assert_eq!(ppu.frame.get_pixel(4, 0), 0x21);
+        assert_eq!(ppu.frame.get_pixel(11, 0), 0x21);
+        assert_eq!(ppu.frame.get_pixel(12, 0), 0x0f);
+    }
+
+    #[test]
+    fn test_render_background_hides_leftmost_column() {
+        let mut ppu = render_test_ppu();
+        ppu.vram[0] = 1;
+        ppu.write_mask(0b0000_1000);
+
+        render_frame(&mut ppu);
+
+        assert_eq!(ppu.frame.get_pixel(0, 0), 0x0f);
+    }
+
+    #[test]
+    fn test_render_sprite_flipping() {
+        let mut ppu = render_test_ppu();
+        ppu.reg_oam_data[0..4].copy_from_slice(&[9, 2, 0b0000_0001, 16]);
+        ppu.reg_oam_data[4..8].copy_from_slice(&[9, 2, 0b0100_0001, 32]);
+        ppu.write_mask(0b0001_0100);
+
+        render_frame(&mut ppu);
+
+        assert_eq!(ppu.frame.get_pixel(16, 9), 0x0f);
+        assert_eq!(ppu.frame.get_pixel(16, 10), 0x27);
+        assert_eq!(ppu.frame.get_pixel(17, 10), 0x0f);
+        assert_eq!(ppu.frame.get_pixel(39, 17), 0x27);
+        assert_eq!(ppu.frame.get_pixel(32, 17), 0x0f);
+        assert_eq!(ppu.frame.get_pixel(16, 18), 0x0f);
+    }
+
+    #[test]
+    fn test_render_sprite_priority_and_zero_hit() {
+        let mut ppu = render_test_ppu();
+        ppu.vram[0x21] = 1;
+        // Sprite 0 behind the background, sprite 1 in front of it at the same position
+        ppu.reg_oam_data[0..4].copy_from_slice(&[7, 1, 0b0010_0000, 8]);
+        ppu.reg_oam_data[4..8].copy_from_slice(&[7, 1, 0b0000_0001, 8]);
+        ppu.write_mask(0b0001_1110);
+
+        render_frame(&mut ppu);
+
+        // Sprite 0 claims the pixels, so the background shows through even though sprite 1 is in front
+        assert_eq!(ppu.frame.get_pixel(8, 8), 0x21);
+        assert_eq!(ppu.frame.get_pixel(8, 16), 0x0f);
+        assert!(ppu.reg_status.contains(StatRegister::SPRITE_ZERO_HIT));
+    }
+
+    #[test]
+    fn test_render_8x16_sprite() {
+        let mut ppu = render_test_ppu();
+        // Tile 0x03 selects the pattern table at 0x1000, tiles 0x02 (top, empty) and 0x03 (bottom)
+        ppu.reg_oam_data[0..4].copy_from_slice(&[0, 0x03, 0, 100]);
+        ppu.write_ctrl(0b0010_0000);
+        ppu.write_mask(0b0001_0100);
+
+        render_frame(&mut ppu);
+
+        assert_eq!(ppu.frame.get_pixel(100, 8), 0x0f);
+        assert_eq!(ppu.frame.get_pixel(100, 9), 0x30);
+        assert_eq!(ppu.frame.get_pixel(107, 16), 0x30);
+    }
+
+    #[test]
+    fn test_scroll_registers_share_t() {
+        let mut ppu = PPU::new_empty_rom();
+        ppu.write_ctrl(0b11);
+        assert_eq!(ppu.temp_addr, 0x0c00);
+
+        ppu.read_status();
+        ppu.write_scroll(0x7d);
+        assert_eq!(ppu.temp_addr, 0x0c0f);
+        assert_eq!(ppu.fine_x, 0x05);
+
+        ppu.write_scroll(0x5e);
+        assert_eq!(ppu.temp_addr, 0x6d6f);
+
+        ppu.write_ppu_addr(0x3d);
+        assert_eq!(ppu.temp_addr, 0x3d6f);
+        assert_eq!(ppu.vram_addr, 0);
+
+        ppu.write_ppu_addr(0xf0);
+        assert_eq!(ppu.temp_addr, 0x3df0);
+        assert_eq!(ppu.vram_addr, 0x3df0);
+    }
+
+    #[test]
+    fn test_render_greyscale_and_emphasis() {
+        let mut ppu = render_test_ppu();
+        ppu.vram[0] = 1;
+        // Greyscale, red and blue emphasis
+        ppu.write_mask(0b1010_1011);
+
+        render_frame(&mut ppu);
+
+        assert_eq!(ppu.frame.get_pixel(0, 0), 0b101 << 6 | 0x20);
+        assert_eq!(ppu.frame.get_pixel(8, 0), 0b101 << 6);
+    }
+
+    #[test]
+    fn test_load_state_rejects_out_of_range_counters() {
+        let load = |ppu: &PPU| {
+            let mut w = StateWriter::new();
+            ppu.save(&mut w);
+            let data = w.into_bytes();
+            PPU::new_empty_rom().load(&mut StateReader::new(&data))
+        };
+
+        let mut ppu = PPU::new_empty_rom();
+        assert!(load(&ppu).is_ok());
+
+        ppu.sprite_count = 9;
+        assert!(load(&ppu).is_err());
+        ppu.sprite_count = 8;
+        ppu.scanline = 262;
+        assert!(load(&ppu).is_err());
+        ppu.scanline = 0;
+        ppu.dot = 341;
+        assert!(load(&ppu).is_err());
+    }
+
+    #[test]
+    fn test_mid_frame_scroll_split() {
+        let mut ppu = render_test_ppu();
+        for row in 0..30 {
+            ppu.vram[row * 32 + 1] = 1;
+        }
+        ppu.write_mask(0b0000_1010);
+        while !ppu.tick(3) {}
+
+        while ppu.scanline() != 100 {
+            ppu.tick(3);
+        }
+        ppu.write_scroll(8);
+        ppu.write_scroll(0);
+        while !ppu.stat_vblank_started() {
+            ppu.tick(3);
+        }
+
+        assert_eq!(ppu.frame.get_pixel(0, 99), 0x0f);
+        assert_eq!(ppu.frame.get_pixel(8, 99), 0x21);
+        assert_eq!(ppu.frame.get_pixel(0, 101), 0x21);
+        assert_eq!(ppu.frame.get_pixel(8, 101), 0x0f);
+    }
 }
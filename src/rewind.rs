@@ -0,0 +1,266 @@
+use std::collections::VecDeque;
+use crate::cpu::CPU;
+
+// Rewind history built from save states. Only the newest snapshot is kept whole; every older one is stored
+// as the XOR against the snapshot taken after it, run-length encoded. A snapshot is ~135K, most of it the
+// 120K frame buffer, and consecutive frames only differ where the picture and the game's RAM changed, so the
+// zero runs keep each step small. Stepping back undoes the newest delta, and the oldest deltas are dropped
+// whenever the history grows past its memory budget.
+pub struct Rewind {
+    interval: usize,
+    budget: usize,
+    frames: usize,
+    latest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+    delta_bytes: usize,
+}
+
+impl Rewind {
+    /// Keeps a snapshot every `interval` frames within roughly `budget` bytes of memory
+    pub fn new(interval: usize, budget: usize) -> Self {
+        Rewind {
+            interval: interval.max(1),
+            budget,
+            frames: 0,
+            latest: None,
+            deltas: VecDeque::new(),
+            delta_bytes: 0,
+        }
+    }
+
+    /// Call once per emulated frame
+    pub fn capture(&mut self, cpu: &CPU) {
+        self.frames += 1;
+        if self.frames < self.interval {
+            return;
+        }
+        self.frames = 0;
+
+        let state = cpu.save_state();
+        if let Some(latest) = self.latest.take() {
+            if latest.len() == state.len() {
+                let delta = encode_delta(&state, &latest);
+                self.delta_bytes += delta.len();
+                self.deltas.push_back(delta);
+            } else {
+                self.clear();
+            }
+        }
+        self.latest = Some(state);
+
+        while self.memory_used() > self.budget {
+            match self.deltas.pop_front() {
+                Some(delta) => self.delta_bytes -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    /// Restores the snapshot before the current one. Returns false once the history is exhausted
+    /// On error the history and the machine are left as they were
+    pub fn step_back(&mut self, cpu: &mut CPU) -> Result<bool, String> {
+        let delta = match self.deltas.back() {
+            Some(delta) => delta,
+            None => return Ok(false),
+        };
+
+        // Work on a copy so a delta that fails partway through does not leave the snapshot half undone
+        let mut previous = self.latest.clone().expect("rewind deltas without a snapshot");
+        apply_delta(&mut previous, delta)?;
+        cpu.load_state(&previous)?;
+
+        let delta = self.deltas.pop_back().unwrap();
+        self.delta_bytes -= delta.len();
+        self.latest = Some(previous);
+        self.frames = 0;
+        Ok(true)
+    }
+
+    /// Number of snapshots that can be stepped back to
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    pub fn memory_used(&self) -> usize {
+        self.delta_bytes + self.latest.as_ref().map_or(0, |state| state.len())
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+        self.delta_bytes = 0;
+        self.frames = 0;
+    }
+}
+
+// XOR of two equally sized buffers as a list of (zero run, literal count, literal bytes) records,
+// with the counts written as LEB128 varints
+fn encode_delta(from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+
+    while i < from.len() {
+        let zeros_start = i;
+        while i < from.len() && from[i] == to[i] {
+            i += 1;
+        }
+        let literal_start = i;
+        while i < from.len() && from[i] != to[i] {
+            i += 1;
+        }
+
+        write_varint(&mut out, literal_start - zeros_start);
+        write_varint(&mut out, i - literal_start);
+        out.extend((literal_start..i).map(|j| from[j] ^ to[j]));
+    }
+    out
+}
+
+fn apply_delta(state: &mut [u8], delta: &[u8]) -> Result<(), String> {
+    let mut pos = 0;
+    let mut i = 0;
+
+    while i < delta.len() {
+        let zeros = read_varint(delta, &mut i)?;
+        let literals = read_varint(delta, &mut i)?;
+        pos += zeros;
+        if pos + literals > state.len() || i + literals > delta.len() {
+            return Err("Rewind delta does not match the snapshot".to_string());
+        }
+
+        for (byte, change) in state[pos..pos + literals].iter_mut().zip(&delta[i..i + literals]) {
+            *byte ^= change;
+        }
+        pos += literals;
+        i += literals;
+    }
+    Ok(())
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], i: &mut usize) -> Result<usize, String> {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *data.get(*i).ok_or("Rewind delta is truncated")?;
+        *i += 1;
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+        if shift >= usize::BITS {
+            return Err("Rewind delta has an invalid length".to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+
+    // loop: INC $10; INC $0300,X; INX; JMP loop
+    fn counting_cpu() -> CPU {
+        let mut cpu = CPU::new(Bus::new(test_rom(vec![0xe6, 0x10, 0xfe, 0x00, 0x03, 0xe8, 0x4c, 0x00, 0x80])));
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn test_delta_roundtrip() {
+        let from = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        let to = vec![1, 2, 0, 4, 5, 9, 9, 8];
+        let delta = encode_delta(&from, &to);
+
+        let mut state = to.clone();
+        apply_delta(&mut state, &delta).unwrap();
+        assert_eq!(state, from);
+        assert!(encode_delta(&from, &from).len() <= 3);
+
+        let mut varint = Vec::new();
+        write_varint(&mut varint, 300);
+        assert_eq!(read_varint(&varint, &mut 0), Ok(300));
+    }
+
+    #[test]
+    fn test_step_back_frame_by_frame() {
+        let mut cpu = counting_cpu();
+        let mut rewind = Rewind::new(1, usize::MAX);
+        let mut history = Vec::new();
+
+        for _ in 0..10 {
+            cpu.run_frame();
+            rewind.capture(&cpu);
+            history.push(cpu.save_state());
+        }
+        assert_eq!(rewind.len(), 9);
+
+        for expected in history.iter().rev().skip(1) {
+            assert_eq!(rewind.step_back(&mut cpu), Ok(true));
+            assert!(cpu.save_state() == *expected);
+        }
+        assert_eq!(rewind.step_back(&mut cpu), Ok(false));
+
+        // Running again after a rewind keeps recording from the restored frame
+        cpu.run_frame();
+        rewind.capture(&cpu);
+        assert_eq!(rewind.step_back(&mut cpu), Ok(true));
+        assert!(cpu.save_state() == history[0]);
+    }
+
+    #[test]
+    fn test_bad_delta_leaves_history_untouched() {
+        let mut cpu = counting_cpu();
+        let mut rewind = Rewind::new(1, usize::MAX);
+        for _ in 0..3 {
+            cpu.run_frame();
+            rewind.capture(&cpu);
+        }
+
+        // The real changes apply, then a run past the end of the snapshot fails
+        let delta = rewind.deltas.back_mut().unwrap();
+        write_varint(delta, usize::MAX / 2);
+        write_varint(delta, 1);
+        delta.push(0xff);
+
+        let latest = rewind.latest.clone();
+        let state = cpu.save_state();
+        assert!(rewind.step_back(&mut cpu).is_err());
+        assert_eq!(rewind.latest, latest);
+        assert_eq!(rewind.len(), 2);
+        assert!(cpu.save_state() == state);
+    }
+
+    #[test]
+    fn test_interval_and_budget() {
+        let mut cpu = counting_cpu();
+        let mut rewind = Rewind::new(4, usize::MAX);
+        for _ in 0..20 {
+            cpu.run_frame();
+            rewind.capture(&cpu);
+        }
+        assert_eq!(rewind.len(), 4);
+
+        let state_size = cpu.save_state().len();
+        let mut rewind = Rewind::new(1, state_size + 2000);
+        for _ in 0..200 {
+            cpu.run_frame();
+            rewind.capture(&cpu);
+        }
+        assert!(rewind.memory_used() <= state_size + 2000);
+        assert!(rewind.len() > 1 && rewind.len() < 199);
+    }
+}
@@ -0,0 +1,224 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::cpu::CPU;
+
+// Save states are a flat little-endian byte stream: a header followed by every component writing its fields
+// in a fixed order. Variable sized buffers are prefixed with their length so a truncated or foreign file is
+// caught instead of silently shifting every field after it.
+// Bump VERSION whenever a component adds, removes or reorders a field.
+pub const MAGIC: [u8; 4] = *b"NESS";
+pub const VERSION: u16 = 2;
+
+pub trait Stateful {
+    fn save(&self, w: &mut StateWriter);
+
+    fn load(&mut self, r: &mut StateReader) -> Result<(), String>;
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        StateWriter { buf: Vec::new() }
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.buf.push(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_usize(&mut self, value: usize) {
+        self.write_u64(value as u64);
+    }
+
+    pub fn write_f32(&mut self, value: f32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_f64(&mut self, value: f64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.buf.extend_from_slice(bytes);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader { data, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.data.len() - self.pos < len {
+            return Err("Save state is truncated".to_string());
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, String> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn read_usize(&mut self) -> Result<usize, String> {
+        Ok(self.read_u64()? as usize)
+    }
+
+    pub fn read_f32(&mut self) -> Result<f32, String> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_f64(&mut self) -> Result<f64, String> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// Reads a buffer written by `write_bytes` into `out`, which must have the same length
+    pub fn read_bytes_into(&mut self, out: &mut [u8]) -> Result<(), String> {
+        let len = self.read_u32()? as usize;
+        if len != out.len() {
+            return Err(format!("Save state buffer has {} bytes, expected {}", len, out.len()));
+        }
+        out.copy_from_slice(self.take(len)?);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+}
+
+/// File used for a numbered save slot, e.g. `game.ss1` beside `game.nes`
+pub fn slot_path(rom_path: &Path, slot: u8) -> PathBuf {
+    rom_path.with_extension(format!("ss{}", slot))
+}
+
+pub fn save_slot(cpu: &CPU, rom_path: &Path, slot: u8) -> Result<(), String> {
+    let path = slot_path(rom_path, slot);
+    fs::write(&path, cpu.save_state()).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+pub fn load_slot(cpu: &mut CPU, rom_path: &Path, slot: u8) -> Result<(), String> {
+    let path = slot_path(rom_path, slot);
+    let data = fs::read(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    cpu.load_state(&data)
+}
+
+// FNV-1a, used to tie a save state to the ROM it was made with
+pub fn fnv1a(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c9dc5, |hash, byte| (hash ^ *byte as u32).wrapping_mul(0x01000193))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+
+    #[test]
+    fn test_roundtrip_primitives() {
+        let mut w = StateWriter::new();
+        w.write_u8(0x12);
+        w.write_bool(true);
+        w.write_u16(0x3456);
+        w.write_u32(0x789abcde);
+        w.write_usize(123456789);
+        w.write_f32(0.25);
+        w.write_bytes(&[1, 2, 3]);
+        let data = w.into_bytes();
+
+        let mut r = StateReader::new(&data);
+        assert_eq!(r.read_u8(), Ok(0x12));
+        assert_eq!(r.read_bool(), Ok(true));
+        assert_eq!(r.read_u16(), Ok(0x3456));
+        assert_eq!(r.read_u32(), Ok(0x789abcde));
+        assert_eq!(r.read_usize(), Ok(123456789));
+        assert_eq!(r.read_f32(), Ok(0.25));
+        let mut bytes = [0; 3];
+        assert!(r.read_bytes_into(&mut bytes).is_ok());
+        assert_eq!(bytes, [1, 2, 3]);
+        assert!(r.is_empty());
+        assert!(r.read_u8().is_err());
+    }
+
+    #[test]
+    fn test_buffer_length_mismatch() {
+        let mut w = StateWriter::new();
+        w.write_bytes(&[1, 2, 3]);
+        let data = w.into_bytes();
+
+        let mut bytes = [0; 4];
+        assert!(StateReader::new(&data).read_bytes_into(&mut bytes).is_err());
+    }
+
+    #[test]
+    fn test_slot_path() {
+        assert_eq!(slot_path(Path::new("roms/zelda.nes"), 3), PathBuf::from("roms/zelda.ss3"));
+    }
+
+    #[test]
+    fn test_save_and_load_slot() {
+        let dir = std::env::temp_dir().join(format!("nes-slot-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let rom_path = dir.join("game.nes");
+
+        let mut cpu = CPU::new(Bus::new(test_rom(vec![0xe8, 0x4c, 0x00, 0x80])));
+        cpu.reset();
+        cpu.run_cycles(100);
+        save_slot(&cpu, &rom_path, 1).unwrap();
+        let x = cpu.reg_x;
+
+        cpu.run_cycles(100);
+        assert_ne!(cpu.reg_x, x);
+        load_slot(&mut cpu, &rom_path, 1).unwrap();
+        assert_eq!(cpu.reg_x, x);
+        assert!(load_slot(&mut cpu, &rom_path, 2).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
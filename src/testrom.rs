@@ -0,0 +1,122 @@
+use crate::cpu::CPU;
+
+// Runner for test ROMs that follow blargg's reporting protocol. While a test runs $6000 holds $80, $81 asks
+// for the reset button to be pressed, and any value below $80 is the final result code, 0 meaning passed.
+// $6001-$6003 hold DE B0 61 once $6000 is valid, and $6004 on is a NUL terminated text log of the results.
+// https://github.com/christopherpow/nes-test-roms/blob/master/instr_test-v5/readme.txt
+const STATUS: u16 = 0x6000;
+const SIGNATURE: [u8; 3] = [0xde, 0xb0, 0x61];
+const TEXT: u16 = 0x6004;
+
+const RUNNING: u8 = 0x80;
+const NEEDS_RESET: u8 = 0x81;
+
+// The ROMs ask for the reset to happen at least 100ms after the request
+const RESET_DELAY_FRAMES: usize = 6;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct TestRomResult {
+    /// 0 when every test passed, otherwise the number of the failing test
+    pub code: u8,
+    pub text: String,
+}
+
+impl TestRomResult {
+    pub fn passed(&self) -> bool {
+        self.code == 0
+    }
+}
+
+/// Runs a freshly reset machine until the ROM reports a result, giving up after `max_frames`
+pub fn run_test_rom(cpu: &mut CPU, max_frames: usize) -> Result<TestRomResult, String> {
+    let mut reset_frame = None;
+
+    for frame in 0..max_frames {
+        cpu.run_frame();
+        if !has_signature(cpu) {
+            continue;
+        }
+
+        match cpu.bus.peek(STATUS) {
+            RUNNING => {}
+            NEEDS_RESET => {
+                let at = *reset_frame.get_or_insert(frame + RESET_DELAY_FRAMES);
+                if frame >= at {
+                    cpu.reset();
+                    reset_frame = None;
+                }
+            }
+            code => return Ok(TestRomResult { code, text: result_text(cpu) }),
+        }
+    }
+
+    let text = if has_signature(cpu) { result_text(cpu) } else { String::new() };
+    Err(format!("No result after {} frames\n{}", max_frames, text))
+}
+
+fn has_signature(cpu: &CPU) -> bool {
+    (0..3).all(|i| cpu.bus.peek(STATUS + 1 + i) == SIGNATURE[i as usize])
+}
+
+fn result_text(cpu: &CPU) -> String {
+    (TEXT..=0x7fff)
+        .map(|addr| cpu.bus.peek(addr))
+        .take_while(|byte| *byte != 0)
+        .map(|byte| byte as char)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+
+    // Writes the signature and `text`, then sets the result code
+    fn reporting_program(code: u8, text: &str) -> Vec<u8> {
+        let mut program = vec![
+            0xa9, 0x80, 0x8d, 0x00, 0x60, // LDA #$80; STA $6000
+            0xa9, 0xde, 0x8d, 0x01, 0x60, // signature
+            0xa9, 0xb0, 0x8d, 0x02, 0x60,
+            0xa9, 0x61, 0x8d, 0x03, 0x60,
+            0xa2, 0x00,                   // LDX #0
+            0xbd, 0x2a, 0x80,             // copy: LDA text,X
+            0x9d, 0x04, 0x60,             // STA $6004,X
+            0xf0, 0x04,                   // BEQ done
+            0xe8,                         // INX
+            0x4c, 0x16, 0x80,             // JMP copy
+            0xa9, code, 0x8d, 0x00, 0x60, // done: LDA #code; STA $6000
+            0x4c, 0x27, 0x80,             // JMP *
+        ];
+        program.extend(text.bytes());
+        program.push(0);
+        program
+    }
+
+    fn run_program(program: Vec<u8>) -> Result<TestRomResult, String> {
+        let mut cpu = CPU::new(Bus::new(test_rom(program)));
+        cpu.reset();
+        run_test_rom(&mut cpu, 10)
+    }
+
+    #[test]
+    fn test_passing_rom() {
+        let result = run_program(reporting_program(0, "Passed\n")).unwrap();
+        assert!(result.passed());
+        assert_eq!(result.text, "Passed\n");
+    }
+
+    #[test]
+    fn test_failing_rom() {
+        let result = run_program(reporting_program(3, "Failed #3\n")).unwrap();
+        assert!(!result.passed());
+        assert_eq!(result.code, 3);
+        assert_eq!(result.text, "Failed #3\n");
+    }
+
+    #[test]
+    fn test_rom_without_protocol_times_out() {
+        // JMP *
+        assert!(run_program(vec![0x4c, 0x00, 0x80]).is_err());
+    }
+}
@@ -1,160 +1,247 @@
-use crate::cpu::Mem;
 use crate::cpu::CPU;
-use crate::debugcodes;
-use crate::debugcodes::AddressingMode;
-
-pub fn trace(cpu: &mut CPU) -> String {
-    let ref opscodes: Vec<debugcodes::OpCodes> = *debugcodes::DEBUG_OPCODES;
+use crate::disasm::{self, Instruction, Mode};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TraceStyle {
+    /// Nintendulator, the layout of the canonical nestest.log
+    Nintendulator,
+    Mesen,
+    /// FCEUX has no PPU column of its own, ours goes in front like its cycle counter
+    Fceux,
+}
+
+/// Layout of a trace line and which optional columns it carries
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct TraceFormat {
+    pub style:  TraceStyle,
+    /// PPU scanline and dot before the instruction
+    pub ppu:    bool,
+    /// CPU cycles since power on before the instruction
+    pub cycles: bool,
+}
+
+impl TraceFormat {
+    /// Matches nestest_no_cycle.log
+    pub const NESTEST: TraceFormat = TraceFormat { style: TraceStyle::Nintendulator, ppu: false, cycles: false };
+
+    /// Matches the canonical nestest.log, including the `PPU:` and `CYC:` columns
+    pub const NINTENDULATOR: TraceFormat = TraceFormat { style: TraceStyle::Nintendulator, ppu: true, cycles: true };
+
+    pub const MESEN: TraceFormat = TraceFormat { style: TraceStyle::Mesen, ppu: true, cycles: true };
+
+    pub const FCEUX: TraceFormat = TraceFormat { style: TraceStyle::Fceux, ppu: false, cycles: true };
+
+    /// Looks up a preset by name, for command line options
+    pub fn from_name(name: &str) -> Result<TraceFormat, String> {
+        match name {
+            "nestest" => Ok(TraceFormat::NESTEST),
+            "nintendulator" => Ok(TraceFormat::NINTENDULATOR),
+            "mesen" => Ok(TraceFormat::MESEN),
+            "fceux" => Ok(TraceFormat::FCEUX),
+            _ => Err(format!("Unknown trace format '{}', expected nestest, nintendulator, mesen or fceux", name)),
+        }
+    }
+}
 
-    let code = cpu.mem_read(cpu.reg_pc);
-    let ops = &opscodes[code as usize];
+// Reads go through `Bus::peek`, so tracing never disturbs the emulated machine
+pub fn trace(cpu: &CPU) -> String {
+    trace_with(cpu, TraceFormat::NESTEST)
+}
 
+pub fn trace_with(cpu: &CPU, format: TraceFormat) -> String {
     let begin = cpu.reg_pc;
-    let mut hex_dump = vec![];
-    hex_dump.push(code);
-
-    let tmp = match ops.len {
-        1 => match code {
-            0x0a | 0x4a | 0x2a | 0x6a => format!("A "),
-            _ => String::from(""),
-        },
-        2 => {
-            let address = cpu.mem_read(begin + 1);
-            match ops.mode {
-                AddressingMode::Immediate => {
-                    hex_dump.push(address);
-                    format!("#${:02x}", address)
-                }
-
-                AddressingMode::ZeroPage => {
-                    let stored_value = cpu.mem_read(address as u16);
-
-                    hex_dump.push(address);
-                    format!("${:02x} = {:02x}", address, stored_value)
-                }
-                
-                AddressingMode::ZeroPage_X => {
-                    let mem_addr = address.wrapping_add(cpu.reg_x) as u16;
-                    let stored_value = cpu.mem_read(mem_addr as u16);
-
-                    hex_dump.push(address);
-                    format!("${:02x},X @ {:02x} = {:02x}", address, mem_addr, stored_value)
-                }
-                
-                AddressingMode::ZeroPage_Y => {
-                    let mem_addr = address.wrapping_add(cpu.reg_y) as u16;
-                    let stored_value = cpu.mem_read(mem_addr as u16);
-
-                    hex_dump.push(address);
-                    format!("${:02x},Y @ {:02x} = {:02x}", address, mem_addr, stored_value)
-                }
-                
-                AddressingMode::Indirect_X => {
-                    let addr_offset = address.wrapping_add(cpu.reg_x);
-                    let lo = cpu.mem_read(addr_offset as u16);
-                    let hi = cpu.mem_read(addr_offset.wrapping_add(1) as u16);
-                    let mem_addr = (hi as u16) << 8 | (lo as u16);
-                    let stored_value = cpu.mem_read(mem_addr);
-
-                    hex_dump.push(address);
-                    format!("(${:02x},X) @ {:02x} = {:04x} = {:02x}", 
-                    address, addr_offset, mem_addr, stored_value) 
-                }
-
-                AddressingMode::Indirect_Y => {
-                    let lo = cpu.mem_read(address as u16);
-                    let hi = cpu.mem_read((address as u8).wrapping_add(1) as u16);
-                    let addr_offset = (hi as u16) << 8 | (lo as u16);
-                    let mem_addr = addr_offset.wrapping_add(cpu.reg_y as u16);
-                    let stored_value = cpu.mem_read(mem_addr);
-
-                    hex_dump.push(address);
-                    format!(
-                        "(${:02x}),Y = {:04x} @ {:04x} = {:02x}",
-                        address, addr_offset, mem_addr, stored_value) 
-                }
-
-                AddressingMode::NoneAddressing => {
-                    hex_dump.push(address);
-
-                    let jmp_address: usize =
-                        (begin as usize + 2).wrapping_add((address as i8) as usize);
-                    format!("${:04x}", jmp_address)
-                }
-
-                _ => panic!(
-                    "unexpected addressing mode {:?} has ops-len 2. code {:02x}",
-                    ops.mode, code
-                ),
-            }
-        }
-        3 => {
-            let address_lo = cpu.mem_read(begin + 1);
-            let address_hi = cpu.mem_read(begin + 2);
-            hex_dump.push(address_lo);
-            hex_dump.push(address_hi);
-            let address = cpu.mem_read_u16(begin + 1);
-
-            match ops.mode {
-                AddressingMode::NoneAddressing => {
-                    if code == 0x6c {
-                        //jmp indirect
-                        let jmp_addr = if address & 0x00FF == 0x00FF {
-                            let lo = cpu.mem_read(address);
-                            let hi = cpu.mem_read(address & 0xFF00);
-                            (hi as u16) << 8 | (lo as u16)
-                        } else {
-                            cpu.mem_read_u16(address)
-                        };
-
-                        format!("(${:04x}) = {:04x}", address, jmp_addr)
-                    } else {
-                        format!("${:04x}", address)
-                    }
-                }
-
-                AddressingMode::Absolute => {
-                    let stored_value: u8 = cpu.mem_read(address);
-
-                    format!("${:04x} = {:02x}", address, stored_value)
-                }
-
-                AddressingMode::Absolute_X => {
-                    let mem_addr = address.wrapping_add(cpu.reg_x as u16);
-                    let stored_value = cpu.mem_read(mem_addr);
-                    
-                    format!("${:04x},X @ {:04x} = {:02x}", address, mem_addr, stored_value)
-                }
-
-                AddressingMode::Absolute_Y => {
-                    let mem_addr = address.wrapping_add(cpu.reg_y as u16);
-                    let stored_value = cpu.mem_read(mem_addr);
-
-                    format!("${:04x},Y @ {:04x} = {:02x}", address, mem_addr, stored_value)
-                }
-
-                _ => panic!(
-                    "unexpected addressing mode {:?} has ops-len 3. code {:02x}",
-                    ops.mode, code
-                ),
-            }
-        }
-        _ => String::from(""),
-    };
+    let bytes = [
+        cpu.bus.peek(begin),
+        cpu.bus.peek(begin.wrapping_add(1)),
+        cpu.bus.peek(begin.wrapping_add(2)),
+    ];
+    let ins = disasm::decode(&bytes, begin).expect("three bytes hold any instruction");
+    let hex_dump = &bytes[..ins.len as usize];
+    let tmp = annotated_operand(cpu, &ins);
 
     let hex_str = hex_dump
         .iter()
         .map(|z| format!("{:02x}", z))
         .collect::<Vec<String>>()
         .join(" ");
-    
-    let asm_str = format!("{:04x}  {:8} {: >4} {}", begin, hex_str, ops.mnemonic, tmp)
-        .trim()
-        .to_string();
-
-    format!(
-        "{:47} A:{:02x} X:{:02x} Y:{:02x} P:{:02x} SP:{:02x}",
-        asm_str, cpu.reg_acc, cpu.reg_x, cpu.reg_y, cpu.reg_status, cpu.reg_stack_ptr,
-    )
-    .to_ascii_uppercase()
-}
\ No newline at end of file
+
+    let ppu = cpu.bus.ppu();
+    match format.style {
+        TraceStyle::Nintendulator => {
+            let asm_str = format!("{:04x}  {:8} {: >4} {}", begin, hex_str, ins.mnemonic, tmp)
+                .trim()
+                .to_string();
+
+            let mut line = format!(
+                "{:47} A:{:02x} X:{:02x} Y:{:02x} P:{:02x} SP:{:02x}",
+                asm_str, cpu.reg_acc, cpu.reg_x, cpu.reg_y, cpu.reg_status, cpu.reg_stack_ptr,
+            )
+            .to_ascii_uppercase();
+
+            if format.ppu {
+                line += &format!(" PPU:{:>3},{:>3}", ppu.scanline(), ppu.dot());
+            }
+            if format.cycles {
+                line += &format!(" CYC:{}", cpu.cycles);
+            }
+            line
+        }
+
+        TraceStyle::Mesen => {
+            let asm_str = format!("{} {}", ins.mnemonic, tmp).trim().to_ascii_uppercase();
+            let mut line = format!(
+                "{:04X}  {:8}  {:32} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+                begin, hex_str.to_ascii_uppercase(), asm_str,
+                cpu.reg_acc, cpu.reg_x, cpu.reg_y, cpu.reg_status, cpu.reg_stack_ptr,
+            );
+
+            if format.ppu {
+                line += &format!(" CYC:{:>3} SL:{:>3}", ppu.dot(), ppu.scanline());
+            }
+            if format.cycles {
+                line += &format!(" CPU Cycle:{}", cpu.cycles);
+            }
+            line
+        }
+
+        TraceStyle::Fceux => {
+            let mut line = String::new();
+            if format.cycles {
+                line += &format!("c{:<11} ", cpu.cycles);
+            }
+            if format.ppu {
+                line += &format!("PPU:{:>3},{:>3} ", ppu.scanline(), ppu.dot());
+            }
+
+            let asm_str = format!("{} {}", ins.mnemonic, tmp).trim().to_ascii_uppercase();
+            line += &format!(
+                "A:{:02X} X:{:02X} Y:{:02X} S:{:02X} P:{}  ${:04X}:{:8}  {}",
+                cpu.reg_acc, cpu.reg_x, cpu.reg_y, cpu.reg_stack_ptr, status_letters(cpu),
+                begin, hex_str.to_ascii_uppercase(), asm_str,
+            );
+            line
+        }
+    }
+}
+
+// Status register as NVUBDIZC, uppercase for set flags
+fn status_letters(cpu: &CPU) -> String {
+    "NVUBDIZC".chars().enumerate()
+        .map(|(i, name)| {
+            if cpu.reg_status.bits() & (0x80 >> i) != 0 { name } else { name.to_ascii_lowercase() }
+        })
+        .collect()
+}
+
+// Operand with the address it resolves to and the value stored there, as in nestest.log
+fn annotated_operand(cpu: &CPU, ins: &Instruction) -> String {
+    match ins.mode {
+        Mode::Implied => String::from(""),
+        Mode::Accumulator => String::from("A "),
+        Mode::Immediate => format!("#${:02x}", ins.operand),
+        Mode::Relative => format!("${:04x}", ins.target.unwrap()),
+
+        Mode::ZeroPage => {
+            let stored_value = cpu.bus.peek(ins.operand);
+            format!("${:02x} = {:02x}", ins.operand, stored_value)
+        }
+
+        Mode::ZeroPage_X => {
+            let mem_addr = (ins.operand as u8).wrapping_add(cpu.reg_x) as u16;
+            let stored_value = cpu.bus.peek(mem_addr);
+            format!("${:02x},X @ {:02x} = {:02x}", ins.operand, mem_addr, stored_value)
+        }
+
+        Mode::ZeroPage_Y => {
+            let mem_addr = (ins.operand as u8).wrapping_add(cpu.reg_y) as u16;
+            let stored_value = cpu.bus.peek(mem_addr);
+            format!("${:02x},Y @ {:02x} = {:02x}", ins.operand, mem_addr, stored_value)
+        }
+
+        Mode::Indirect_X => {
+            let addr_offset = (ins.operand as u8).wrapping_add(cpu.reg_x);
+            let lo = cpu.bus.peek(addr_offset as u16);
+            let hi = cpu.bus.peek(addr_offset.wrapping_add(1) as u16);
+            let mem_addr = (hi as u16) << 8 | (lo as u16);
+            let stored_value = cpu.bus.peek(mem_addr);
+            format!("(${:02x},X) @ {:02x} = {:04x} = {:02x}",
+                ins.operand, addr_offset, mem_addr, stored_value)
+        }
+
+        Mode::Indirect_Y => {
+            let lo = cpu.bus.peek(ins.operand);
+            let hi = cpu.bus.peek((ins.operand as u8).wrapping_add(1) as u16);
+            let addr_offset = (hi as u16) << 8 | (lo as u16);
+            let mem_addr = addr_offset.wrapping_add(cpu.reg_y as u16);
+            let stored_value = cpu.bus.peek(mem_addr);
+            format!("(${:02x}),Y = {:04x} @ {:04x} = {:02x}",
+                ins.operand, addr_offset, mem_addr, stored_value)
+        }
+
+        //jmp indirect wraps within the page like the real CPU
+        Mode::Indirect => {
+            let address = ins.operand;
+            let lo = cpu.bus.peek(address);
+            let hi = cpu.bus.peek(if address & 0x00FF == 0x00FF { address & 0xFF00 } else { address + 1 });
+            let jmp_addr = (hi as u16) << 8 | (lo as u16);
+            format!("(${:04x}) = {:04x}", address, jmp_addr)
+        }
+
+        Mode::Absolute => match ins.target {
+            Some(target) => format!("${:04x}", target),
+            None => {
+                let stored_value = cpu.bus.peek(ins.operand);
+                format!("${:04x} = {:02x}", ins.operand, stored_value)
+            }
+        },
+
+        Mode::Absolute_X => {
+            let mem_addr = ins.operand.wrapping_add(cpu.reg_x as u16);
+            let stored_value = cpu.bus.peek(mem_addr);
+            format!("${:04x},X @ {:04x} = {:02x}", ins.operand, mem_addr, stored_value)
+        }
+
+        Mode::Absolute_Y => {
+            let mem_addr = ins.operand.wrapping_add(cpu.reg_y as u16);
+            let stored_value = cpu.bus.peek(mem_addr);
+            format!("${:04x},Y @ {:04x} = {:02x}", ins.operand, mem_addr, stored_value)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::test::test_rom;
+
+    // LDX #$05; STX $10
+    fn traced_cpu() -> CPU {
+        let mut cpu = CPU::new(Bus::new(test_rom(vec![0xa2, 0x05, 0x86, 0x10, 0x00])));
+        cpu.reset();
+        cpu.step();
+        cpu
+    }
+
+    #[test]
+    fn test_trace_formats() {
+        let cpu = traced_cpu();
+        assert_eq!(trace(&cpu),
+            "8002  86 10     STX $10 = 00                    A:00 X:05 Y:00 P:24 SP:FD");
+        assert_eq!(trace_with(&cpu, TraceFormat::NINTENDULATOR),
+            "8002  86 10     STX $10 = 00                    A:00 X:05 Y:00 P:24 SP:FD PPU:  0, 27 CYC:9");
+        assert_eq!(trace_with(&cpu, TraceFormat::MESEN),
+            "8002  86 10     STX $10 = 00                     A:00 X:05 Y:00 P:24 SP:FD CYC: 27 SL:  0 CPU Cycle:9");
+        assert_eq!(trace_with(&cpu, TraceFormat::FCEUX),
+            "c9           A:00 X:05 Y:00 S:FD P:nvUbdIzc  $8002:86 10     STX $10 = 00");
+
+        let format = TraceFormat { style: TraceStyle::Fceux, ppu: true, cycles: false };
+        assert!(trace_with(&cpu, format).starts_with("PPU:  0, 27 A:00"));
+    }
+
+    #[test]
+    fn test_trace_format_names() {
+        assert_eq!(TraceFormat::from_name("mesen"), Ok(TraceFormat::MESEN));
+        assert!(TraceFormat::from_name("bizhawk").is_err());
+    }
+}
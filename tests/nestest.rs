@@ -0,0 +1,100 @@
+use std::path::Path;
+
+use nes_emulator::bus::Bus;
+use nes_emulator::cartridge::Rom;
+use nes_emulator::cpu::CPU;
+use nes_emulator::disasm;
+use nes_emulator::trace::trace;
+
+// Lines of the log shown before a divergence
+const CONTEXT: usize = 8;
+
+// Nintendulator logs $FF for the write-only APU registers, the value a read would actually put on the bus
+// depends on open bus behaviour. Mask the value in "$40xx = NN" so only the instruction and registers count
+fn normalize(line: &str) -> String {
+    let mut line = line.trim_end().to_string();
+    if let Some(pos) = line.find(" $40") {
+        let value = pos + " $40xx = ".len();
+        if line[pos + 4..].starts_with(|c: char| c.is_ascii_hexdigit()) && line.get(pos + 6..value) == Some(" = ") {
+            line.replace_range(value..value + 2, "??");
+        }
+    }
+    line
+}
+
+fn describe_divergence(expected: &[&str], actual: &[String], index: usize, last_opcode: Option<(u16, u8)>) -> String {
+    let mut report = format!("nestest diverges from nestest_no_cycle.log at line {}\n", index + 1);
+    for line in &actual[index.saturating_sub(CONTEXT)..index] {
+        report += &format!("           {}\n", line);
+    }
+    report += &format!("expected > {}\n", expected.get(index).unwrap_or(&"<end of log>"));
+    report += &format!("actual   > {}\n", actual.get(index).map_or("<end of run>", |line| line.as_str()));
+
+    if let Some((addr, opcode)) = last_opcode {
+        let mnemonic = disasm::decode(&[opcode, 0, 0], addr).map_or("???", |ins| ins.mnemonic);
+        report += &format!(
+            "the last instruction executed was {} (opcode ${:02X}) at ${:04X}\n",
+            mnemonic.to_ascii_uppercase(), opcode, addr);
+    }
+    report
+}
+
+// Only instructions and registers are compared, the log has no PPU or CYC columns. Cycle counts are not checked
+// against the canonical nestest.log, the NINTENDULATOR trace format and its counts are only covered by the single
+// line in trace::test::test_trace_formats
+#[test]
+fn nestest_matches_golden_log() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let golden = std::fs::read_to_string(root.join("nestest_no_cycle.log")).unwrap();
+    let expected: Vec<&str> = golden.lines().collect();
+
+    let bytes = std::fs::read(root.join("nestest.nes")).unwrap();
+    let mut cpu = CPU::new(Bus::new(Rom::new(&bytes).unwrap()));
+    cpu.reset();
+
+    // Automation mode starts at $C000 instead of the reset vector, and returns to $0001 when every test has run
+    cpu.reg_pc = 0xC000;
+    let mut actual: Vec<String> = Vec::new();
+    let mut last_opcode = None;
+    let mut divergence = None;
+
+    cpu.run_with_callback(|cpu| {
+        if cpu.reg_pc == 0x0001 {
+            cpu.request_stop();
+            return;
+        }
+
+        let line = trace(cpu);
+        let index = actual.len();
+        let matches = expected.get(index).is_some_and(|golden| normalize(golden) == normalize(&line));
+        actual.push(line);
+
+        if !matches {
+            divergence = Some((index, last_opcode));
+            cpu.request_stop();
+            return;
+        }
+        last_opcode = Some((cpu.reg_pc, cpu.bus.peek(cpu.reg_pc)));
+    });
+
+    if actual.len() < expected.len() && divergence.is_none() {
+        divergence = Some((actual.len(), last_opcode));
+    }
+    if let Some((index, opcode)) = divergence {
+        panic!("{}", describe_divergence(&expected, &actual, index, opcode));
+    }
+
+    // nestest also leaves its own verdict in $02 (official opcodes) and $03 (unofficial opcodes)
+    assert_eq!(cpu.bus.peek(0x02), 0x00, "nestest reported official opcode error ${:02X}", cpu.bus.peek(0x02));
+    assert_eq!(cpu.bus.peek(0x03), 0x00, "nestest reported unofficial opcode error ${:02X}", cpu.bus.peek(0x03));
+}
+
+#[test]
+fn normalize_masks_apu_register_values() {
+    assert_eq!(
+        normalize("C68B  8D 15 40  STA $4015 = FF                  A:02 X:FF Y:15 P:25 SP:FB"),
+        "C68B  8D 15 40  STA $4015 = ??                  A:02 X:FF Y:15 P:25 SP:FB");
+    assert_eq!(
+        normalize("C5F7  86 00     STX $00 = 00                    A:00 X:00 Y:00 P:26 SP:FD\r"),
+        "C5F7  86 00     STX $00 = 00                    A:00 X:00 Y:00 P:26 SP:FD");
+}
@@ -0,0 +1,97 @@
+use std::path::PathBuf;
+
+use nes_emulator::bus::Bus;
+use nes_emulator::cartridge::Rom;
+use nes_emulator::cpu::CPU;
+use nes_emulator::testrom::run_test_rom;
+
+// The ROMs are not part of the repository, so these tests are ignored by default. Point NES_TEST_ROMS at a checkout
+// of https://github.com/christopherpow/nes-test-roms, or copy the suites into test_roms/, and run them with
+// `cargo test --test test_roms -- --ignored`. A missing ROM fails its test.
+const MAX_FRAMES: usize = 60 * 60;
+
+fn rom_dir() -> PathBuf {
+    match std::env::var_os("NES_TEST_ROMS") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("test_roms"),
+    }
+}
+
+fn run(path: &str) {
+    let path = rom_dir().join(path);
+    let bytes = std::fs::read(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+
+    let mut cpu = CPU::new(Bus::new(Rom::new(&bytes).unwrap()));
+    cpu.reset();
+    match run_test_rom(&mut cpu, MAX_FRAMES) {
+        Ok(result) => assert!(result.passed(), "{} failed with code {}\n{}", path.display(), result.code, result.text),
+        Err(e) => panic!("{}: {}", path.display(), e),
+    }
+}
+
+macro_rules! test_roms {
+    ($($name:ident => $path:expr,)*) => {
+        $(
+            #[test]
+            #[ignore = "needs the test ROMs from NES_TEST_ROMS or test_roms/"]
+            fn $name() {
+                run($path);
+            }
+        )*
+    };
+}
+
+test_roms! {
+    instr_test_basics => "instr_test-v5/rom_singles/01-basics.nes",
+    instr_test_implied => "instr_test-v5/rom_singles/02-implied.nes",
+    instr_test_immediate => "instr_test-v5/rom_singles/03-immediate.nes",
+    instr_test_zero_page => "instr_test-v5/rom_singles/04-zero_page.nes",
+    instr_test_zp_xy => "instr_test-v5/rom_singles/05-zp_xy.nes",
+    instr_test_absolute => "instr_test-v5/rom_singles/06-absolute.nes",
+    instr_test_abs_xy => "instr_test-v5/rom_singles/07-abs_xy.nes",
+    instr_test_ind_x => "instr_test-v5/rom_singles/08-ind_x.nes",
+    instr_test_ind_y => "instr_test-v5/rom_singles/09-ind_y.nes",
+    instr_test_branches => "instr_test-v5/rom_singles/10-branches.nes",
+    instr_test_stack => "instr_test-v5/rom_singles/11-stack.nes",
+    instr_test_jmp_jsr => "instr_test-v5/rom_singles/12-jmp_jsr.nes",
+    instr_test_rts => "instr_test-v5/rom_singles/13-rts.nes",
+    instr_test_rti => "instr_test-v5/rom_singles/14-rti.nes",
+    instr_test_brk => "instr_test-v5/rom_singles/15-brk.nes",
+    instr_test_special => "instr_test-v5/rom_singles/16-special.nes",
+
+    // cpu_timing_test6 only reports on screen, instr_timing covers the same ground through $6000
+    instr_timing => "instr_timing/rom_singles/1-instr_timing.nes",
+    branch_timing => "instr_timing/rom_singles/2-branch_timing.nes",
+
+    ppu_vbl_basics => "ppu_vbl_nmi/rom_singles/01-vbl_basics.nes",
+    ppu_vbl_set_time => "ppu_vbl_nmi/rom_singles/02-vbl_set_time.nes",
+    ppu_vbl_clear_time => "ppu_vbl_nmi/rom_singles/03-vbl_clear_time.nes",
+    ppu_nmi_control => "ppu_vbl_nmi/rom_singles/04-nmi_control.nes",
+    ppu_nmi_timing => "ppu_vbl_nmi/rom_singles/05-nmi_timing.nes",
+    ppu_suppression => "ppu_vbl_nmi/rom_singles/06-suppression.nes",
+    ppu_nmi_on_timing => "ppu_vbl_nmi/rom_singles/07-nmi_on_timing.nes",
+    ppu_nmi_off_timing => "ppu_vbl_nmi/rom_singles/08-nmi_off_timing.nes",
+    ppu_even_odd_frames => "ppu_vbl_nmi/rom_singles/09-even_odd_frames.nes",
+    ppu_even_odd_timing => "ppu_vbl_nmi/rom_singles/10-even_odd_timing.nes",
+
+    // The 2005 sprite_hit_tests predate the $6000 protocol, ppu_sprite_hit is their successor
+    sprite_hit_basics => "ppu_sprite_hit/rom_singles/01-basics.nes",
+    sprite_hit_alignment => "ppu_sprite_hit/rom_singles/02-alignment.nes",
+    sprite_hit_corners => "ppu_sprite_hit/rom_singles/03-corners.nes",
+    sprite_hit_flip => "ppu_sprite_hit/rom_singles/04-flip.nes",
+    sprite_hit_left_clip => "ppu_sprite_hit/rom_singles/05-left_clip.nes",
+    sprite_hit_right_edge => "ppu_sprite_hit/rom_singles/06-right_edge.nes",
+    sprite_hit_screen_bottom => "ppu_sprite_hit/rom_singles/07-screen_bottom.nes",
+    sprite_hit_double_height => "ppu_sprite_hit/rom_singles/08-double_height.nes",
+    sprite_hit_timing => "ppu_sprite_hit/rom_singles/09-timing.nes",
+    sprite_hit_timing_order => "ppu_sprite_hit/rom_singles/10-timing_order.nes",
+
+    apu_len_ctr => "apu_test/rom_singles/1-len_ctr.nes",
+    apu_len_table => "apu_test/rom_singles/2-len_table.nes",
+    apu_irq_flag => "apu_test/rom_singles/3-irq_flag.nes",
+    apu_jitter => "apu_test/rom_singles/4-jitter.nes",
+    apu_len_timing => "apu_test/rom_singles/5-len_timing.nes",
+    apu_irq_flag_timing => "apu_test/rom_singles/6-irq_flag_timing.nes",
+    apu_dmc_basics => "apu_test/rom_singles/7-dmc_basics.nes",
+    apu_dmc_rates => "apu_test/rom_singles/8-dmc_rates.nes",
+}